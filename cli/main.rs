@@ -50,8 +50,9 @@ fn main() -> anyhow::Result<()> {
             let mut target = std::io::BufWriter::with_capacity(1024 * 1024 * 16, target);
             let t0 = std::time::Instant::now();
             let tree = BaoTree::new(size, bs);
-            let hash = bao_tree::io::sync::outboard_post_order(source, tree, &mut target)?;
-            target.write_all(size.to_le_bytes().as_ref())?;
+            let hash =
+                bao_tree::io::sync::outboard_post_order_with_suffix(source, tree, &mut target)?;
+            target.flush()?;
             let dt = t0.elapsed();
             let rate = size as f64 / dt.as_secs_f64();
             println!("{}", hash);
@@ -24,14 +24,17 @@ use std::{
 use anyhow::Context;
 use bao_tree::{
     blake3,
-    io::{outboard::PreOrderMemOutboard, round_up_to_chunks, Leaf, Parent},
-    BlockSize, ChunkNum, ChunkRanges,
+    io::{
+        outboard::PreOrderMemOutboard,
+        ranges::{decode_chunk_ranges, encode_chunk_ranges, DEFAULT_MAX_RANGES},
+        round_up_to_chunks, Leaf, Parent,
+    },
+    BlockSize, ChunkRanges,
 };
 use bytes::Bytes;
 use clap::{Parser, Subcommand};
 use range_collections::RangeSet2;
 use serde::{Deserialize, Serialize};
-use smallvec::SmallVec;
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -111,7 +114,8 @@ struct Message {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct MessageWireFormat {
     hash: [u8; 32],
-    ranges: Vec<u64>,
+    /// Ranges in the compact encoding from [bao_tree::io::ranges].
+    ranges: Vec<u8>,
     encoded: Vec<u8>,
 }
 
@@ -119,7 +123,7 @@ impl From<Message> for MessageWireFormat {
     fn from(msg: Message) -> Self {
         Self {
             hash: msg.hash.into(),
-            ranges: msg.ranges.boundaries().iter().map(|b| b.0).collect(),
+            ranges: encode_chunk_ranges(&msg.ranges),
             encoded: msg.encoded,
         }
     }
@@ -130,12 +134,8 @@ impl TryFrom<MessageWireFormat> for Message {
 
     fn try_from(msg: MessageWireFormat) -> Result<Self, Self::Error> {
         let hash = blake3::Hash::from(msg.hash);
-        let ranges = msg
-            .ranges
-            .iter()
-            .map(|b| ChunkNum(*b))
-            .collect::<SmallVec<_>>();
-        let ranges = ChunkRanges::new(ranges).context("chunks not sorted")?;
+        let ranges =
+            decode_chunk_ranges(&msg.ranges, DEFAULT_MAX_RANGES).context("invalid ranges")?;
         Ok(Self {
             hash,
             ranges,
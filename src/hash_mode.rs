@@ -0,0 +1,179 @@
+//! BLAKE3 hash modes
+//!
+//! The default mode uses the tree internals of the blake3 crate we re-export.
+//! For keyed and derive key mode, the chunk group and parent hashes are
+//! computed with the `hazmat` api of the upstream blake3 crate.
+use std::fmt::{self, Debug};
+
+use blake3_hazmat::hazmat::{self, HasherExt};
+
+use crate::blake3;
+
+/// The BLAKE3 mode that is used to hash the data of a tree.
+///
+/// In the [default](HashMode::default) mode, the root hash of a tree is the
+/// plain BLAKE3 hash of the data. In [keyed](HashMode::keyed) mode, it is the
+/// keyed BLAKE3 hash, so the root hash works as a MAC, and nobody without the
+/// key can compute it from the data. [Derive key](HashMode::derive_key) mode
+/// is the BLAKE3 key derivation function, with the data as key material.
+///
+/// The mode is part of the [BaoTree](crate::BaoTree), and is used for all
+/// hashing when creating outboards, encoding, decoding and validating.
+///
+/// The [Debug] impl does not show the key, and comparing modes compares the
+/// keys in constant time.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct HashMode(Inner);
+
+#[derive(Clone, Copy, Eq)]
+enum Inner {
+    Default,
+    Keyed([u8; 32]),
+    /// The context key, see [hazmat::hash_derive_key_context]
+    DeriveKey([u8; 32]),
+}
+
+impl PartialEq for Inner {
+    fn eq(&self, other: &Self) -> bool {
+        // compare as hashes, which is constant time
+        let key = |key: &[u8; 32]| blake3::Hash::from(*key);
+        match (self, other) {
+            (Self::Default, Self::Default) => true,
+            (Self::Keyed(a), Self::Keyed(b)) => key(a) == key(b),
+            (Self::DeriveKey(a), Self::DeriveKey(b)) => key(a) == key(b),
+            _ => false,
+        }
+    }
+}
+
+impl Default for HashMode {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl Debug for HashMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Inner::Default => write!(f, "Default"),
+            Inner::Keyed(_) => write!(f, "Keyed(..)"),
+            Inner::DeriveKey(_) => write!(f, "DeriveKey(..)"),
+        }
+    }
+}
+
+impl HashMode {
+    /// The regular BLAKE3 hash function
+    pub const DEFAULT: Self = Self(Inner::Default);
+
+    /// The keyed BLAKE3 hash function, see [blake3::keyed_hash]
+    pub fn keyed(key: &[u8; 32]) -> Self {
+        Self(Inner::Keyed(*key))
+    }
+
+    /// The BLAKE3 key derivation function, see [blake3::derive_key]
+    ///
+    /// The context string should be hardcoded, globally unique, and
+    /// application-specific.
+    pub fn derive_key(context: &str) -> Self {
+        Self(Inner::DeriveKey(hazmat::hash_derive_key_context(context)))
+    }
+
+    /// True if this is the regular BLAKE3 hash function
+    pub fn is_default(&self) -> bool {
+        matches!(self.0, Inner::Default)
+    }
+
+    /// A tag that identifies the kind of mode, but not the key.
+    ///
+    /// 0 for the default mode, 1 for keyed and 2 for derive key mode.
+    pub(crate) fn tag(&self) -> u8 {
+        match self.0 {
+            Inner::Default => 0,
+            Inner::Keyed(_) => 1,
+            Inner::DeriveKey(_) => 2,
+        }
+    }
+
+    fn hazmat_mode(&self) -> hazmat::Mode<'_> {
+        match &self.0 {
+            Inner::Default => hazmat::Mode::Hash,
+            Inner::Keyed(key) => hazmat::Mode::KeyedHash(key),
+            Inner::DeriveKey(context_key) => hazmat::Mode::DeriveKeyMaterial(context_key),
+        }
+    }
+
+    fn hasher(&self) -> blake3_hazmat::Hasher {
+        match &self.0 {
+            Inner::Default => blake3_hazmat::Hasher::new(),
+            Inner::Keyed(key) => blake3_hazmat::Hasher::new_keyed(key),
+            Inner::DeriveKey(context_key) => {
+                blake3_hazmat::Hasher::new_from_context_key(context_key)
+            }
+        }
+    }
+
+    /// Compute the hash of a subtree consisting of one or many chunks.
+    pub(crate) fn hash_subtree(
+        &self,
+        start_chunk: u64,
+        data: &[u8],
+        is_root: bool,
+    ) -> blake3::Hash {
+        if self.is_default() {
+            return crate::hash_subtree(start_chunk, data, is_root);
+        }
+        let mut hasher = self.hasher();
+        if is_root {
+            if start_chunk != 0 {
+                return invalid_subtree();
+            }
+            hasher.update(data);
+            return blake3::Hash::from(*hasher.finalize().as_bytes());
+        }
+        // the hazmat api panics for subtrees that can not be part of a valid
+        // tree, so check for them before hashing
+        let Some(offset) = start_chunk.checked_mul(1024) else {
+            return invalid_subtree();
+        };
+        let max_len = hazmat::max_subtree_len(offset).unwrap_or(u64::MAX);
+        if data.is_empty() || data.len() as u64 > max_len {
+            return invalid_subtree();
+        }
+        hasher.set_input_offset(offset);
+        hasher.update(data);
+        blake3::Hash::from(hasher.finalize_non_root())
+    }
+
+    /// Compute the hash of a parent node from the hashes of its children.
+    pub(crate) fn parent_cv(
+        &self,
+        left: &blake3::Hash,
+        right: &blake3::Hash,
+        is_root: bool,
+    ) -> blake3::Hash {
+        if self.is_default() {
+            return blake3::guts::parent_cv(left, right, is_root);
+        }
+        let (left, right) = (left.as_bytes(), right.as_bytes());
+        if is_root {
+            let hash = hazmat::merge_subtrees_root(left, right, self.hazmat_mode());
+            blake3::Hash::from(*hash.as_bytes())
+        } else {
+            blake3::Hash::from(hazmat::merge_subtrees_non_root(
+                left,
+                right,
+                self.hazmat_mode(),
+            ))
+        }
+    }
+}
+
+/// The hash for a subtree that can not be part of a valid tree, such as an
+/// empty subtree that is not the root.
+///
+/// Expected hashes always come from a trusted root, so this will never match
+/// unless someone finds a preimage.
+fn invalid_subtree() -> blake3::Hash {
+    blake3::Hash::from([0u8; 32])
+}
@@ -0,0 +1,205 @@
+//! Compatibility with the formats of the [bao crate](https://crates.io/crates/bao)
+//!
+//! The bao crate always uses a block size of 1024 bytes, stores outboards in
+//! pre order and prefixes all encodings and outboards with the size of the
+//! data as a little endian u64. A bao slice is the encoding of a single byte
+//! range of the data.
+//!
+//! The functions in this module read and write these formats, so you can
+//! exchange data with implementations based on the bao crate or import
+//! existing `.bao` and `.obao` files.
+use std::io::{self, Read, Write};
+
+use positioned_io::{ReadAt, Size, Slice, WriteAt};
+
+use crate::{
+    blake3,
+    io::{
+        outboard::{PreOrderMemOutboard, PreOrderOutboard},
+        sync::{decode_ranges, encode_ranges_validated, DecodeResponseIter, Outboard},
+        BaoContentItem, Leaf,
+    },
+    BaoTree, BlockSize, ChunkNum, ChunkRanges,
+};
+
+/// Size of the size prefix of bao encodings and outboards
+pub const PREFIX_SIZE: usize = 8;
+
+/// Compute the combined encoding of the data, with the size prefix.
+///
+/// This is the same as `bao::encode::encode`.
+pub fn encode(data: impl AsRef<[u8]>) -> (Vec<u8>, blake3::Hash) {
+    let data = data.as_ref();
+    let outboard = PreOrderMemOutboard::create(data, BlockSize::ZERO);
+    let mut res = Vec::with_capacity(encoded_size(outboard.tree));
+    encode_with_outboard(data, &outboard, &mut res).unwrap();
+    (res, outboard.root)
+}
+
+/// Compute the outboard of the data, with the size prefix.
+///
+/// This is the same as `bao::encode::outboard`.
+pub fn outboard(data: impl AsRef<[u8]>) -> (Vec<u8>, blake3::Hash) {
+    let outboard = PreOrderMemOutboard::create(data, BlockSize::ZERO);
+    let root = outboard.root;
+    (outboard.into_inner_with_prefix(), root)
+}
+
+/// Write the combined encoding of the data, with the size prefix.
+///
+/// The outboard must use a block size of 1024 bytes and the default hash mode.
+/// The data is validated against the outboard while encoding.
+pub fn encode_with_outboard<D: ReadAt + Size, O: Outboard, W: Write>(
+    data: D,
+    outboard: O,
+    mut encoded: W,
+) -> io::Result<()> {
+    let tree = outboard.tree();
+    check_tree(tree)?;
+    encoded.write_all(&tree.size.to_le_bytes())?;
+    encode_ranges_validated(data, outboard, &ChunkRanges::all(), encoded)?;
+    Ok(())
+}
+
+/// Open an outboard in the bao format, with the size prefix.
+///
+/// The size of the data is taken from the prefix. Fails with
+/// [io::ErrorKind::InvalidData] if the size of the outboard does not match.
+pub fn open_outboard<D: ReadAt + Size>(
+    data: D,
+    root: blake3::Hash,
+) -> io::Result<PreOrderOutboard<Slice<D>>> {
+    let mut prefix = [0u8; PREFIX_SIZE];
+    data.read_exact_at(0, &mut prefix).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            invalid_data("outboard is too short to contain the size prefix")
+        } else {
+            e
+        }
+    })?;
+    let tree = BaoTree::new(u64::from_le_bytes(prefix), BlockSize::ZERO);
+    let outboard_size = tree.outboard_size();
+    if data.size()? != Some(PREFIX_SIZE as u64 + outboard_size) {
+        return Err(invalid_data("outboard size does not match the size prefix"));
+    }
+    Ok(PreOrderOutboard {
+        root,
+        tree,
+        data: Slice::new(data, PREFIX_SIZE as u64, Some(outboard_size)),
+    })
+}
+
+/// Decode a combined encoding, with the size prefix.
+///
+/// The data is written to `target`. Returns the outboard, which can be saved
+/// or used to serve the data again.
+pub fn decode<R: Read, W: WriteAt>(
+    mut encoded: R,
+    root: blake3::Hash,
+    target: W,
+) -> io::Result<PreOrderOutboard> {
+    let size = read_prefix(&mut encoded)?;
+    let mut outboard = PreOrderOutboard {
+        root,
+        tree: BaoTree::new(size, BlockSize::ZERO),
+        data: Vec::new(),
+    };
+    decode_ranges(encoded, &ChunkRanges::all(), target, &mut outboard)?;
+    Ok(outboard)
+}
+
+/// Write a slice of the data, with the size prefix.
+///
+/// The slice contains all chunks that overlap the byte range `slice_start`
+/// with length `slice_len`. Like in the bao crate, a slice is never empty. If
+/// the byte range is empty or starts after the end of the data, the slice
+/// contains the chunk at the start or the last chunk of the data, respectively.
+///
+/// This is the same as `bao::encode::SliceExtractor`.
+pub fn extract_slice<D: ReadAt + Size, O: Outboard, W: Write>(
+    data: D,
+    outboard: O,
+    slice_start: u64,
+    slice_len: u64,
+    mut encoded: W,
+) -> io::Result<()> {
+    let tree = outboard.tree();
+    check_tree(tree)?;
+    let ranges = slice_ranges(tree.size, slice_start, slice_len);
+    encoded.write_all(&tree.size.to_le_bytes())?;
+    encode_ranges_validated(data, outboard, &ranges, encoded)?;
+    Ok(())
+}
+
+/// Decode a slice, with the size prefix.
+///
+/// Only the bytes of the data within the byte range `slice_start` with length
+/// `slice_len` are written to `target`. Returns the number of bytes written.
+///
+/// This is the same as `bao::decode::SliceDecoder`.
+pub fn decode_slice<R: Read, W: Write>(
+    mut encoded: R,
+    root: blake3::Hash,
+    slice_start: u64,
+    slice_len: u64,
+    mut target: W,
+) -> io::Result<u64> {
+    let size = read_prefix(&mut encoded)?;
+    let tree = BaoTree::new(size, BlockSize::ZERO);
+    let ranges = slice_ranges(size, slice_start, slice_len);
+    let start = slice_start.min(size);
+    let end = slice_start.saturating_add(slice_len).min(size);
+    let mut written = 0;
+    for item in DecodeResponseIter::new(root, tree, encoded, &ranges) {
+        if let BaoContentItem::Leaf(Leaf { offset, data }) = item? {
+            let leaf_end = offset + data.len() as u64;
+            if leaf_end <= start || offset >= end {
+                continue;
+            }
+            let from = start.saturating_sub(offset) as usize;
+            let to = (end.min(leaf_end) - offset) as usize;
+            target.write_all(&data[from..to])?;
+            written += (to - from) as u64;
+        }
+    }
+    Ok(written)
+}
+
+/// The chunk ranges of a bao slice.
+fn slice_ranges(size: u64, slice_start: u64, slice_len: u64) -> ChunkRanges {
+    let start = if slice_start >= size {
+        // the last chunk, to prove the size
+        ChunkNum::chunks(size).max(ChunkNum(1)) - 1
+    } else {
+        ChunkNum::full_chunks(slice_start)
+    };
+    let end = ChunkNum::chunks(slice_start.saturating_add(slice_len.max(1)).min(size));
+    ChunkRanges::from(start..end.max(start + 1))
+}
+
+/// Size of the combined encoding of a tree, including the size prefix.
+fn encoded_size(tree: BaoTree) -> usize {
+    (PREFIX_SIZE as u64 + tree.outboard_size() + tree.size)
+        .try_into()
+        .unwrap()
+}
+
+fn read_prefix(mut encoded: impl Read) -> io::Result<u64> {
+    let mut prefix = [0u8; PREFIX_SIZE];
+    encoded.read_exact(&mut prefix)?;
+    Ok(u64::from_le_bytes(prefix))
+}
+
+fn check_tree(tree: BaoTree) -> io::Result<()> {
+    if tree.block_size != BlockSize::ZERO || !tree.mode.is_default() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "bao compatibility requires a block size of 1024 bytes and the default hash mode",
+        ));
+    }
+    Ok(())
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,239 @@
+//! Coordinate downloading a blob from multiple peers
+//!
+//! A [DownloadCoordinator] keeps track of which chunk groups of a blob are
+//! wanted, which have been received, and which have been requested from which
+//! peer. It does not do any io itself. The typical loop is:
+//!
+//! - call [DownloadCoordinator::plan] to get a request for each idle peer,
+//! - decode the responses, e.g. using [ResponseDecoder](crate::io::fsm::ResponseDecoder),
+//!   and pass every item to [DownloadCoordinator::received] (or
+//!   [DownloadCoordinator::write] to also store it),
+//! - call [DownloadCoordinator::finished] or [DownloadCoordinator::failed]
+//!   when a response ends, and plan again,
+//!
+//! until [DownloadCoordinator::is_complete] returns true.
+//!
+//! Since the items of a response are validated against the root hash before
+//! they are returned by the decoder, items from different peers can be written
+//! to the same target and outboard in any order.
+use std::collections::{BTreeMap, BTreeSet};
+
+use range_collections::range_set::RangeSetRange;
+
+use crate::{
+    blake3,
+    io::{round_up_to_chunk_groups, BaoContentItem, DecodeError, Leaf},
+    rec::truncate_ranges,
+    BaoTree, ChunkNum, ChunkRanges, ChunkRangesRef,
+};
+
+/// Keeps track of a download of a blob from multiple peers.
+///
+/// `P` is the type used to identify peers.
+#[derive(Debug, Clone)]
+pub struct DownloadCoordinator<P> {
+    root: blake3::Hash,
+    tree: BaoTree,
+    /// the ranges we want, rounded up to chunk groups and truncated to the blob
+    wanted: ChunkRanges,
+    /// the ranges we have received
+    received: ChunkRanges,
+    /// the ranges currently requested from each peer
+    requests: BTreeMap<P, ChunkRanges>,
+    /// peers that sent invalid data
+    banned: BTreeSet<P>,
+}
+
+impl<P: Ord + Clone> DownloadCoordinator<P> {
+    /// Create a new coordinator for downloading the given ranges of a blob.
+    ///
+    /// The ranges are rounded up to chunk groups, since that is the
+    /// granularity at which the outboard can store proofs. Like for encoding,
+    /// ranges past the end of the blob are treated as a request for the last chunk.
+    pub fn new(root: blake3::Hash, tree: BaoTree, ranges: &ChunkRangesRef) -> Self {
+        let ranges = truncate_ranges(ranges, tree.size);
+        // even an empty blob has a single chunk, which proves its size
+        let end = tree.chunks().max(ChunkNum(1));
+        let wanted = round_up_to_chunk_groups(ranges, tree.block_size) & ChunkRanges::from(..end);
+        Self {
+            root,
+            tree,
+            wanted,
+            received: ChunkRanges::empty(),
+            requests: BTreeMap::new(),
+            banned: BTreeSet::new(),
+        }
+    }
+
+    /// The root hash of the blob
+    pub fn root(&self) -> blake3::Hash {
+        self.root
+    }
+
+    /// The geometry of the blob
+    pub fn tree(&self) -> BaoTree {
+        self.tree
+    }
+
+    /// The ranges that have been received so far
+    pub fn received_ranges(&self) -> &ChunkRangesRef {
+        &self.received
+    }
+
+    /// The ranges that are neither received nor currently requested from any peer
+    pub fn missing(&self) -> ChunkRanges {
+        let mut res = &self.wanted - &self.received;
+        for ranges in self.requests.values() {
+            res = &res - ranges;
+        }
+        res
+    }
+
+    /// The ranges currently requested from a peer, if any
+    pub fn request(&self, peer: &P) -> Option<&ChunkRangesRef> {
+        self.requests.get(peer).map(|x| x.as_ref())
+    }
+
+    /// True if the peer has sent invalid data
+    pub fn is_banned(&self, peer: &P) -> bool {
+        self.banned.contains(peer)
+    }
+
+    /// True if all wanted ranges have been received
+    pub fn is_complete(&self) -> bool {
+        self.wanted.is_subset(&self.received)
+    }
+
+    /// Split the missing ranges into requests for the given peers.
+    ///
+    /// Peers that are banned or already have a request are skipped. The missing
+    /// chunk groups are split into contiguous parts of roughly equal size, one
+    /// per remaining peer. Returns the new requests, which are recorded as in
+    /// flight until [Self::finished] or [Self::failed] is called for the peer.
+    pub fn plan(&mut self, peers: impl IntoIterator<Item = P>) -> Vec<(P, ChunkRanges)> {
+        let peers = peers
+            .into_iter()
+            .filter(|peer| !self.banned.contains(peer) && !self.requests.contains_key(peer))
+            .collect::<BTreeSet<_>>();
+        let mut missing = self.missing();
+        let group_chunks = 1u64 << self.tree.block_size.0;
+        let total = count_groups(&missing, group_chunks);
+        if total == 0 || peers.is_empty() {
+            return Vec::new();
+        }
+        let per_peer = total.div_ceil(peers.len() as u64);
+        let mut res = Vec::new();
+        for peer in peers {
+            if missing.is_empty() {
+                break;
+            }
+            let part = take_groups(&missing, per_peer, group_chunks);
+            missing = &missing - &part;
+            self.requests.insert(peer.clone(), part.clone());
+            res.push((peer, part));
+        }
+        res
+    }
+
+    /// Record an item that was received from a peer.
+    ///
+    /// The item must have been validated, e.g. by a
+    /// [ResponseDecoder](crate::io::fsm::ResponseDecoder). Parents do not
+    /// change the state, leaves mark the chunks they cover as received.
+    pub fn received(&mut self, peer: &P, item: &BaoContentItem) {
+        let BaoContentItem::Leaf(Leaf { offset, data }) = item else {
+            return;
+        };
+        let start = ChunkNum::full_chunks(*offset);
+        let end = ChunkNum::chunks(*offset + data.len() as u64).max(start + 1);
+        let ranges = ChunkRanges::from(start..end);
+        if let Some(request) = self.requests.get_mut(peer) {
+            *request = &*request - &ranges;
+        }
+        self.received |= ranges;
+    }
+
+    /// A response from a peer has ended successfully.
+    ///
+    /// Anything that was requested from the peer but not received becomes
+    /// missing again.
+    pub fn finished(&mut self, peer: &P) {
+        self.requests.remove(peer);
+    }
+
+    /// A response from a peer failed.
+    ///
+    /// Anything that was requested from the peer but not received becomes
+    /// missing again. If the peer sent data that does not match the root hash,
+    /// it is banned and will not be used by [Self::plan] again. Io errors,
+    /// including responses that end early, do not ban the peer.
+    pub fn failed(&mut self, peer: &P, error: &DecodeError) {
+        self.requests.remove(peer);
+        if matches!(
+            error,
+            DecodeError::ParentHashMismatch(_)
+                | DecodeError::LeafHashMismatch(_)
+                | DecodeError::SizeMismatch
+        ) {
+            self.banned.insert(peer.clone());
+        }
+    }
+
+    /// Record an item that was received from a peer, and write it to a
+    /// target and outboard.
+    ///
+    /// Parents are saved to the outboard, leaves are written to the target.
+    #[cfg(feature = "tokio_fsm")]
+    pub async fn write(
+        &mut self,
+        peer: &P,
+        item: BaoContentItem,
+        mut target: impl iroh_io::AsyncSliceWriter,
+        mut outboard: impl crate::io::fsm::OutboardMut,
+    ) -> std::io::Result<()> {
+        match &item {
+            BaoContentItem::Parent(parent) => {
+                outboard.save(parent.node, &parent.pair).await?;
+            }
+            BaoContentItem::Leaf(leaf) => {
+                target
+                    .write_bytes_at(leaf.offset, leaf.data.clone())
+                    .await?;
+            }
+        }
+        self.received(peer, &item);
+        Ok(())
+    }
+}
+
+/// Count the chunk groups in a set of bounded, chunk group aligned ranges.
+///
+/// The last range may end in the middle of a chunk group at the end of the blob.
+fn count_groups(ranges: &ChunkRangesRef, group_chunks: u64) -> u64 {
+    ranges
+        .iter()
+        .map(|range| match range {
+            RangeSetRange::Range(range) => (range.end.0 - range.start.0).div_ceil(group_chunks),
+            RangeSetRange::RangeFrom(_) => unreachable!("ranges are bounded"),
+        })
+        .sum()
+}
+
+/// Take the first `n` chunk groups from a set of bounded, chunk group aligned ranges
+fn take_groups(ranges: &ChunkRangesRef, mut n: u64, group_chunks: u64) -> ChunkRanges {
+    let mut res = ChunkRanges::empty();
+    for range in ranges.iter() {
+        if n == 0 {
+            break;
+        }
+        let RangeSetRange::Range(range) = range else {
+            unreachable!("ranges are bounded");
+        };
+        let groups = (range.end.0 - range.start.0).div_ceil(group_chunks);
+        let take = groups.min(n);
+        let end = (range.start.0 + take * group_chunks).min(range.end.0);
+        res |= ChunkRanges::from(*range.start..ChunkNum(end));
+        n -= take;
+    }
+    res
+}
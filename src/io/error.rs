@@ -1,11 +1,12 @@
 //! Errors when encoding or decoding
 //!
 //! These erros contain more specific information about e.g. where a hash mismatch occured
-use crate::{ChunkNum, TreeNode};
+use crate::{BaoTree, ChunkNum, TreeNode};
 use std::{fmt, io};
 
 /// Error when decoding from a reader, after the size has been read
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum DecodeError {
     /// We got an EOF while reading a parent hash pair, indicating that the remote end does not have the outboard
     ParentNotFound(TreeNode),
@@ -15,6 +16,8 @@ pub enum DecodeError {
     ParentHashMismatch(TreeNode),
     /// The hash of a leaf did not match the expected hash
     LeafHashMismatch(ChunkNum),
+    /// A size proof did not match the claimed size
+    SizeMismatch,
     /// There was an error reading from the underlying io
     Io(io::Error),
 }
@@ -56,6 +59,9 @@ impl From<DecodeError> for io::Error {
                 io::ErrorKind::InvalidData,
                 format!("leaf hash mismatch (offset {})", chunk.to_bytes()),
             ),
+            DecodeError::SizeMismatch => {
+                io::Error::new(io::ErrorKind::InvalidData, "size mismatch")
+            }
             DecodeError::LeafNotFound(_) => io::Error::new(io::ErrorKind::UnexpectedEof, e),
             DecodeError::ParentNotFound(_) => io::Error::new(io::ErrorKind::UnexpectedEof, e),
         }
@@ -78,6 +84,21 @@ impl DecodeError {
             Self::Io(e)
         }
     }
+
+    /// When verifying a size proof, the tree is computed from the claimed size.
+    ///
+    /// A mismatch of the last leaf means that the claimed size is wrong, and so
+    /// does a proof that ends early, since a proof for a claimed size that is too
+    /// large needs more data than the sender has. Mismatches of parents are
+    /// kept, since they can also be caused by a corrupted proof.
+    pub(crate) fn into_size_mismatch(self, tree: &BaoTree) -> Self {
+        let last_block = ChunkNum((tree.blocks() - 1) << tree.block_size.0);
+        match self {
+            Self::LeafHashMismatch(chunk) if chunk >= last_block => Self::SizeMismatch,
+            Self::ParentNotFound(_) | Self::LeafNotFound(_) => Self::SizeMismatch,
+            e => e,
+        }
+    }
 }
 
 /// Error when encoding from outboard and data
@@ -86,11 +107,14 @@ impl DecodeError {
 /// or a size mismatch. If the remote end stops listening while we are writing,
 /// the error will indicate which parent or chunk we were writing at the time.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum EncodeError {
     /// The hash of a parent did not match the expected hash
     ParentHashMismatch(TreeNode),
     /// The hash of a leaf did not match the expected hash
     LeafHashMismatch(ChunkNum),
+    /// The outboard does not contain the hash pair for a parent
+    ParentNotFound(TreeNode),
     /// We got a ConnectionReset while writing a parent hash pair, indicating that the remote end stopped listening
     ParentWrite(TreeNode),
     /// We got a ConnectionReset while writing a chunk, indicating that the remote end stopped listening
@@ -132,6 +156,14 @@ impl From<EncodeError> for io::Error {
                 io::ErrorKind::InvalidData,
                 format!("leaf hash mismatch at {}", chunk.to_bytes()),
             ),
+            EncodeError::ParentNotFound(node) => io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "parent not found (level {}, block {})",
+                    node.level(),
+                    node.mid().0
+                ),
+            ),
             EncodeError::ParentWrite(node) => io::Error::new(
                 io::ErrorKind::ConnectionReset,
                 format!(
@@ -11,17 +11,18 @@
 use std::{
     future::Future,
     io::{self, Cursor},
+    ops::Range,
     result,
 };
 
 use crate::{
-    blake3, hash_subtree,
+    blake3,
+    io::LocalBoxFuture,
     iter::ResponseIter,
     rec::{encode_selected_rec, truncate_ranges, truncate_ranges_owned},
-    ChunkRanges, ChunkRangesRef,
+    ChunkNum, ChunkRanges, ChunkRangesRef,
 };
-use blake3::guts::parent_cv;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use iroh_io::{AsyncStreamReader, AsyncStreamWriter};
 use smallvec::SmallVec;
 
@@ -29,15 +30,21 @@ pub use super::BaoContentItem;
 use crate::{
     io::{
         error::EncodeError,
-        outboard::{PostOrderOutboard, PreOrderOutboard},
+        hasher::PostOrderHasher,
+        outboard::{
+            Header, HeaderOutboard, OutboardOrder, PostOrderOutboard, PreOrderOutboard, HEADER_SIZE,
+        },
         Leaf, Parent,
     },
     iter::BaoChunk,
-    BaoTree, BlockSize, TreeNode,
+    BaoTree, BlockSize, HashMode, TreeNode,
 };
 pub use iroh_io::{AsyncSliceReader, AsyncSliceWriter};
 
-use super::{combine_hash_pair, DecodeError};
+use super::{
+    available_ranges, check_stack_depth, combine_hash_pair, hash_pairs_group, skip_len,
+    skip_to_offset, DecodeError, DecoderState,
+};
 
 /// A binary merkle tree for blake3 hashes of a blob.
 ///
@@ -130,13 +137,14 @@ pub trait CreateOutboard {
     /// tree and only init the data and set the root hash.
     ///
     /// So this can be used to initialize an outboard that does not have a default,
-    /// such as a file based one.
+    /// such as a file based one. It can also be used to create an outboard with a
+    /// non default [HashMode], by setting the tree before.
     ///
     /// It will only include data up the the current tree size.
     fn init_from(&mut self, data: impl AsyncStreamReader) -> impl Future<Output = io::Result<()>>;
 }
 
-impl<'b, O: Outboard> Outboard for &'b mut O {
+impl<O: Outboard> Outboard for &mut O {
     fn root(&self) -> blake3::Hash {
         (**self).root()
     }
@@ -173,7 +181,7 @@ impl<R: AsyncSliceReader> Outboard for PreOrderOutboard<R> {
     }
 }
 
-impl<'b, O: OutboardMut> OutboardMut for &'b mut O {
+impl<O: OutboardMut> OutboardMut for &mut O {
     async fn save(
         &mut self,
         node: TreeNode,
@@ -322,6 +330,7 @@ pub(crate) fn parse_hash_pair(buf: Bytes) -> io::Result<(blake3::Hash, blake3::H
 struct ResponseDecoderInner<R> {
     iter: ResponseIter,
     stack: SmallVec<[blake3::Hash; 10]>,
+    encoded_offset: u64,
     encoded: R,
 }
 
@@ -332,6 +341,7 @@ impl<R> ResponseDecoderInner<R> {
         let mut res = Self {
             iter: ResponseIter::new(tree, ranges),
             stack: SmallVec::new(),
+            encoded_offset: 0,
             encoded,
         };
         res.stack.push(hash);
@@ -367,6 +377,69 @@ impl<R: AsyncStreamReader> ResponseDecoder<R> {
         )))
     }
 
+    /// Create a new response decoder state machine for a response that starts
+    /// with the size of the blob, as produced by [encode_ranges_with_size_proof].
+    ///
+    /// This reads the size prefix and verifies the size proof that follows it,
+    /// so the tree is known to be correct before any data is yielded. If the
+    /// proof does not match the claimed size, this fails with
+    /// [DecodeError::SizeMismatch].
+    ///
+    /// The tree is created with the given block size and hash mode.
+    ///
+    /// This is a different wire format than a response that consists of just
+    /// the size and the encoded ranges, so it can only decode responses that
+    /// contain the size proof. Both sides have to agree on the format.
+    pub async fn new_with_size_proof(
+        hash: blake3::Hash,
+        ranges: ChunkRanges,
+        block_size: BlockSize,
+        mode: HashMode,
+        mut encoded: R,
+    ) -> result::Result<Self, DecodeError> {
+        let size = u64::from_le_bytes(encoded.read::<8>().await?);
+        let tree = verify_size_proof(&mut encoded, hash, size, block_size, mode).await?;
+        Ok(Self::new(hash, ranges, tree, encoded))
+    }
+
+    /// Resume decoding from a state that was obtained using [Self::state].
+    ///
+    /// The reader must provide the encoded stream starting at
+    /// [DecoderState::encoded_offset], e.g. as produced by [encode_ranges_validated_from].
+    ///
+    /// Fails if the offset is not at a chunk boundary of the response, or if
+    /// the stack of the state does not have the depth needed at that offset.
+    pub fn resume(state: DecoderState, encoded: R) -> io::Result<Self> {
+        let DecoderState {
+            tree,
+            ranges,
+            encoded_offset,
+            stack,
+        } = state;
+        let ranges = truncate_ranges_owned(ranges, tree.size());
+        let mut iter = ResponseIter::new(tree, ranges);
+        let depth = skip_to_offset(&mut iter, encoded_offset)?;
+        check_stack_depth(&stack, depth)?;
+        Ok(Self(Box::new(ResponseDecoderInner {
+            iter,
+            stack: stack.into_iter().collect(),
+            encoded_offset,
+            encoded,
+        })))
+    }
+
+    /// The current state of the decoder, which can be persisted to resume later.
+    ///
+    /// See [DecoderState] for details.
+    pub fn state(&self) -> DecoderState {
+        DecoderState {
+            tree: self.tree(),
+            ranges: ChunkRanges::new_unchecked(self.0.iter.ranges().boundaries().into()),
+            encoded_offset: self.0.encoded_offset,
+            stack: self.0.stack.to_vec(),
+        }
+    }
+
     /// Proceed to the next state by reading the next chunk from the stream.
     pub async fn next(mut self) -> ResponseDecoderNext<R> {
         if let Some(chunk) = self.0.iter.next() {
@@ -409,7 +482,7 @@ impl<R: AsyncStreamReader> ResponseDecoder<R> {
                     .map_err(|e| DecodeError::maybe_parent_not_found(e, node))?;
                 let pair @ (l_hash, r_hash) = read_parent(&buf);
                 let parent_hash = this.stack.pop().unwrap();
-                let actual = parent_cv(&l_hash, &r_hash, is_root);
+                let actual = this.iter.tree().mode.parent_cv(&l_hash, &r_hash, is_root);
                 // Push the children in reverse order so they are popped in the correct order
                 // only push right if the range intersects with the right child
                 if right {
@@ -423,6 +496,7 @@ impl<R: AsyncStreamReader> ResponseDecoder<R> {
                 if parent_hash != actual {
                     return Err(DecodeError::ParentHashMismatch(node));
                 }
+                this.encoded_offset += 64;
                 Parent { pair, node }.into()
             }
             BaoChunk::Leaf {
@@ -433,16 +507,19 @@ impl<R: AsyncStreamReader> ResponseDecoder<R> {
             } => {
                 // this will resize always to chunk group size, except for the last chunk
                 let this = &mut self.0;
-                let data = this
-                    .encoded
-                    .read_bytes(size)
+                let data = read_bytes_exact(&mut this.encoded, size)
                     .await
                     .map_err(|e| DecodeError::maybe_leaf_not_found(e, start_chunk))?;
                 let leaf_hash = this.stack.pop().unwrap();
-                let actual = hash_subtree(start_chunk.0, &data, is_root);
+                let actual = this
+                    .iter
+                    .tree()
+                    .mode
+                    .hash_subtree(start_chunk.0, &data, is_root);
                 if leaf_hash != actual {
                     return Err(DecodeError::LeafHashMismatch(start_chunk));
                 }
+                this.encoded_offset += size as u64;
                 Leaf {
                     offset: start_chunk.to_bytes(),
                     data,
@@ -460,10 +537,31 @@ impl<R: AsyncStreamReader> ResponseDecoder<R> {
 /// It is possible to encode ranges from a partial file and outboard.
 /// This will either succeed if the requested ranges are all present, or fail
 /// as soon as a range is missing.
+/// To encode just the ranges that are present, use [encode_ranges_available].
 pub async fn encode_ranges<D, O, W>(
+    data: D,
+    outboard: O,
+    ranges: &ChunkRangesRef,
+    encoded: W,
+) -> result::Result<(), EncodeError>
+where
+    D: AsyncSliceReader,
+    O: Outboard,
+    W: AsyncStreamWriter,
+{
+    encode_ranges_from(data, outboard, ranges, 0, encoded).await
+}
+
+/// Encode ranges relevant to a query, starting at a byte offset in the encoded stream
+///
+/// The output is the output of [encode_ranges] without the first `offset` bytes.
+/// This can be used to resume an interrupted transfer. Data before the offset
+/// is not read unless it is needed to encode a partially requested chunk group.
+pub async fn encode_ranges_from<D, O, W>(
     mut data: D,
     mut outboard: O,
     ranges: &ChunkRangesRef,
+    offset: u64,
     encoded: W,
 ) -> result::Result<(), EncodeError>
 where
@@ -473,25 +571,66 @@ where
 {
     let mut encoded = encoded;
     let tree = outboard.tree();
+    // buffer for writing incomplete subtrees.
+    // for queries that don't have incomplete subtrees, this will never be used.
+    let mut out_buf = Vec::new();
+    // current offset in the encoded stream
+    let mut current = 0u64;
+    let ranges = truncate_ranges(ranges, tree.size());
     for item in tree.ranges_pre_order_chunks_iter_ref(ranges, 0) {
         match item {
             BaoChunk::Parent { node, .. } => {
-                let (l_hash, r_hash) = outboard.load(node).await?.unwrap();
+                if current + 64 <= offset {
+                    current += 64;
+                    continue;
+                }
+                let (l_hash, r_hash) = outboard
+                    .load(node)
+                    .await?
+                    .ok_or(EncodeError::ParentNotFound(node))?;
                 let pair = combine_hash_pair(&l_hash, &r_hash);
                 encoded
-                    .write(&pair)
+                    .write(&pair[skip_len(current, 64, offset)..])
                     .await
                     .map_err(|e| EncodeError::maybe_parent_write(e, node))?;
+                current += 64;
             }
             BaoChunk::Leaf {
-                start_chunk, size, ..
+                start_chunk,
+                size,
+                is_root,
+                ranges,
             } => {
+                if ranges.is_all() && current + size as u64 <= offset {
+                    current += size as u64;
+                    continue;
+                }
                 let start = start_chunk.to_bytes();
                 let bytes = data.read_at(start, size).await?;
+                let to_write: Bytes = if !ranges.is_all() {
+                    // we need to encode just a part of the data, including the
+                    // hashes below the chunk group level, so we have to hash.
+                    out_buf.clear();
+                    encode_selected_rec(
+                        tree.mode,
+                        start_chunk,
+                        &bytes,
+                        is_root,
+                        ranges,
+                        tree.block_size.to_u32(),
+                        true,
+                        &mut out_buf,
+                    );
+                    out_buf.clone().into()
+                } else {
+                    bytes
+                };
+                let len = to_write.len();
                 encoded
-                    .write_bytes(bytes)
+                    .write_bytes(to_write.slice(skip_len(current, len, offset)..))
                     .await
                     .map_err(|e| EncodeError::maybe_leaf_write(e, start_chunk))?;
+                current += len as u64;
             }
         }
     }
@@ -505,10 +644,62 @@ where
 /// It is possible to encode ranges from a partial file and outboard.
 /// This will either succeed if the requested ranges are all present, or fail
 /// as soon as a range is missing.
+/// To encode just the ranges that are present, use [encode_ranges_available].
 pub async fn encode_ranges_validated<D, O, W>(
+    data: D,
+    outboard: O,
+    ranges: &ChunkRangesRef,
+    encoded: W,
+) -> result::Result<(), EncodeError>
+where
+    D: AsyncSliceReader,
+    O: Outboard,
+    W: AsyncStreamWriter,
+{
+    encode_ranges_validated_from(data, outboard, ranges, 0, encoded).await
+}
+
+/// Encode the part of a query that is available from a partial blob
+///
+/// `available` are the chunks of the data that are present and valid.
+/// Only the part of `ranges` that is covered by complete chunk groups in
+/// `available` is encoded, using [encode_ranges_validated]. Returns the ranges
+/// that were encoded. The receiver must decode the response using these ranges.
+///
+/// If the outboard is missing hash pairs for the available data, this fails with
+/// [EncodeError::ParentNotFound].
+pub async fn encode_ranges_available<D, O, W>(
+    data: D,
+    outboard: O,
+    ranges: &ChunkRangesRef,
+    available: &ChunkRangesRef,
+    encoded: W,
+) -> result::Result<ChunkRanges, EncodeError>
+where
+    D: AsyncSliceReader,
+    O: Outboard,
+    W: AsyncStreamWriter,
+{
+    let ranges = available_ranges(&outboard.tree(), ranges, available);
+    if !ranges.is_empty() {
+        encode_ranges_validated(data, outboard, &ranges, encoded).await?;
+    }
+    Ok(ranges)
+}
+
+/// Encode ranges relevant to a query, starting at a byte offset in the encoded stream
+///
+/// The output is the output of [encode_ranges_validated] without the first
+/// `offset` bytes. This can be used to resume an interrupted transfer, see
+/// [ResponseDecoder::resume].
+///
+/// Parents before the offset are still loaded and validated, but full chunk
+/// groups before the offset are neither read nor validated.
+pub async fn encode_ranges_validated_from<D, O, W>(
     mut data: D,
     mut outboard: O,
     ranges: &ChunkRangesRef,
+    offset: u64,
     encoded: W,
 ) -> result::Result<(), EncodeError>
 where
@@ -523,6 +714,8 @@ where
     stack.push(outboard.root());
     let mut encoded = encoded;
     let tree = outboard.tree();
+    // current offset in the encoded stream
+    let mut current = 0u64;
     let ranges = truncate_ranges(ranges, tree.size());
     for item in tree.ranges_pre_order_chunks_iter_ref(ranges, 0) {
         match item {
@@ -533,8 +726,11 @@ where
                 node,
                 ..
             } => {
-                let (l_hash, r_hash) = outboard.load(node).await?.unwrap();
-                let actual = parent_cv(&l_hash, &r_hash, is_root);
+                let (l_hash, r_hash) = outboard
+                    .load(node)
+                    .await?
+                    .ok_or(EncodeError::ParentNotFound(node))?;
+                let actual = tree.mode.parent_cv(&l_hash, &r_hash, is_root);
                 let expected = stack.pop().unwrap();
                 if actual != expected {
                     return Err(EncodeError::ParentHashMismatch(node));
@@ -547,9 +743,10 @@ where
                 }
                 let pair = combine_hash_pair(&l_hash, &r_hash);
                 encoded
-                    .write(&pair)
+                    .write(&pair[skip_len(current, 64, offset)..])
                     .await
                     .map_err(|e| EncodeError::maybe_parent_write(e, node))?;
+                current += 64;
             }
             BaoChunk::Leaf {
                 start_chunk,
@@ -559,6 +756,10 @@ where
                 ..
             } => {
                 let expected = stack.pop().unwrap();
+                if ranges.is_all() && current + size as u64 <= offset {
+                    current += size as u64;
+                    continue;
+                }
                 let start = start_chunk.to_bytes();
                 let bytes = data.read_at(start, size).await?;
                 let (actual, to_write) = if !ranges.is_all() {
@@ -568,6 +769,7 @@ where
                     // before writing to the output.
                     out_buf.clear();
                     let actual = encode_selected_rec(
+                        tree.mode,
                         start_chunk,
                         &bytes,
                         is_root,
@@ -578,16 +780,18 @@ where
                     );
                     (actual, out_buf.clone().into())
                 } else {
-                    let actual = hash_subtree(start_chunk.0, &bytes, is_root);
+                    let actual = tree.mode.hash_subtree(start_chunk.0, &bytes, is_root);
                     (actual, bytes)
                 };
                 if actual != expected {
                     return Err(EncodeError::LeafHashMismatch(start_chunk));
                 }
+                let len = to_write.len();
                 encoded
-                    .write_bytes(to_write)
+                    .write_bytes(to_write.slice(skip_len(current, len, offset)..))
                     .await
                     .map_err(|e| EncodeError::maybe_leaf_write(e, start_chunk))?;
+                current += len as u64;
             }
         }
     }
@@ -629,6 +833,92 @@ where
     }
     Ok(())
 }
+
+/// Encode a proof for the size of the blob from a reader and outboard to a writer.
+///
+/// The proof consists of the last chunk of the blob and the hash pairs on the
+/// path from the root to it. It is the same as encoding the ranges
+/// `ChunkNum(u64::MAX)..`, since any range past the end of the blob is
+/// treated as a request for the last chunk.
+///
+/// This function validates the data before writing.
+pub async fn encode_size_proof<D, O, W>(
+    data: D,
+    outboard: O,
+    encoded: W,
+) -> result::Result<(), EncodeError>
+where
+    D: AsyncSliceReader,
+    O: Outboard,
+    W: AsyncStreamWriter,
+{
+    let ranges = ChunkRanges::from(ChunkNum(u64::MAX)..);
+    encode_ranges_validated(data, outboard, &ranges, encoded).await
+}
+
+/// Verify a size proof that was produced by [encode_size_proof].
+///
+/// The tree for the claimed size is created with the given block size and
+/// hash mode, which must match the ones used for encoding.
+///
+/// On success, returns the tree for the claimed size. If the last chunk does
+/// not match the claimed size, or the proof ends before the last chunk, e.g.
+/// because the claimed size is too large, this fails with
+/// [DecodeError::SizeMismatch].
+///
+/// If the hash pairs on the path to the last chunk do not match, this fails
+/// with [DecodeError::ParentHashMismatch]. This can be caused by a wrong claimed
+/// size as well as by a corrupted proof.
+pub async fn verify_size_proof<R: AsyncStreamReader>(
+    encoded: R,
+    root: blake3::Hash,
+    size: u64,
+    block_size: BlockSize,
+    mode: HashMode,
+) -> result::Result<BaoTree, DecodeError> {
+    let tree = BaoTree::new_with_mode(size, block_size, mode);
+    let ranges = ChunkRanges::from(ChunkNum(u64::MAX)..);
+    let mut reading = ResponseDecoder::new(root, ranges, tree, encoded);
+    loop {
+        match reading.next().await {
+            ResponseDecoderNext::Done(_reader) => break,
+            ResponseDecoderNext::More((next, item)) => {
+                reading = next;
+                item.map_err(|e| e.into_size_mismatch(&tree))?;
+            }
+        }
+    }
+    Ok(tree)
+}
+
+/// Encode ranges relevant to a query, preceded by the size of the blob and a
+/// size proof.
+///
+/// The size is written as a little endian u64, followed by the proof produced by
+/// [encode_size_proof] and the ranges as produced by [encode_ranges_validated].
+/// Use [ResponseDecoder::new_with_size_proof] to decode the result.
+///
+/// This is a new framing that is not compatible with a response consisting of
+/// the size followed by the encoded ranges: the size proof is sent in
+/// addition to the ranges, even if the ranges already contain the last chunk.
+/// A receiver that expects the size to be followed directly by the ranges
+/// cannot decode it, and [ResponseDecoder::new_with_size_proof] cannot decode such a response.
+pub async fn encode_ranges_with_size_proof<D, O, W>(
+    mut data: D,
+    mut outboard: O,
+    ranges: &ChunkRangesRef,
+    mut encoded: W,
+) -> result::Result<(), EncodeError>
+where
+    D: AsyncSliceReader,
+    O: Outboard,
+    W: AsyncStreamWriter,
+{
+    encoded.write(&outboard.tree().size.to_le_bytes()).await?;
+    encode_size_proof(&mut data, &mut outboard, &mut encoded).await?;
+    encode_ranges_validated(data, outboard, ranges, encoded).await
+}
+
 fn read_parent(buf: &[u8]) -> (blake3::Hash, blake3::Hash) {
     let l_hash = blake3::Hash::from(<[u8; 32]>::try_from(&buf[..32]).unwrap());
     let r_hash = blake3::Hash::from(<[u8; 32]>::try_from(&buf[32..64]).unwrap());
@@ -665,7 +955,7 @@ async fn outboard_impl(
                 let right_hash = stack.pop().unwrap();
                 let left_hash = stack.pop().unwrap();
                 outboard.save(node, &(left_hash, right_hash)).await?;
-                let parent = parent_cv(&left_hash, &right_hash, is_root);
+                let parent = tree.mode.parent_cv(&left_hash, &right_hash, is_root);
                 stack.push(parent);
             }
             BaoChunk::Leaf {
@@ -675,7 +965,7 @@ async fn outboard_impl(
                 ..
             } => {
                 let buf = data.read_bytes(size).await?;
-                let hash = hash_subtree(start_chunk.0, &buf, is_root);
+                let hash = tree.mode.hash_subtree(start_chunk.0, &buf, is_root);
                 stack.push(hash);
             }
         }
@@ -685,6 +975,57 @@ async fn outboard_impl(
     Ok(hash)
 }
 
+/// Open an outboard that starts with a [Header].
+///
+/// The order, tree and root hash are taken from the header. The hash mode is
+/// not fully stored in the header, so it has to be given. Fails with
+/// [io::ErrorKind::InvalidData] if the header is invalid, does not match the
+/// kind of hash mode, or the size of the data does not match the header.
+pub async fn open_with_header<D: AsyncSliceReader>(
+    mut data: D,
+    mode: HashMode,
+) -> io::Result<HeaderOutboard<D>> {
+    let buf = data.read_at(0, HEADER_SIZE).await?;
+    let buf = <[u8; HEADER_SIZE]>::try_from(&buf[..]).map_err(|_| Header::too_short())?;
+    let header = Header::from_bytes(&buf, mode)?;
+    let len = data.size().await?;
+    header.check_len(len)?;
+    Ok(HeaderOutboard::new(header, data))
+}
+
+/// Compute an outboard with a [Header] for the given data, writing it to
+/// the given target.
+///
+/// The target is truncated to the size of the header and outboard. The
+/// returned outboard is ready to use.
+///
+/// The kind of hash mode is recorded in the header, but not the key.
+pub async fn create_with_header<W: AsyncSliceWriter>(
+    data: impl AsyncStreamReader,
+    size: u64,
+    block_size: BlockSize,
+    mode: HashMode,
+    order: OutboardOrder,
+    target: W,
+) -> io::Result<HeaderOutboard<W>> {
+    let tree = BaoTree::new_with_mode(size, block_size, mode);
+    let header = Header {
+        order,
+        tree,
+        root: blake3::Hash::from([0; 32]),
+    };
+    let mut res = HeaderOutboard::new(header, target);
+    res.inner_mut()
+        .set_len(HEADER_SIZE as u64 + tree.outboard_size())
+        .await?;
+    let root = outboard(data, tree, &mut res).await?;
+    res.set_root(root);
+    let header = res.header().to_bytes()?;
+    res.inner_mut().write_at(0, &header).await?;
+    res.sync().await?;
+    Ok(res)
+}
+
 /// Compute the post order outboard for the given data, writing into a io::Write
 ///
 /// For the post order outboard, writes to the target are sequential.
@@ -718,7 +1059,7 @@ async fn outboard_post_order_impl(
                 let left_hash = stack.pop().unwrap();
                 outboard.write(left_hash.as_bytes()).await?;
                 outboard.write(right_hash.as_bytes()).await?;
-                let parent = parent_cv(&left_hash, &right_hash, is_root);
+                let parent = tree.mode.parent_cv(&left_hash, &right_hash, is_root);
                 stack.push(parent);
             }
             BaoChunk::Leaf {
@@ -728,7 +1069,7 @@ async fn outboard_post_order_impl(
                 ..
             } => {
                 let buf = data.read_bytes(size).await?;
-                let hash = hash_subtree(start_chunk.0, &buf, is_root);
+                let hash = tree.mode.hash_subtree(start_chunk.0, &buf, is_root);
                 stack.push(hash);
             }
         }
@@ -738,6 +1079,224 @@ async fn outboard_post_order_impl(
     Ok(hash)
 }
 
+/// A writer that computes the outboard while data is written to a data sink.
+///
+/// Data written using the [AsyncStreamWriter] impl is passed through to the
+/// wrapped data writer and hashed at the same time, so the data does not have
+/// to be read again to compute the outboard. The hash pairs of the post order
+/// outboard are written to a separate writer as soon as they are stable.
+#[derive(Debug)]
+pub struct OutboardingWriter<W, O> {
+    data: W,
+    outboard: O,
+    hasher: PostOrderHasher,
+}
+
+impl<W: AsyncStreamWriter, O: AsyncStreamWriter> OutboardingWriter<W, O> {
+    /// Create a new writer that writes data to `data` and the outboard to `outboard`.
+    pub fn new(data: W, block_size: BlockSize, outboard: O) -> Self {
+        Self::new_with_mode(data, block_size, HashMode::DEFAULT, outboard)
+    }
+
+    /// Create a new writer that uses the given hash mode.
+    pub fn new_with_mode(data: W, block_size: BlockSize, mode: HashMode, outboard: O) -> Self {
+        Self {
+            data,
+            outboard,
+            hasher: PostOrderHasher::new(block_size, mode),
+        }
+    }
+
+    /// Number of bytes that have been written so far.
+    pub fn size(&self) -> u64 {
+        self.hasher.size()
+    }
+
+    /// Finish writing, and sync both the data and the outboard.
+    ///
+    /// Returns the data writer and the complete outboard, which contains the
+    /// root hash and the tree.
+    pub async fn finalize(mut self) -> io::Result<(W, PostOrderOutboard<O>)> {
+        self.data.sync().await?;
+        let (root, tree) = self.hasher.finalize();
+        self.write_pairs().await?;
+        self.outboard.sync().await?;
+        Ok((
+            self.data,
+            PostOrderOutboard {
+                root,
+                tree,
+                data: self.outboard,
+            },
+        ))
+    }
+
+    async fn write_pairs(&mut self) -> io::Result<()> {
+        let n = self.hasher.pairs().len();
+        if n > 0 {
+            self.outboard.write(self.hasher.pairs()).await?;
+            self.hasher.consume_pairs(n);
+        }
+        Ok(())
+    }
+}
+
+impl<W: AsyncStreamWriter, O: AsyncStreamWriter> AsyncStreamWriter for OutboardingWriter<W, O> {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.data.write(data).await?;
+        self.hasher.update(data);
+        self.write_pairs().await
+    }
+
+    async fn write_bytes(&mut self, data: Bytes) -> io::Result<()> {
+        self.data.write_bytes(data.clone()).await?;
+        self.hasher.update(&data);
+        self.write_pairs().await
+    }
+
+    async fn sync(&mut self) -> io::Result<()> {
+        self.data.sync().await?;
+        self.outboard.sync().await
+    }
+}
+
+/// Grow a post order outboard to cover data that has been appended to the blob.
+///
+/// `data` is the entire data after the append, and `new_size` is its new size.
+/// Nodes that were already complete at the old size are
+/// [stable](crate::PostOrderOffset::Stable), so their hash pairs are loaded
+/// from the outboard instead of being recomputed. Only the data from the last
+/// incomplete leaf of the old tree onwards is read and hashed, and only the
+/// unstable nodes are rewritten.
+///
+/// On success, the tree and root hash of the outboard are updated and the new
+/// root hash is returned. On failure the outboard is left in an unspecified state.
+pub async fn append_post_order<D, W>(
+    outboard: &mut PostOrderOutboard<W>,
+    data: D,
+    new_size: u64,
+) -> io::Result<blake3::Hash>
+where
+    D: AsyncSliceReader,
+    W: AsyncSliceReader + AsyncSliceWriter,
+{
+    let old_size = outboard.tree.size;
+    if new_size < old_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "new size must not be smaller than the current size",
+        ));
+    }
+    // stable nodes have the same offset in the old and the new tree,
+    // so we can switch to the new tree right away
+    let tree = BaoTree::new_with_mode(new_size, outboard.tree.block_size, outboard.tree.mode);
+    outboard.tree = tree;
+    let (shifted_root, shifted_filled_size) = tree.shifted();
+    let mut appender = PostOrderAppender {
+        tree,
+        old_size,
+        shifted_filled_size,
+        outboard,
+        data,
+    };
+    let root = appender.append_rec(shifted_root, true).await?;
+    outboard.root = root;
+    outboard.sync().await?;
+    Ok(root)
+}
+
+struct PostOrderAppender<'a, D, W> {
+    tree: BaoTree,
+    old_size: u64,
+    shifted_filled_size: TreeNode,
+    outboard: &'a mut PostOrderOutboard<W>,
+    data: D,
+}
+
+impl<'a, D, W> PostOrderAppender<'a, D, W>
+where
+    D: AsyncSliceReader,
+    W: AsyncSliceReader + AsyncSliceWriter,
+{
+    fn append_rec(
+        &mut self,
+        shifted: TreeNode,
+        is_root: bool,
+    ) -> LocalBoxFuture<'_, io::Result<blake3::Hash>> {
+        Box::pin(async move {
+            let node = shifted.subtract_block_size(self.tree.block_size.0);
+            if node.byte_range().end <= self.old_size {
+                // the node was already complete before the append, so it is stable
+                let (l_hash, r_hash) = self.outboard.load(node).await?.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "stable node not found")
+                })?;
+                return Ok(self.tree.mode.parent_cv(&l_hash, &r_hash, is_root));
+            }
+            if shifted.is_leaf() {
+                let (l, m, r) = self.tree.leaf_byte_ranges3(node);
+                let start_chunk = ChunkNum::full_chunks(l);
+                let buf = read_exact_at(&mut self.data, l, (r - l) as usize).await?;
+                if m == r {
+                    // half full leaf, this is not stored in the outboard
+                    return Ok(self.tree.mode.hash_subtree(start_chunk.0, &buf, is_root));
+                }
+                let (lb, rb) = buf.split_at((m - l) as usize);
+                let l_hash = self.tree.mode.hash_subtree(start_chunk.0, lb, false);
+                let r_hash = self
+                    .tree
+                    .mode
+                    .hash_subtree(ChunkNum::full_chunks(m).0, rb, false);
+                self.outboard.save(node, &(l_hash, r_hash)).await?;
+                Ok(self.tree.mode.parent_cv(&l_hash, &r_hash, is_root))
+            } else {
+                // recurse (we are in the domain of the shifted tree)
+                let left = shifted.left_child().unwrap();
+                let l_hash = self.append_rec(left, false).await?;
+                let right = shifted.right_descendant(self.shifted_filled_size).unwrap();
+                let r_hash = self.append_rec(right, false).await?;
+                self.outboard.save(node, &(l_hash, r_hash)).await?;
+                Ok(self.tree.mode.parent_cv(&l_hash, &r_hash, is_root))
+            }
+        })
+    }
+}
+
+/// Read exactly `len` bytes at `offset`, failing with [io::ErrorKind::UnexpectedEof]
+/// if the reader returns less data.
+async fn read_exact_at(
+    data: &mut impl AsyncSliceReader,
+    offset: u64,
+    len: usize,
+) -> io::Result<Bytes> {
+    let res = data.read_at(offset, len).await?;
+    if res.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(res)
+}
+
+/// Read exactly `len` bytes from a stream, failing with [io::ErrorKind::UnexpectedEof]
+/// if the stream ends early.
+///
+/// [AsyncStreamReader::read_bytes] may return less data than requested, so we
+/// might need multiple reads.
+async fn read_bytes_exact(data: &mut impl AsyncStreamReader, len: usize) -> io::Result<Bytes> {
+    let res = data.read_bytes(len).await?;
+    if res.len() == len {
+        return Ok(res);
+    }
+    let mut buf = BytesMut::with_capacity(len);
+    buf.extend_from_slice(&res);
+    while buf.len() < len {
+        let res = data.read_bytes(len - buf.len()).await?;
+        if res.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&res);
+    }
+    Ok(buf.freeze())
+}
+
 /// Copy an outboard to another outboard.
 ///
 /// This can be used to persist an in memory outboard or to change from
@@ -752,20 +1311,285 @@ pub async fn copy(mut from: impl Outboard, mut to: impl OutboardMut) -> io::Resu
     Ok(())
 }
 
+/// Convert an outboard to a different block size.
+///
+/// The root hash does not depend on the block size, so `to` must be set up
+/// with the same root hash and size as `from`, but the new block size.
+///
+/// When increasing the block size, the hash pairs below the new chunk group
+/// size are just dropped, and `data` is not used. When decreasing the block
+/// size, the missing hash pairs are computed from `data`, one chunk group of
+/// the old tree at a time. Each chunk group is checked against the hash in the
+/// old outboard, and the conversion fails with [io::ErrorKind::InvalidData] if
+/// the data does not match.
+pub async fn convert_block_size(
+    mut from: impl Outboard,
+    mut data: impl AsyncSliceReader,
+    mut to: impl OutboardMut,
+    block_size: BlockSize,
+) -> io::Result<()> {
+    let tree = from.tree();
+    let new_tree = BaoTree::new_with_mode(tree.size, block_size, tree.mode);
+    for node in new_tree.pre_order_nodes_iter() {
+        if node.level() < tree.block_size.0 as u32 || !new_tree.is_relevant_for_outboard(node) {
+            continue;
+        }
+        let Some(hash_pair) = from.load(node).await? else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("hash pair not found (level {})", node.level()),
+            ));
+        };
+        to.save(node, &hash_pair).await?;
+    }
+    if block_size < tree.block_size {
+        let mut pairs = Vec::new();
+        // the hashes of the old tree, to check the chunk groups against
+        let mut stack = vec![from.root()];
+        for item in tree.ranges_pre_order_chunks_iter_ref(&ChunkRanges::all(), 0) {
+            match item {
+                BaoChunk::Parent {
+                    node,
+                    is_root,
+                    left,
+                    right,
+                    ..
+                } => {
+                    let Some((l_hash, r_hash)) = from.load(node).await? else {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("hash pair not found (level {})", node.level()),
+                        ));
+                    };
+                    let expected = stack.pop().unwrap();
+                    if tree.mode.parent_cv(&l_hash, &r_hash, is_root) != expected {
+                        return Err(EncodeError::ParentHashMismatch(node).into());
+                    }
+                    if right {
+                        stack.push(r_hash);
+                    }
+                    if left {
+                        stack.push(l_hash);
+                    }
+                }
+                BaoChunk::Leaf {
+                    start_chunk,
+                    size,
+                    is_root,
+                    ..
+                } => {
+                    let buf = read_exact_at(&mut data, start_chunk.to_bytes(), size).await?;
+                    pairs.clear();
+                    let height = tree.block_size.0;
+                    let hash = hash_pairs_group(
+                        tree.mode,
+                        start_chunk,
+                        height,
+                        &buf,
+                        block_size,
+                        is_root,
+                        &mut pairs,
+                    );
+                    // don't save pairs that are inconsistent with the old tree
+                    if hash != stack.pop().unwrap() {
+                        return Err(EncodeError::LeafHashMismatch(start_chunk).into());
+                    }
+                    for (node, hash_pair) in &pairs {
+                        to.save(*node, hash_pair).await?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Compute the chunk ranges in which the data described by `b` differs from
+/// the data described by `a`.
+///
+/// Both outboards must have the same block size. The trees are walked top-down,
+/// and subtrees that cover the same bytes and have the same hash in both
+/// outboards are skipped, so the cost is proportional to the number of changes
+/// rather than to the size of the data.
+///
+/// The result is relative to `b` and has chunk group granularity, so it can be
+/// used directly to request the changed data of `b`. Everything in `b` beyond
+/// the size of `a` is considered changed. If `a` consists of just a single
+/// chunk group, only its root hash is known, so that group is considered
+/// changed unless the sizes are the same.
+///
+/// Nodes that can not be loaded from either outboard are also considered changed.
+pub async fn diff(a: impl Outboard, b: impl Outboard) -> io::Result<ChunkRanges> {
+    let tree = b.tree();
+    if a.tree().block_size != tree.block_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "outboards must have the same block size",
+        ));
+    }
+    let root_a = (a.tree().size == tree.size).then(|| a.root());
+    let root_b = b.root();
+    let (shifted_root, shifted_filled_size) = tree.shifted();
+    let mut differ = Differ {
+        a,
+        b,
+        shifted_filled_size,
+        res: ChunkRanges::empty(),
+    };
+    differ.diff_rec(shifted_root, root_b, root_a).await?;
+    Ok(differ.res)
+}
+
+struct Differ<A, B> {
+    a: A,
+    b: B,
+    shifted_filled_size: TreeNode,
+    res: ChunkRanges,
+}
+
+impl<A: Outboard, B: Outboard> Differ<A, B> {
+    fn add(&mut self, range: Range<u64>) {
+        self.res |=
+            ChunkRanges::from(ChunkNum::full_chunks(range.start)..ChunkNum::chunks(range.end));
+    }
+
+    /// Compare a subtree of `b` with the hash of the subtree covering the same
+    /// bytes in `a`, if there is one.
+    fn diff_rec(
+        &mut self,
+        shifted: TreeNode,
+        hash_b: blake3::Hash,
+        hash_a: Option<blake3::Hash>,
+    ) -> LocalBoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            if hash_a == Some(hash_b) {
+                // same data, no need to look at the children
+                return Ok(());
+            }
+            let tree = self.b.tree();
+            let node = shifted.subtract_block_size(tree.block_size.0);
+            let (l, m, r) = tree.leaf_byte_ranges3(node);
+            if !tree.is_relevant_for_outboard(node) {
+                // half full leaf, this is just a single chunk group
+                let group_bytes = tree.chunk_group_bytes() as u64;
+                let hash_a = match hash_a {
+                    // if the group is complete in both, it has the same hash in a
+                    // even if the tree of a has a different shape
+                    None if r - l == group_bytes && r <= self.a.tree().size => {
+                        self.group_hash_a(ChunkNum::full_chunks(l)).await?
+                    }
+                    hash_a => hash_a,
+                };
+                if hash_a != Some(hash_b) {
+                    self.add(l..r);
+                }
+                return Ok(());
+            }
+            let Some((l_hash, r_hash)) = self.b.load(node).await? else {
+                self.add(l..r);
+                return Ok(());
+            };
+            let (l_hash_a, r_hash_a) = self.child_hashes_a(node).await?;
+            if shifted.is_leaf() {
+                if l_hash_a != Some(l_hash) {
+                    self.add(l..m);
+                }
+                if r_hash_a != Some(r_hash) {
+                    self.add(m..r);
+                }
+            } else {
+                // recurse (we are in the domain of the shifted tree)
+                let left = shifted.left_child().unwrap();
+                self.diff_rec(left, l_hash, l_hash_a).await?;
+                let right = shifted.right_descendant(self.shifted_filled_size).unwrap();
+                self.diff_rec(right, r_hash, r_hash_a).await?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Hashes of the children of `node` in `a`, for the children that cover
+    /// the same bytes as in `b`.
+    async fn child_hashes_a(
+        &mut self,
+        node: TreeNode,
+    ) -> io::Result<(Option<blake3::Hash>, Option<blake3::Hash>)> {
+        let size_a = self.a.tree().size;
+        let size_b = self.b.tree().size;
+        let mid = node.mid().to_bytes();
+        let end = node.byte_range().end;
+        Ok(if mid < size_a {
+            // the node exists in a, and the left child is complete in both
+            let Some((l_hash, r_hash)) = self.a.load(node).await? else {
+                return Ok((None, None));
+            };
+            let same_right = end.min(size_a) == end.min(size_b);
+            (Some(l_hash), same_right.then_some(r_hash))
+        } else if mid == size_a {
+            // the data of a ends exactly at the end of the left child
+            let l_hash = match node.left_child() {
+                Some(left) if self.a.tree().is_relevant_for_outboard(left) => self
+                    .a
+                    .load(left)
+                    .await?
+                    .map(|(l, r)| self.a.tree().mode.parent_cv(&l, &r, false)),
+                _ => self.group_hash_a(node.chunk_range().start).await?,
+            };
+            (l_hash, None)
+        } else {
+            (None, None)
+        })
+    }
+
+    /// Find the hash of the chunk group starting at `start` in `a`.
+    ///
+    /// Hashes of chunk groups are only stored as part of the hash pair of their
+    /// parent, so we need to search from the root.
+    async fn group_hash_a(&mut self, start: ChunkNum) -> io::Result<Option<blake3::Hash>> {
+        let tree = self.a.tree();
+        let (mut shifted, shifted_filled_size) = tree.shifted();
+        // hash of the current node, unless it is the root
+        let mut hash = None;
+        loop {
+            let node = shifted.subtract_block_size(tree.block_size.0);
+            if !tree.is_relevant_for_outboard(node) {
+                // half full leaf, the hash is stored in the parent
+                return Ok(hash);
+            }
+            let Some((l_hash, r_hash)) = self.a.load(node).await? else {
+                return Ok(None);
+            };
+            let is_left = start < node.mid();
+            if shifted.is_leaf() {
+                return Ok(Some(if is_left { l_hash } else { r_hash }));
+            }
+            if is_left {
+                shifted = shifted.left_child().unwrap();
+                hash = Some(l_hash);
+            } else {
+                shifted = shifted.right_descendant(shifted_filled_size).unwrap();
+                hash = Some(r_hash);
+            }
+        }
+    }
+}
+
 #[cfg(feature = "validate")]
 mod validate {
-    use std::{io, ops::Range};
+    use std::{io, ops::Range, result};
 
-    use futures_lite::{FutureExt, Stream};
+    use futures_lite::{FutureExt, Stream, StreamExt};
     use genawaiter::sync::{Co, Gen};
-    use iroh_io::AsyncSliceReader;
+    use iroh_io::{AsyncSliceReader, AsyncSliceWriter, AsyncStreamReader};
 
     use crate::{
-        blake3, hash_subtree, io::LocalBoxFuture, rec::truncate_ranges, split, BaoTree, ChunkNum,
-        ChunkRangesRef, TreeNode,
+        blake3,
+        io::{round_up_to_chunk_groups, DecodeError, LocalBoxFuture},
+        rec::truncate_ranges,
+        split, BaoTree, ChunkNum, ChunkRanges, ChunkRangesRef, TreeNode,
     };
 
-    use super::Outboard;
+    use super::{decode_ranges, Outboard, OutboardMut};
 
     /// Given a data file and an outboard, compute all valid ranges.
     ///
@@ -809,7 +1633,7 @@ mod validate {
                 // special case for a tree that fits in one block / chunk group
                 let mut data = data;
                 let data = data.read_at(0, tree.size().try_into().unwrap()).await?;
-                let actual = hash_subtree(0, &data, true);
+                let actual = tree.mode.hash_subtree(0, &data, true);
                 if actual == outboard.root() {
                     co.yield_(Ok(ChunkNum(0)..tree.chunks())).await;
                 }
@@ -839,7 +1663,10 @@ mod validate {
             let len = (range.end - range.start).try_into().unwrap();
             let data = self.data.read_at(range.start, len).await?;
             // is_root is always false because the case of a single chunk group is handled before calling this function
-            let actual = hash_subtree(ChunkNum::full_chunks(range.start).0, &data, is_root);
+            let actual =
+                self.tree
+                    .mode
+                    .hash_subtree(ChunkNum::full_chunks(range.start).0, &data, is_root);
             if &actual == hash {
                 // yield the left range
                 self.co
@@ -873,7 +1700,7 @@ mod validate {
                     // outboard is incomplete, we can't validate
                     return Ok(());
                 };
-                let actual = blake3::guts::parent_cv(&l_hash, &r_hash, is_root);
+                let actual = self.tree.mode.parent_cv(&l_hash, &r_hash, is_root);
                 if &actual != parent_hash {
                     // hash mismatch, we can't validate
                     return Ok(());
@@ -976,7 +1803,7 @@ mod validate {
                     // outboard is incomplete, we can't validate
                     return Ok(());
                 };
-                let actual = blake3::guts::parent_cv(&l_hash, &r_hash, is_root);
+                let actual = self.tree.mode.parent_cv(&l_hash, &r_hash, is_root);
                 if &actual != parent_hash {
                     // hash mismatch, we can't validate
                     return Ok(());
@@ -1000,6 +1827,62 @@ mod validate {
             })
         }
     }
+
+    /// Compute the ranges of a blob that are missing or corrupted locally.
+    ///
+    /// This validates the data and outboard within `ranges` using [valid_ranges],
+    /// and returns everything that is not valid. The result is truncated to the
+    /// size of the blob and rounded up to chunk groups, so it can be used
+    /// directly as the request for [repair].
+    pub async fn invalid_ranges<O, D>(
+        outboard: O,
+        data: D,
+        ranges: &ChunkRangesRef,
+    ) -> io::Result<ChunkRanges>
+    where
+        O: Outboard,
+        D: AsyncSliceReader,
+    {
+        let tree = outboard.tree();
+        let ranges = truncate_ranges(ranges, tree.size());
+        let wanted =
+            round_up_to_chunk_groups(ranges, tree.block_size) & ChunkRanges::from(..tree.chunks());
+        let mut valid = ChunkRanges::empty();
+        let mut stream = valid_ranges(outboard, data, &wanted);
+        while let Some(range) = stream.next().await {
+            valid |= ChunkRanges::from(range?);
+        }
+        Ok(&wanted - &valid)
+    }
+
+    /// Repair a blob using a response to a request for `ranges`.
+    ///
+    /// `ranges` is typically computed using [invalid_ranges]. The response is
+    /// verified and written to the data and outboard using [decode_ranges].
+    /// Then the data is validated again, and the chunk ranges within `ranges`
+    /// that are now valid are returned.
+    pub async fn repair<R, O, D>(
+        encoded: R,
+        ranges: &ChunkRangesRef,
+        mut data: D,
+        mut outboard: O,
+    ) -> result::Result<ChunkRanges, DecodeError>
+    where
+        R: AsyncStreamReader,
+        O: OutboardMut + Outboard,
+        D: AsyncSliceReader + AsyncSliceWriter,
+    {
+        if !ranges.is_empty() {
+            let owned = ChunkRanges::new_unchecked(ranges.boundaries().into());
+            decode_ranges(encoded, owned, &mut data, &mut outboard).await?;
+        }
+        let mut valid = ChunkRanges::empty();
+        let mut stream = valid_ranges(&mut outboard, &mut data, ranges);
+        while let Some(range) = stream.next().await {
+            valid |= ChunkRanges::from(range?);
+        }
+        Ok(valid)
+    }
 }
 #[cfg(feature = "validate")]
-pub use validate::{valid_outboard_ranges, valid_ranges};
+pub use validate::{invalid_ranges, repair, valid_outboard_ranges, valid_ranges};
@@ -0,0 +1,129 @@
+//! Sans-io incremental hasher for data of unknown size
+//!
+//! This is shared by the sync and async io code.
+use smallvec::SmallVec;
+
+use crate::{blake3, BaoTree, BlockSize, HashMode};
+
+use super::combine_hash_pair;
+
+/// Incremental hasher that produces the hash pairs of a post order outboard.
+///
+/// The size of the data does not need to be known in advance. Complete
+/// subtrees are merged as soon as the next chunk group starts, so their hash
+/// pairs are final and can be written out immediately. They are buffered in
+/// [PostOrderHasher::pairs] until the caller drains them.
+///
+/// The last chunk group is only hashed once more data arrives or the hasher
+/// is finalized, so we never have to guess whether a node is the root.
+#[derive(Debug)]
+pub(crate) struct PostOrderHasher {
+    block_size: BlockSize,
+    mode: HashMode,
+    /// data of the current, possibly incomplete chunk group
+    buffer: Vec<u8>,
+    /// number of chunk groups that have been hashed
+    groups: u64,
+    /// hashes of complete subtrees, from left to right
+    stack: SmallVec<[blake3::Hash; 10]>,
+    /// serialized hash pairs in post order that have not yet been written
+    pairs: Vec<u8>,
+}
+
+impl PostOrderHasher {
+    pub fn new(block_size: BlockSize, mode: HashMode) -> Self {
+        Self {
+            block_size,
+            mode,
+            buffer: Vec::with_capacity(block_size.bytes()),
+            groups: 0,
+            stack: SmallVec::new(),
+            pairs: Vec::new(),
+        }
+    }
+
+    /// Number of bytes that have been added so far
+    pub fn size(&self) -> u64 {
+        (self.groups << (self.block_size.0 + 10)) + self.buffer.len() as u64
+    }
+
+    /// Hash pairs that are ready to be written, in post order
+    pub fn pairs(&self) -> &[u8] {
+        &self.pairs
+    }
+
+    /// Forget about the first `n` bytes of hash pairs, after a partial write
+    pub fn consume_pairs(&mut self, n: usize) {
+        self.pairs.drain(..n);
+    }
+
+    /// Add data to the hasher
+    pub fn update(&mut self, mut data: &[u8]) {
+        let group_bytes = self.block_size.bytes();
+        while !data.is_empty() {
+            if self.buffer.len() == group_bytes {
+                // there is more data, so the buffered chunk group is complete
+                let hash = self.hash_group(&self.buffer);
+                self.push_group(hash);
+                self.buffer.clear();
+            }
+            if self.buffer.is_empty() && data.len() > group_bytes {
+                // fast path, hash directly from the input
+                let (group, rest) = data.split_at(group_bytes);
+                self.push_group(self.hash_group(group));
+                data = rest;
+                continue;
+            }
+            let n = (group_bytes - self.buffer.len()).min(data.len());
+            self.buffer.extend_from_slice(&data[..n]);
+            data = &data[n..];
+        }
+    }
+
+    /// Finish hashing, returning the root hash and the tree.
+    ///
+    /// The remaining hash pairs, for the unstable nodes, are added to
+    /// [PostOrderHasher::pairs]. The hasher is reset afterwards, except for
+    /// the pairs.
+    pub fn finalize(&mut self) -> (blake3::Hash, BaoTree) {
+        let tree = BaoTree::new_with_mode(self.size(), self.block_size, self.mode);
+        let start_chunk = self.groups << self.block_size.0;
+        let mut hash = self
+            .mode
+            .hash_subtree(start_chunk, &self.buffer, self.stack.is_empty());
+        // collapse the stack from the right. This produces the unstable nodes in post order.
+        while let Some(left) = self.stack.pop() {
+            self.pairs
+                .extend_from_slice(&combine_hash_pair(&left, &hash));
+            hash = self.mode.parent_cv(&left, &hash, self.stack.is_empty());
+        }
+        self.buffer.clear();
+        self.groups = 0;
+        (hash, tree)
+    }
+
+    /// Hash the next complete chunk group
+    fn hash_group(&self, data: &[u8]) -> blake3::Hash {
+        let start_chunk = self.groups << self.block_size.0;
+        self.mode.hash_subtree(start_chunk, data, false)
+    }
+
+    /// Add the hash of a complete chunk group, merging complete subtrees.
+    ///
+    /// This must only be called if there is more data after the group, so
+    /// none of the merged nodes can be the root.
+    fn push_group(&mut self, hash: blake3::Hash) {
+        self.groups += 1;
+        let mut right = hash;
+        // every trailing zero in the number of groups is a complete subtree
+        let mut total = self.groups;
+        while total & 1 == 0 {
+            let left = self.stack.pop().unwrap();
+            self.pairs
+                .extend_from_slice(&combine_hash_pair(&left, &right));
+            right = self.mode.parent_cv(&left, &right, false);
+            total >>= 1;
+        }
+        self.stack.push(right);
+    }
+}
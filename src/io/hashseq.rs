@@ -0,0 +1,527 @@
+//! Hash sequences: verified collections of blobs
+//!
+//! A [HashSeq] is a blob whose content is a concatenation of 32 byte blake3
+//! hashes, each of which refers to a child blob.
+//!
+//! A [HashSeqRequest] selects ranges of the hash sequence itself and of some of
+//! its children. The response to such a request is a single stream, which
+//! contains for the hash sequence and then for each selected child, in order of
+//! the child index, the size as a little endian u64 followed by the encoded
+//! ranges. Children that are beyond the end of the hash sequence are skipped.
+//! Requesting a child always requests the chunk of the hash sequence that
+//! contains its hash, so for children beyond the end the last chunk is
+//! requested, and the receiver verifies the size before skipping them.
+//!
+//! Like for a single blob, the size of a blob is only verified if the
+//! requested ranges include the last chunk.
+//!
+//! A request is encoded with [HashSeqRequest::to_bytes] as the 32 byte root
+//! hash, the block size, the kind of [HashMode] as one byte, and the ranges
+//! of the hash sequence, followed by the number of children and for each child
+//! its index and ranges. Ranges are encoded as described in [super::ranges],
+//! the number of children and the indices as varints, where every index but
+//! the first is encoded as the difference to the previous one. The key of the
+//! hash mode is never sent.
+use std::{collections::BTreeMap, io};
+
+use bytes::Bytes;
+
+use super::ranges::{read_chunk_ranges, read_varint, write_chunk_ranges, write_varint};
+use crate::{blake3, BlockSize, ChunkNum, ChunkRanges, ChunkRangesRef, HashMode};
+
+/// A sequence of blake3 hashes
+///
+/// This is just a wrapper around the bytes of the blob, which are guaranteed
+/// to be a multiple of 32 bytes long.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct HashSeq(Bytes);
+
+impl HashSeq {
+    /// Create a hash sequence from the content of a blob.
+    ///
+    /// Returns `None` if the size is not a multiple of 32.
+    pub fn new(bytes: Bytes) -> Option<Self> {
+        (bytes.len() % 32 == 0).then_some(Self(bytes))
+    }
+
+    /// The number of hashes
+    pub fn len(&self) -> usize {
+        self.0.len() / 32
+    }
+
+    /// True if there are no hashes
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Get the hash at the given index
+    pub fn get(&self, index: usize) -> Option<blake3::Hash> {
+        let offset = index.checked_mul(32)?;
+        let bytes = self.0.get(offset..offset + 32)?;
+        Some(blake3::Hash::from(<[u8; 32]>::try_from(bytes).unwrap()))
+    }
+
+    /// Iterate over the hashes
+    pub fn iter(&self) -> impl Iterator<Item = blake3::Hash> + '_ {
+        self.0
+            .chunks_exact(32)
+            .map(|x| blake3::Hash::from(<[u8; 32]>::try_from(x).unwrap()))
+    }
+
+    /// The hash of the hash sequence blob
+    ///
+    /// This is the regular BLAKE3 hash, use [Self::hash_with_mode] for blobs
+    /// that are hashed in another mode.
+    pub fn hash(&self) -> blake3::Hash {
+        blake3::hash(&self.0)
+    }
+
+    /// The hash of the hash sequence blob in the given hash mode
+    pub fn hash_with_mode(&self, mode: HashMode) -> blake3::Hash {
+        mode.hash_subtree(0, &self.0, true)
+    }
+
+    /// The content of the hash sequence blob
+    pub fn as_bytes(&self) -> &Bytes {
+        &self.0
+    }
+
+    /// Return the content of the hash sequence blob
+    pub fn into_inner(self) -> Bytes {
+        self.0
+    }
+}
+
+impl FromIterator<blake3::Hash> for HashSeq {
+    fn from_iter<T: IntoIterator<Item = blake3::Hash>>(iter: T) -> Self {
+        let mut bytes = Vec::new();
+        for hash in iter {
+            bytes.extend_from_slice(hash.as_bytes());
+        }
+        Self(bytes.into())
+    }
+}
+
+/// A request for ranges of a hash sequence and of some of its children
+///
+/// The ranges of the hash sequence always include the chunks that contain the
+/// hashes of the requested children, so the receiver can verify the children.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashSeqRequest {
+    root: blake3::Hash,
+    block_size: BlockSize,
+    mode: HashMode,
+    seq: ChunkRanges,
+    children: BTreeMap<u64, ChunkRanges>,
+}
+
+impl HashSeqRequest {
+    /// Create a new, empty request for the hash sequence with the given hash.
+    ///
+    /// The block size must match the block size of the outboards of the sender.
+    /// All blobs use the default hash mode, use [Self::with_mode] to change it.
+    pub fn new(root: blake3::Hash, block_size: BlockSize) -> Self {
+        Self {
+            root,
+            block_size,
+            mode: HashMode::DEFAULT,
+            seq: ChunkRanges::empty(),
+            children: BTreeMap::new(),
+        }
+    }
+
+    /// Set the hash mode that is used for the hash sequence and all children.
+    ///
+    /// The mode must match the mode of the outboards of the sender.
+    pub fn with_mode(mut self, mode: HashMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Request ranges of the hash sequence itself.
+    pub fn with_seq_ranges(mut self, ranges: ChunkRanges) -> Self {
+        self.seq |= ranges;
+        self
+    }
+
+    /// Request ranges of the child with the given index.
+    ///
+    /// This also requests the chunk of the hash sequence that contains the
+    /// hash of the child.
+    pub fn with_child(mut self, index: u64, ranges: ChunkRanges) -> Self {
+        if ranges.is_empty() {
+            return self;
+        }
+        // a chunk contains exactly 32 hashes
+        let chunk = ChunkNum(index / 32);
+        self.seq |= ChunkRanges::from(chunk..chunk + 1);
+        let entry = self
+            .children
+            .entry(index)
+            .or_insert_with(ChunkRanges::empty);
+        *entry |= ranges;
+        self
+    }
+
+    /// The hash of the hash sequence
+    pub fn root(&self) -> blake3::Hash {
+        self.root
+    }
+
+    /// The block size used for all blobs
+    pub fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+
+    /// The hash mode used for all blobs
+    pub fn mode(&self) -> HashMode {
+        self.mode
+    }
+
+    /// The requested ranges of the hash sequence
+    pub fn seq_ranges(&self) -> &ChunkRangesRef {
+        &self.seq
+    }
+
+    /// The requested ranges of the children, in order of the child index
+    pub fn children(&self) -> impl Iterator<Item = (u64, &ChunkRangesRef)> {
+        self.children
+            .iter()
+            .map(|(index, ranges)| (*index, ranges.as_ref()))
+    }
+
+    /// Encode the request, see the [module docs](self) for the format.
+    ///
+    /// The key of the hash mode is not part of the encoding.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(34);
+        res.extend_from_slice(self.root.as_bytes());
+        res.push(self.block_size.0);
+        res.push(self.mode.tag());
+        write_chunk_ranges(&self.seq, &mut res);
+        write_varint(self.children.len() as u64, &mut res);
+        let mut prev = 0;
+        for (index, ranges) in &self.children {
+            write_varint(index - prev, &mut res);
+            write_chunk_ranges(ranges, &mut res);
+            prev = *index;
+        }
+        res
+    }
+
+    /// Decode a request that was encoded with [Self::to_bytes].
+    ///
+    /// The mode must be of the same kind as the mode of the encoded request,
+    /// and provides the key. Fails if the request contains more than
+    /// `max_ranges` ranges in total, if the ranges of the hash sequence do not
+    /// contain the hashes of all requested children, or if there is data after
+    /// the encoded request.
+    pub fn from_bytes(mut data: &[u8], mode: HashMode, max_ranges: usize) -> io::Result<Self> {
+        let data = &mut data;
+        let mut root = [0u8; 32];
+        io::Read::read_exact(data, &mut root)?;
+        let mut header = [0u8; 2];
+        io::Read::read_exact(data, &mut header)?;
+        let [block_size, tag] = header;
+        if block_size > BlockSize::MAX_CHUNK_LOG {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid block size {}", block_size),
+            ));
+        }
+        if tag != mode.tag() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "hash mode mismatch, expected {:?} but the request has mode {}",
+                    mode, tag
+                ),
+            ));
+        }
+        let mut remaining = max_ranges;
+        let mut read_ranges = |data: &mut &[u8]| {
+            let ranges = read_chunk_ranges(data, remaining)?;
+            remaining -= ranges.boundaries().len().div_ceil(2);
+            io::Result::Ok(ranges)
+        };
+        let seq = read_ranges(data)?;
+        let n = read_varint(data)?;
+        let mut children = BTreeMap::new();
+        let mut index = 0u64;
+        for i in 0..n {
+            let delta = read_varint(data)?;
+            if i > 0 && delta == 0 {
+                return Err(invalid_data("child indices not sorted"));
+            }
+            index = index
+                .checked_add(delta)
+                .ok_or_else(|| invalid_data("child index overflow"))?;
+            let ranges = read_ranges(data)?;
+            if ranges.is_empty() {
+                return Err(invalid_data("empty child ranges"));
+            }
+            if !seq.contains(&ChunkNum(index / 32)) {
+                return Err(invalid_data("child hash not requested"));
+            }
+            children.insert(index, ranges);
+        }
+        if !data.is_empty() {
+            return Err(invalid_data("trailing data after request"));
+        }
+        Ok(Self {
+            root: blake3::Hash::from(root),
+            block_size: BlockSize(block_size),
+            mode,
+            seq,
+            children,
+        })
+    }
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// An item of a decoded hash sequence response
+#[derive(Debug)]
+pub enum HashSeqItem {
+    /// The start of a blob
+    Start {
+        /// The index of the child, or `None` for the hash sequence itself
+        child: Option<u64>,
+        /// The hash of the blob
+        hash: blake3::Hash,
+        /// The size of the blob, as claimed by the sender
+        size: u64,
+    },
+    /// Verified content of the current blob
+    Content {
+        /// The index of the child, or `None` for the hash sequence itself
+        child: Option<u64>,
+        /// The content
+        item: super::BaoContentItem,
+    },
+}
+
+#[cfg(feature = "tokio_fsm")]
+mod fsm_impl {
+    use std::{collections::btree_map, io, result};
+
+    use iroh_io::{AsyncSliceReader, AsyncStreamReader, AsyncStreamWriter};
+
+    use super::*;
+    use crate::{
+        io::{
+            fsm::{encode_ranges_validated, Outboard, ResponseDecoder, ResponseDecoderNext},
+            BaoContentItem, DecodeError, EncodeError, Leaf,
+        },
+        rec::truncate_ranges,
+        BaoTree,
+    };
+
+    fn not_a_hash_seq() -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, "size is not a multiple of 32")
+    }
+
+    /// Get the data and outboard for a blob, and check that they match the request
+    fn open_blob<D, O: Outboard>(
+        blobs: &mut impl FnMut(&blake3::Hash) -> io::Result<(D, O)>,
+        hash: &blake3::Hash,
+        block_size: BlockSize,
+        mode: HashMode,
+    ) -> io::Result<(D, O)> {
+        let (data, outboard) = blobs(hash)?;
+        if outboard.root() != *hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "outboard does not match hash",
+            ));
+        }
+        if outboard.tree().block_size != block_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "block size does not match request",
+            ));
+        }
+        if outboard.tree().mode != mode {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "hash mode does not match request",
+            ));
+        }
+        Ok((data, outboard))
+    }
+
+    /// Encode the response to a [HashSeqRequest].
+    ///
+    /// `blobs` is used to get the data and outboard for the hash sequence and
+    /// the children. All ranges are encoded using [encode_ranges_validated].
+    pub async fn encode_hash_seq<D, O, W, F>(
+        request: &HashSeqRequest,
+        mut blobs: F,
+        mut encoded: W,
+    ) -> result::Result<(), EncodeError>
+    where
+        D: AsyncSliceReader,
+        O: Outboard,
+        W: AsyncStreamWriter,
+        F: FnMut(&blake3::Hash) -> io::Result<(D, O)>,
+    {
+        if request.seq.is_empty() {
+            // requesting children always requests parts of the hash sequence
+            return Ok(());
+        }
+        let (mut data, mut outboard) =
+            open_blob(&mut blobs, &request.root, request.block_size, request.mode)?;
+        let size = outboard.tree().size;
+        if size % 32 != 0 {
+            return Err(not_a_hash_seq().into());
+        }
+        encoded.write(&size.to_le_bytes()).await?;
+        encode_ranges_validated(&mut data, &mut outboard, &request.seq, &mut encoded).await?;
+        for (&index, ranges) in &request.children {
+            if index >= size / 32 {
+                break;
+            }
+            let hash = data.read_at(index * 32, 32).await?;
+            let hash = <[u8; 32]>::try_from(&hash[..])
+                .map_err(|_| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+            let hash = blake3::Hash::from(hash);
+            let (child_data, child_outboard) =
+                open_blob(&mut blobs, &hash, request.block_size, request.mode)?;
+            encoded
+                .write(&child_outboard.tree().size.to_le_bytes())
+                .await?;
+            encode_ranges_validated(child_data, child_outboard, ranges, &mut encoded).await?;
+        }
+        Ok(())
+    }
+
+    /// Decoder for the response to a [HashSeqRequest].
+    ///
+    /// All content is verified against the hash of the hash sequence, and the
+    /// hashes of the children are taken from the verified hash sequence.
+    #[derive(Debug)]
+    pub struct HashSeqResponseDecoder<R> {
+        root: blake3::Hash,
+        block_size: BlockSize,
+        mode: HashMode,
+        /// the ranges of the hash sequence, until we have started decoding it
+        seq: Option<ChunkRanges>,
+        children: btree_map::IntoIter<u64, ChunkRanges>,
+        /// verified leaves of the hash sequence, by offset
+        seq_leaves: BTreeMap<u64, Bytes>,
+        /// number of hashes in the hash sequence, as claimed by the sender
+        count: u64,
+        /// true if the size of the hash sequence, and therefore `count`, has
+        /// been verified by decoding its last chunk
+        count_verified: bool,
+        reader: Option<R>,
+        decoder: Option<(Option<u64>, ResponseDecoder<R>)>,
+    }
+
+    impl<R: AsyncStreamReader> HashSeqResponseDecoder<R> {
+        /// Create a new decoder for the response to a request.
+        pub fn new(request: HashSeqRequest, encoded: R) -> Self {
+            Self {
+                root: request.root,
+                block_size: request.block_size,
+                mode: request.mode,
+                seq: Some(request.seq),
+                children: request.children.into_iter(),
+                seq_leaves: BTreeMap::new(),
+                count: 0,
+                count_verified: false,
+                reader: Some(encoded),
+                decoder: None,
+            }
+        }
+
+        /// Get the next item.
+        ///
+        /// Returns `None` when the response is complete. After an error, the
+        /// response can not be decoded any further, and `None` is returned.
+        pub async fn next(&mut self) -> Option<result::Result<HashSeqItem, DecodeError>> {
+            if let Some((child, decoder)) = self.decoder.take() {
+                match decoder.next().await {
+                    ResponseDecoderNext::More((decoder, Ok(item))) => {
+                        if let (None, BaoContentItem::Leaf(Leaf { offset, data })) = (child, &item)
+                        {
+                            self.seq_leaves.insert(*offset, data.clone());
+                        }
+                        self.decoder = Some((child, decoder));
+                        return Some(Ok(HashSeqItem::Content { child, item }));
+                    }
+                    ResponseDecoderNext::More((_, Err(cause))) => return Some(Err(cause)),
+                    ResponseDecoderNext::Done(reader) => self.reader = Some(reader),
+                }
+            }
+            self.reader.as_ref()?;
+            let (child, hash, ranges) = if let Some(ranges) = self.seq.take() {
+                if ranges.is_empty() {
+                    return None;
+                }
+                (None, self.root, ranges)
+            } else {
+                let (index, ranges) = self.children.next()?;
+                if index >= self.count {
+                    if !self.count_verified {
+                        // the sender could skip children by claiming a smaller size
+                        self.reader = None;
+                        return Some(Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "size of the hash sequence was not verified",
+                        )
+                        .into()));
+                    }
+                    return None;
+                }
+                let Some(hash) = self.entry(index) else {
+                    self.reader = None;
+                    return Some(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "hash of child not found",
+                    )
+                    .into()));
+                };
+                (Some(index), hash, ranges)
+            };
+            let mut reader = self.reader.take()?;
+            let size = match reader.read::<8>().await {
+                Ok(size) => u64::from_le_bytes(size),
+                Err(cause) => return Some(Err(cause.into())),
+            };
+            if child.is_none() {
+                if size % 32 != 0 {
+                    return Some(Err(not_a_hash_seq().into()));
+                }
+                self.count = size / 32;
+                // if the ranges contain the last chunk, decoding verifies the size
+                let last_chunk = ChunkNum::chunks(size).max(ChunkNum(1)) - 1;
+                self.count_verified = truncate_ranges(&ranges, size).contains(&last_chunk);
+            }
+            let tree = BaoTree::new_with_mode(size, self.block_size, self.mode);
+            self.decoder = Some((child, ResponseDecoder::new(hash, ranges, tree, reader)));
+            Some(Ok(HashSeqItem::Start { child, hash, size }))
+        }
+
+        /// Return the underlying reader, unless decoding failed.
+        pub fn finish(self) -> Option<R> {
+            match self.decoder {
+                Some((_, decoder)) => Some(decoder.finish()),
+                None => self.reader,
+            }
+        }
+
+        /// Get the hash of a child from the verified leaves of the hash sequence
+        fn entry(&self, index: u64) -> Option<blake3::Hash> {
+            let start = index * 32;
+            let (offset, data) = self.seq_leaves.range(..=start).next_back()?;
+            let start = usize::try_from(start - offset).ok()?;
+            let bytes = data.get(start..start + 32)?;
+            Some(blake3::Hash::from(<[u8; 32]>::try_from(bytes).unwrap()))
+        }
+    }
+}
+#[cfg(feature = "tokio_fsm")]
+pub use fsm_impl::{encode_hash_seq, HashSeqResponseDecoder};
@@ -1,9 +1,17 @@
 //! Implementation of bao streaming for std io and tokio io
-use std::pin::Pin;
+use std::{
+    io::{self, Read},
+    pin::Pin,
+};
 
-use crate::{blake3, BlockSize, ChunkNum, ChunkRanges, TreeNode};
+use crate::{
+    blake3, iter::BaoChunk, BaoTree, BlockSize, ChunkNum, ChunkRanges, HashMode, TreeNode,
+};
 use bytes::Bytes;
+use smallvec::SmallVec;
 
+pub mod compat;
+pub mod download;
 mod error;
 pub use error::*;
 use range_collections::{range_set::RangeSetRange, RangeSetRef};
@@ -11,8 +19,16 @@ use std::future::Future;
 
 #[cfg(feature = "tokio_fsm")]
 pub mod fsm;
+mod hasher;
+pub mod hashseq;
 pub mod outboard;
+pub mod partial;
+pub mod proof;
+pub mod ranges;
 pub mod sync;
+#[cfg(feature = "tokio")]
+pub mod tokio;
+pub mod verified;
 
 /// A parent hash pair.
 #[derive(Debug)]
@@ -56,6 +72,155 @@ impl From<Leaf> for BaoContentItem {
     }
 }
 
+/// The state of a response decoder between two chunks.
+///
+/// This can be persisted to resume an interrupted transfer. To resume, ask the
+/// sender to encode the same ranges starting at [DecoderState::encoded_offset],
+/// e.g. using [sync::encode_ranges_validated_from], and continue decoding
+/// with the restored decoder.
+///
+/// If reading a chunk fails, e.g. because the connection was dropped, the
+/// state is still the state before that chunk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecoderState {
+    /// The tree geometry
+    pub tree: BaoTree,
+    /// The requested ranges, truncated to the size of the tree
+    pub ranges: ChunkRanges,
+    /// The number of bytes of the encoded stream that have been decoded
+    pub encoded_offset: u64,
+    /// Hashes that have not been used for validation yet.
+    ///
+    /// The hash that is needed next is the last element.
+    pub stack: Vec<blake3::Hash>,
+}
+
+impl DecoderState {
+    /// Serialize the state.
+    ///
+    /// The format is the size of the tree, the block size as a single byte,
+    /// the encoded offset, the number of range boundaries followed by the
+    /// boundaries, and the number of hashes followed by the hashes. All numbers
+    /// except for the block size are encoded as little endian u64.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let boundaries = self.ranges.boundaries();
+        let mut res = Vec::with_capacity(33 + boundaries.len() * 8 + self.stack.len() * 32);
+        res.extend_from_slice(&self.tree.size.to_le_bytes());
+        res.push(self.tree.block_size.0);
+        res.extend_from_slice(&self.encoded_offset.to_le_bytes());
+        res.extend_from_slice(&(boundaries.len() as u64).to_le_bytes());
+        for boundary in boundaries {
+            res.extend_from_slice(&boundary.0.to_le_bytes());
+        }
+        res.extend_from_slice(&(self.stack.len() as u64).to_le_bytes());
+        for hash in &self.stack {
+            res.extend_from_slice(hash.as_bytes());
+        }
+        res
+    }
+
+    /// Deserialize a state that was serialized with [DecoderState::to_bytes].
+    ///
+    /// The hash mode of the tree is not serialized, so that no key ends up in
+    /// the serialized state. It has to be given when deserializing, and must be
+    /// the mode of the tree that was serialized.
+    ///
+    /// Fails with [io::ErrorKind::InvalidData] if the data is not a valid
+    /// state, e.g. if the block size is larger than [BlockSize::MAX_CHUNK_LOG].
+    pub fn from_bytes(mut data: &[u8], mode: HashMode) -> io::Result<Self> {
+        let size = read_u64(&mut data)?;
+        let mut block_size = [0u8; 1];
+        data.read_exact(&mut block_size)?;
+        if block_size[0] > BlockSize::MAX_CHUNK_LOG {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid block size {}", block_size[0]),
+            ));
+        }
+        let tree = BaoTree::new_with_mode(size, BlockSize(block_size[0]), mode);
+        let encoded_offset = read_u64(&mut data)?;
+        let n = read_len(&mut data, 8)?;
+        let mut boundaries = SmallVec::with_capacity(n);
+        for _ in 0..n {
+            boundaries.push(ChunkNum(read_u64(&mut data)?));
+        }
+        let ranges = ChunkRanges::new(boundaries)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid ranges"))?;
+        let n = read_len(&mut data, 32)?;
+        let mut stack = Vec::with_capacity(n);
+        for _ in 0..n {
+            let mut hash = [0u8; 32];
+            data.read_exact(&mut hash)?;
+            stack.push(blake3::Hash::from(hash));
+        }
+        if !data.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "trailing data after decoder state",
+            ));
+        }
+        Ok(Self {
+            tree,
+            ranges,
+            encoded_offset,
+            stack,
+        })
+    }
+}
+
+/// Advance a response iterator to the given offset in the encoded stream.
+///
+/// Returns the number of hashes a decoder needs on its stack at that offset.
+/// Fails if the offset is not at a chunk boundary.
+pub(crate) fn skip_to_offset(
+    iter: &mut impl Iterator<Item = BaoChunk>,
+    offset: u64,
+) -> io::Result<usize> {
+    let mut current = 0;
+    // a fresh decoder has just the root hash on the stack
+    let mut depth = 1usize;
+    while current < offset {
+        let Some(chunk) = iter.next() else {
+            break;
+        };
+        // every chunk consumes a hash, parents push the hashes of their relevant children
+        depth = depth.saturating_sub(1);
+        if let BaoChunk::Parent { left, right, .. } = chunk {
+            depth += left as usize + right as usize;
+        }
+        current += chunk.size() as u64;
+    }
+    if current != offset {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "encoded offset is not at a chunk boundary",
+        ));
+    }
+    Ok(depth)
+}
+
+/// Check that the stack of a decoder state has the depth that is needed to
+/// continue decoding.
+pub(crate) fn check_stack_depth(stack: &[blake3::Hash], depth: usize) -> io::Result<()> {
+    if stack.len() != depth {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "decoder state has {} hashes, but {} are needed at this offset",
+                stack.len(),
+                depth
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// The number of bytes to skip of an encoded chunk of length `len` at `current`,
+/// so that only the bytes at or after `offset` are written.
+pub(crate) fn skip_len(current: u64, len: usize, offset: u64) -> usize {
+    offset.saturating_sub(current).min(len as u64) as usize
+}
+
 /// Given a range set of byte ranges, round it up to full chunks.
 ///
 /// E.g. a byte range from 1..3 will be converted into the chunk range 0..1 (0..1024 bytes).
@@ -109,6 +274,108 @@ pub fn full_chunk_groups(ranges: &ChunkRanges, block_size: BlockSize) -> ChunkRa
     res
 }
 
+/// Round chunk ranges outwards to chunk group boundaries.
+///
+/// This is the opposite of [full_chunk_groups], which rounds inwards.
+pub(crate) fn round_up_to_chunk_groups(
+    ranges: &RangeSetRef<ChunkNum>,
+    block_size: BlockSize,
+) -> ChunkRanges {
+    let shift = block_size.0;
+    let floor = |x: ChunkNum| ChunkNum(x.0 >> shift << shift);
+    let ceil = |x: ChunkNum| ChunkNum(x.0.saturating_add((1 << shift) - 1) >> shift << shift);
+    let mut res = ChunkRanges::empty();
+    for range in ranges.iter() {
+        res |= match range {
+            RangeSetRange::Range(range) => ChunkRanges::from(floor(*range.start)..ceil(*range.end)),
+            RangeSetRange::RangeFrom(range) => ChunkRanges::from(floor(*range.start)..),
+        };
+    }
+    res
+}
+
+/// The part of `ranges` that can be encoded if only the chunks in `available`
+/// are present.
+///
+/// The hashes inside a chunk group are computed from the data, so only chunk
+/// groups that are available completely can be encoded. The last chunk group
+/// of the blob is complete if all chunks up to the end are available.
+pub(crate) fn available_ranges(
+    tree: &BaoTree,
+    ranges: &RangeSetRef<ChunkNum>,
+    available: &RangeSetRef<ChunkNum>,
+) -> ChunkRanges {
+    let ranges = crate::rec::truncate_ranges(ranges, tree.size);
+    let ranges = ChunkRanges::new_unchecked(ranges.boundaries().into());
+    let available = ChunkRanges::new_unchecked(available.boundaries().into())
+        | ChunkRanges::from(tree.chunks()..);
+    // even an empty blob has a single chunk, which proves its size
+    let end = tree.chunks().max(ChunkNum(1));
+    ranges & full_chunk_groups(&available, tree.block_size) & ChunkRanges::from(..end)
+}
+
+/// Compute the hash pairs of a subtree down to the given block size.
+///
+/// `start` is the first chunk of the subtree, which covers `2^height` chunks,
+/// and `data` is the data of the subtree, which may be shorter at the end of
+/// the blob. The hash pairs of all nodes at or above the level given by the
+/// block size are added to `pairs` in post order.
+///
+/// Returns the hash of the subtree, assuming it is not the root.
+pub(crate) fn hash_pairs_rec(
+    mode: HashMode,
+    start: ChunkNum,
+    height: u8,
+    data: &[u8],
+    block_size: BlockSize,
+    pairs: &mut Vec<(TreeNode, (blake3::Hash, blake3::Hash))>,
+) -> blake3::Hash {
+    if height <= block_size.0 {
+        return mode.hash_subtree(start.0, data, false);
+    }
+    let half = 1u64 << (height - 1);
+    let mid = ChunkNum(half).to_bytes() as usize;
+    if data.len() <= mid {
+        // no data in the right half, so there is no node for this subtree
+        return hash_pairs_rec(mode, start, height - 1, data, block_size, pairs);
+    }
+    let l_hash = hash_pairs_rec(mode, start, height - 1, &data[..mid], block_size, pairs);
+    let r_hash = hash_pairs_rec(
+        mode,
+        ChunkNum(start.0 + half),
+        height - 1,
+        &data[mid..],
+        block_size,
+        pairs,
+    );
+    // the node whose mid is the start of the right half
+    let node = TreeNode(start.0 + half - 1);
+    pairs.push((node, (l_hash, r_hash)));
+    mode.parent_cv(&l_hash, &r_hash, false)
+}
+
+/// Like [hash_pairs_rec], but returns the root hash if `is_root` is set.
+pub(crate) fn hash_pairs_group(
+    mode: HashMode,
+    start: ChunkNum,
+    height: u8,
+    data: &[u8],
+    block_size: BlockSize,
+    is_root: bool,
+    pairs: &mut Vec<(TreeNode, (blake3::Hash, blake3::Hash))>,
+) -> blake3::Hash {
+    let n = pairs.len();
+    let hash = hash_pairs_rec(mode, start, height, data, block_size, pairs);
+    if !is_root {
+        return hash;
+    }
+    // pairs are in post order, so the last new pair is the top of the subtree
+    match pairs[n..].last() {
+        Some((_, (l_hash, r_hash))) => mode.parent_cv(l_hash, r_hash, true),
+        None => mode.hash_subtree(start.0, data, true),
+    }
+}
+
 pub(crate) fn combine_hash_pair(l: &blake3::Hash, r: &blake3::Hash) -> [u8; 64] {
     let mut res = [0u8; 64];
     let lb: &mut [u8; 32] = (&mut res[0..32]).try_into().unwrap();
@@ -119,3 +386,18 @@ pub(crate) fn combine_hash_pair(l: &blake3::Hash, r: &blake3::Hash) -> [u8; 64]
 }
 
 pub(crate) type LocalBoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+pub(crate) fn read_u64(mut from: impl Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    from.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Read a number of items, and check that there are enough bytes left for them
+pub(crate) fn read_len(data: &mut &[u8], item_size: usize) -> io::Result<usize> {
+    let n = read_u64(&mut *data)?;
+    match usize::try_from(n) {
+        Ok(n) if n.checked_mul(item_size).is_some_and(|x| x <= data.len()) => Ok(n),
+        _ => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
@@ -3,9 +3,16 @@
 //! A number of implementations for the sync and async outboard traits are provided.
 //! Implementations for in-memory outboards, for outboards where the data resides on disk,
 //! and a special implementation [EmptyOutboard] that just ignores all writes.
-use crate::{blake3, BaoTree, BlockSize, TreeNode};
+use crate::{blake3, BaoTree, BlockSize, HashMode, TreeNode};
 use std::io;
 
+mod header;
+pub use header::{AfterHeader, Header, HeaderOutboard, OutboardOrder, HEADER_SIZE};
+#[cfg(feature = "mmap")]
+mod mmap;
+#[cfg(feature = "mmap")]
+pub use mmap::MmapData;
+
 /// An empty outboard, that just returns 0 hashes for all nodes.
 ///
 /// Also allows you to write and will immediately discard the data, a bit like /dev/null
@@ -180,9 +187,14 @@ impl PostOrderMemOutboard {
     ///
     /// It is just a shortcut that calls [crate::io::sync::outboard_post_order].
     pub fn create(data: impl AsRef<[u8]>, block_size: BlockSize) -> Self {
+        Self::create_with_mode(data, block_size, HashMode::DEFAULT)
+    }
+
+    /// Create a new outboard from `data` and a `block_size`, using the given hash mode.
+    pub fn create_with_mode(data: impl AsRef<[u8]>, block_size: BlockSize, mode: HashMode) -> Self {
         let data = data.as_ref();
         let size = data.len() as u64;
-        let tree = BaoTree::new(size, block_size);
+        let tree = BaoTree::new_with_mode(size, block_size, mode);
         let mut outboard = Vec::with_capacity(tree.outboard_size().try_into().unwrap());
         let root = crate::io::sync::outboard_post_order(data, tree, &mut outboard).unwrap();
         Self {
@@ -256,19 +268,7 @@ impl<T: AsRef<[u8]>> crate::io::fsm::Outboard for PostOrderMemOutboard<T> {
 
 impl<T: AsMut<[u8]>> crate::io::sync::OutboardMut for PostOrderMemOutboard<T> {
     fn save(&mut self, node: TreeNode, pair: &(blake3::Hash, blake3::Hash)) -> io::Result<()> {
-        match self.tree.post_order_offset(node) {
-            Some(offset) => {
-                let offset = usize::try_from(offset.value() * 64).unwrap();
-                let data = self.data.as_mut();
-                data[offset..offset + 32].copy_from_slice(pair.0.as_bytes());
-                data[offset + 32..offset + 64].copy_from_slice(pair.1.as_bytes());
-                Ok(())
-            }
-            None => Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "invalid node for this outboard",
-            )),
-        }
+        save_post(&self.tree, self.data.as_mut(), node, pair)
     }
 
     fn sync(&mut self) -> io::Result<()> {
@@ -283,19 +283,7 @@ impl<T: AsMut<[u8]>> crate::io::fsm::OutboardMut for PostOrderMemOutboard<T> {
         node: TreeNode,
         pair: &(blake3::Hash, blake3::Hash),
     ) -> io::Result<()> {
-        match self.tree.post_order_offset(node) {
-            Some(offset) => {
-                let offset = usize::try_from(offset.value() * 64).unwrap();
-                let data = self.data.as_mut();
-                data[offset..offset + 32].copy_from_slice(pair.0.as_bytes());
-                data[offset + 32..offset + 64].copy_from_slice(pair.1.as_bytes());
-                Ok(())
-            }
-            None => Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "invalid node for this outboard",
-            )),
-        }
+        save_post(&self.tree, self.data.as_mut(), node, pair)
     }
 
     async fn sync(&mut self) -> io::Result<()> {
@@ -314,6 +302,26 @@ fn load_post(tree: &BaoTree, data: &[u8], node: TreeNode) -> Option<(blake3::Has
     load_raw_post_mem(tree, data, node).map(parse_hash_pair)
 }
 
+fn save_post(
+    tree: &BaoTree,
+    data: &mut [u8],
+    node: TreeNode,
+    pair: &(blake3::Hash, blake3::Hash),
+) -> io::Result<()> {
+    match tree.post_order_offset(node) {
+        Some(offset) => {
+            let offset = usize::try_from(offset.value() * 64).unwrap();
+            data[offset..offset + 32].copy_from_slice(pair.0.as_bytes());
+            data[offset + 32..offset + 64].copy_from_slice(pair.1.as_bytes());
+            Ok(())
+        }
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid node for this outboard",
+        )),
+    }
+}
+
 /// A pre order outboard that is optimized for memory storage.
 ///
 /// The traits are implemented for fixed size slices or mutable slices, so you
@@ -350,9 +358,14 @@ impl PreOrderMemOutboard {
     ///
     /// This will hash the data and create an outboard
     pub fn create(data: impl AsRef<[u8]>, block_size: BlockSize) -> Self {
+        Self::create_with_mode(data, block_size, HashMode::DEFAULT)
+    }
+
+    /// Create a new outboard from `data` and a `block_size`, using the given hash mode.
+    pub fn create_with_mode(data: impl AsRef<[u8]>, block_size: BlockSize, mode: HashMode) -> Self {
         let data = data.as_ref();
         let size = data.len() as u64;
-        let tree = BaoTree::new(size, block_size);
+        let tree = BaoTree::new_with_mode(size, block_size, mode);
         // the outboard impl for PreOrderMemOutboard requires just AsMut<[u8]>,
         // so the data must already be the right size.
         let outboard = vec![0u8; tree.outboard_size().try_into().unwrap()];
@@ -410,20 +423,7 @@ impl<T: AsRef<[u8]>> crate::io::sync::Outboard for PreOrderMemOutboard<T> {
 
 impl<T: AsMut<[u8]>> crate::io::sync::OutboardMut for PreOrderMemOutboard<T> {
     fn save(&mut self, node: TreeNode, pair: &(blake3::Hash, blake3::Hash)) -> io::Result<()> {
-        match self.tree.pre_order_offset(node) {
-            Some(offset) => {
-                let offset_u64 = offset * 64;
-                let offset = usize::try_from(offset_u64).unwrap();
-                let data = self.data.as_mut();
-                data[offset..offset + 32].copy_from_slice(pair.0.as_bytes());
-                data[offset + 32..offset + 64].copy_from_slice(pair.1.as_bytes());
-                Ok(())
-            }
-            None => Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "invalid node for this outboard",
-            )),
-        }
+        save_pre(&self.tree, self.data.as_mut(), node, pair)
     }
 
     fn sync(&mut self) -> io::Result<()> {
@@ -451,20 +451,7 @@ impl<T: AsMut<[u8]>> crate::io::fsm::OutboardMut for PreOrderMemOutboard<T> {
         node: TreeNode,
         pair: &(blake3::Hash, blake3::Hash),
     ) -> io::Result<()> {
-        match self.tree.pre_order_offset(node) {
-            Some(offset) => {
-                let offset_u64 = offset * 64;
-                let offset = usize::try_from(offset_u64).unwrap();
-                let data = self.data.as_mut();
-                data[offset..offset + 32].copy_from_slice(pair.0.as_bytes());
-                data[offset + 32..offset + 64].copy_from_slice(pair.1.as_bytes());
-                Ok(())
-            }
-            None => Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "invalid node for this outboard",
-            )),
-        }
+        save_pre(&self.tree, self.data.as_mut(), node, pair)
     }
 
     async fn sync(&mut self) -> io::Result<()> {
@@ -487,6 +474,26 @@ fn load_pre(tree: &BaoTree, data: &[u8], node: TreeNode) -> Option<(blake3::Hash
     load_raw_pre_mem(tree, data, node).map(parse_hash_pair)
 }
 
+fn save_pre(
+    tree: &BaoTree,
+    data: &mut [u8],
+    node: TreeNode,
+    pair: &(blake3::Hash, blake3::Hash),
+) -> io::Result<()> {
+    match tree.pre_order_offset(node) {
+        Some(offset) => {
+            let offset = usize::try_from(offset * 64).unwrap();
+            data[offset..offset + 32].copy_from_slice(pair.0.as_bytes());
+            data[offset + 32..offset + 64].copy_from_slice(pair.1.as_bytes());
+            Ok(())
+        }
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid node for this outboard",
+        )),
+    }
+}
+
 pub(crate) fn parse_hash_pair(buf: [u8; 64]) -> (blake3::Hash, blake3::Hash) {
     let l_hash = blake3::Hash::from(<[u8; 32]>::try_from(&buf[..32]).unwrap());
     let r_hash = blake3::Hash::from(<[u8; 32]>::try_from(&buf[32..]).unwrap());
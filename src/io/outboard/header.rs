@@ -0,0 +1,383 @@
+//! Self describing outboard files
+//!
+//! An outboard file with a header starts with [HEADER_SIZE] bytes that describe
+//! the outboard, followed by the hash pairs in pre or post order:
+//!
+//! | offset | size | content                                       |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 7    | magic bytes `BAOTREE`                         |
+//! | 7      | 1    | format version, currently 1                   |
+//! | 8      | 1    | order, 0 for pre order, 1 for post order      |
+//! | 9      | 1    | block size, as the log2 of the chunk group size |
+//! | 10     | 1    | hash mode, 0 for default, 1 for keyed, 2 for derive key |
+//! | 11     | 5    | reserved, must be 0                           |
+//! | 16     | 8    | data size in bytes, little endian             |
+//! | 24     | 32   | root hash                                     |
+//! | 56     | 8    | first 8 bytes of the blake3 hash of bytes 0..56 |
+//!
+//! The key of a keyed or derive key [HashMode] is not stored, only the kind of
+//! mode, so the mode has to be provided when opening an outboard.
+//!
+//! Use [open_with_header](crate::io::sync::open_with_header) and
+//! [create_with_header](crate::io::sync::create_with_header) or their async
+//! equivalents to get an outboard that is ready to use.
+use std::io;
+
+use positioned_io::{ReadAt, Size, WriteAt};
+
+use super::{PostOrderOutboard, PreOrderOutboard};
+use crate::{blake3, BaoTree, BlockSize, HashMode, TreeNode};
+
+/// Size of the header in bytes
+pub const HEADER_SIZE: usize = 64;
+
+const MAGIC: &[u8; 7] = b"BAOTREE";
+
+const VERSION: u8 = 1;
+
+/// Order of the hash pairs in an outboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutboardOrder {
+    /// Pre order, like [PreOrderOutboard]
+    PreOrder,
+    /// Post order, like [PostOrderOutboard]
+    PostOrder,
+}
+
+/// The header of a self describing outboard file
+///
+/// The header records the kind of [HashMode] of the tree, but not the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// Order of the hash pairs after the header
+    pub order: OutboardOrder,
+    /// The tree geometry, with the data size, block size and hash mode
+    pub tree: BaoTree,
+    /// The root hash of the data
+    pub root: blake3::Hash,
+}
+
+fn invalid_header(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn checksum(bytes: &[u8]) -> [u8; 8] {
+    blake3::hash(bytes).as_bytes()[..8].try_into().unwrap()
+}
+
+impl Header {
+    /// Serialize the header.
+    ///
+    /// Fails with [io::ErrorKind::InvalidInput] if the block size is larger
+    /// than [BlockSize::MAX_CHUNK_LOG], since such a header could not be read.
+    pub fn to_bytes(&self) -> io::Result<[u8; HEADER_SIZE]> {
+        if self.tree.block_size.0 > BlockSize::MAX_CHUNK_LOG {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid block size {}", self.tree.block_size.0),
+            ));
+        }
+        let mut res = [0u8; HEADER_SIZE];
+        res[0..7].copy_from_slice(MAGIC);
+        res[7] = VERSION;
+        res[8] = match self.order {
+            OutboardOrder::PreOrder => 0,
+            OutboardOrder::PostOrder => 1,
+        };
+        res[9] = self.tree.block_size.0;
+        res[10] = self.tree.mode.tag();
+        res[16..24].copy_from_slice(&self.tree.size.to_le_bytes());
+        res[24..56].copy_from_slice(self.root.as_bytes());
+        let checksum = checksum(&res[..56]);
+        res[56..64].copy_from_slice(&checksum);
+        Ok(res)
+    }
+
+    /// Deserialize a header that was serialized with [Header::to_bytes].
+    ///
+    /// The hash mode of the tree is not fully stored in the header, so it has
+    /// to be given. Only the kind of mode is checked, a wrong key will show up
+    /// as hash mismatches when using the outboard.
+    ///
+    /// Fails with [io::ErrorKind::InvalidData] and a message describing the
+    /// problem if the header is invalid or was written for a different kind of
+    /// hash mode.
+    pub fn from_bytes(bytes: &[u8; HEADER_SIZE], mode: HashMode) -> io::Result<Self> {
+        if &bytes[0..7] != MAGIC {
+            return Err(invalid_header(
+                "not an outboard with header, magic mismatch",
+            ));
+        }
+        if bytes[7] != VERSION {
+            return Err(invalid_header(format!(
+                "unsupported header version {}",
+                bytes[7]
+            )));
+        }
+        if checksum(&bytes[..56]) != bytes[56..64] {
+            return Err(invalid_header("header checksum mismatch"));
+        }
+        let order = match bytes[8] {
+            0 => OutboardOrder::PreOrder,
+            1 => OutboardOrder::PostOrder,
+            x => return Err(invalid_header(format!("invalid outboard order {}", x))),
+        };
+        if bytes[9] > BlockSize::MAX_CHUNK_LOG {
+            return Err(invalid_header(format!("invalid block size {}", bytes[9])));
+        }
+        if bytes[10] != mode.tag() {
+            return Err(invalid_header(format!(
+                "hash mode mismatch, expected {:?} but the header has mode {}",
+                mode, bytes[10]
+            )));
+        }
+        if bytes[11..16] != [0u8; 5] {
+            return Err(invalid_header("reserved header bytes are not zero"));
+        }
+        let size = u64::from_le_bytes(bytes[16..24].try_into().unwrap());
+        let root = blake3::Hash::from(<[u8; 32]>::try_from(&bytes[24..56]).unwrap());
+        Ok(Self {
+            order,
+            tree: BaoTree::new_with_mode(size, BlockSize(bytes[9]), mode),
+            root,
+        })
+    }
+
+    /// Error for an outboard that is too short to contain a header
+    pub(crate) fn too_short() -> io::Error {
+        invalid_header("outboard is too short to contain a header")
+    }
+
+    /// Check that an outboard file of the given length matches the header.
+    pub(crate) fn check_len(&self, len: u64) -> io::Result<()> {
+        let expected = HEADER_SIZE as u64 + self.tree.outboard_size();
+        if len != expected {
+            return Err(invalid_header(format!(
+                "outboard length {} does not match the header, expected {}",
+                len, expected
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The part of a file or buffer after the header.
+///
+/// All offsets are shifted by [HEADER_SIZE], so this can be used as the data
+/// of a [PreOrderOutboard] or [PostOrderOutboard].
+#[derive(Debug)]
+pub struct AfterHeader<D>(pub(crate) D);
+
+impl<D> AfterHeader<D> {
+    /// Get a reference to the underlying file or buffer
+    pub fn get_ref(&self) -> &D {
+        &self.0
+    }
+
+    /// Get the underlying file or buffer, including the header
+    pub fn into_inner(self) -> D {
+        self.0
+    }
+}
+
+impl<D: ReadAt> ReadAt for AfterHeader<D> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read_at(pos + HEADER_SIZE as u64, buf)
+    }
+}
+
+impl<D: WriteAt> WriteAt for AfterHeader<D> {
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_at(pos + HEADER_SIZE as u64, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<D: Size> Size for AfterHeader<D> {
+    fn size(&self) -> io::Result<Option<u64>> {
+        Ok(self
+            .0
+            .size()?
+            .map(|size| size.saturating_sub(HEADER_SIZE as u64)))
+    }
+}
+
+#[cfg(feature = "tokio_fsm")]
+impl<D: iroh_io::AsyncSliceReader> iroh_io::AsyncSliceReader for AfterHeader<D> {
+    async fn read_at(&mut self, offset: u64, len: usize) -> io::Result<bytes::Bytes> {
+        self.0.read_at(offset + HEADER_SIZE as u64, len).await
+    }
+
+    async fn size(&mut self) -> io::Result<u64> {
+        Ok(self.0.size().await?.saturating_sub(HEADER_SIZE as u64))
+    }
+}
+
+#[cfg(feature = "tokio_fsm")]
+impl<D: iroh_io::AsyncSliceWriter> iroh_io::AsyncSliceWriter for AfterHeader<D> {
+    async fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.0.write_at(offset + HEADER_SIZE as u64, data).await
+    }
+
+    async fn write_bytes_at(&mut self, offset: u64, data: bytes::Bytes) -> io::Result<()> {
+        self.0
+            .write_bytes_at(offset + HEADER_SIZE as u64, data)
+            .await
+    }
+
+    async fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.0.set_len(len + HEADER_SIZE as u64).await
+    }
+
+    async fn sync(&mut self) -> io::Result<()> {
+        self.0.sync().await
+    }
+}
+
+/// An outboard in a file or buffer that starts with a [Header].
+///
+/// Depending on the order in the header, the hash pairs after the header are
+/// in pre or post order.
+#[derive(Debug)]
+pub enum HeaderOutboard<D> {
+    /// Hash pairs in pre order
+    PreOrder(PreOrderOutboard<AfterHeader<D>>),
+    /// Hash pairs in post order
+    PostOrder(PostOrderOutboard<AfterHeader<D>>),
+}
+
+impl<D> HeaderOutboard<D> {
+    /// Create an outboard from a header and the file or buffer, which
+    /// includes the header.
+    pub(crate) fn new(header: Header, data: D) -> Self {
+        let Header { order, tree, root } = header;
+        let data = AfterHeader(data);
+        match order {
+            OutboardOrder::PreOrder => Self::PreOrder(PreOrderOutboard { root, tree, data }),
+            OutboardOrder::PostOrder => Self::PostOrder(PostOrderOutboard { root, tree, data }),
+        }
+    }
+
+    /// Set the root hash, after the outboard has been computed
+    pub(crate) fn set_root(&mut self, root: blake3::Hash) {
+        match self {
+            Self::PreOrder(ob) => ob.root = root,
+            Self::PostOrder(ob) => ob.root = root,
+        }
+    }
+
+    /// Get a mutable reference to the underlying file or buffer
+    pub(crate) fn inner_mut(&mut self) -> &mut D {
+        match self {
+            Self::PreOrder(ob) => &mut ob.data.0,
+            Self::PostOrder(ob) => &mut ob.data.0,
+        }
+    }
+
+    /// The header of the outboard
+    pub fn header(&self) -> Header {
+        match self {
+            Self::PreOrder(ob) => Header {
+                order: OutboardOrder::PreOrder,
+                tree: ob.tree,
+                root: ob.root,
+            },
+            Self::PostOrder(ob) => Header {
+                order: OutboardOrder::PostOrder,
+                tree: ob.tree,
+                root: ob.root,
+            },
+        }
+    }
+
+    /// Get the underlying file or buffer, including the header
+    pub fn into_inner(self) -> D {
+        match self {
+            Self::PreOrder(ob) => ob.data.into_inner(),
+            Self::PostOrder(ob) => ob.data.into_inner(),
+        }
+    }
+}
+
+impl<D: ReadAt> crate::io::sync::Outboard for HeaderOutboard<D> {
+    fn root(&self) -> blake3::Hash {
+        match self {
+            Self::PreOrder(ob) => ob.root,
+            Self::PostOrder(ob) => ob.root,
+        }
+    }
+    fn tree(&self) -> BaoTree {
+        match self {
+            Self::PreOrder(ob) => ob.tree,
+            Self::PostOrder(ob) => ob.tree,
+        }
+    }
+    fn load(&self, node: TreeNode) -> io::Result<Option<(blake3::Hash, blake3::Hash)>> {
+        match self {
+            Self::PreOrder(ob) => ob.load(node),
+            Self::PostOrder(ob) => ob.load(node),
+        }
+    }
+}
+
+impl<D: WriteAt> crate::io::sync::OutboardMut for HeaderOutboard<D> {
+    fn save(&mut self, node: TreeNode, pair: &(blake3::Hash, blake3::Hash)) -> io::Result<()> {
+        match self {
+            Self::PreOrder(ob) => ob.save(node, pair),
+            Self::PostOrder(ob) => ob.save(node, pair),
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        match self {
+            Self::PreOrder(ob) => ob.sync(),
+            Self::PostOrder(ob) => ob.sync(),
+        }
+    }
+}
+
+#[cfg(feature = "tokio_fsm")]
+impl<D: iroh_io::AsyncSliceReader> crate::io::fsm::Outboard for HeaderOutboard<D> {
+    fn root(&self) -> blake3::Hash {
+        match self {
+            Self::PreOrder(ob) => ob.root,
+            Self::PostOrder(ob) => ob.root,
+        }
+    }
+    fn tree(&self) -> BaoTree {
+        match self {
+            Self::PreOrder(ob) => ob.tree,
+            Self::PostOrder(ob) => ob.tree,
+        }
+    }
+    async fn load(&mut self, node: TreeNode) -> io::Result<Option<(blake3::Hash, blake3::Hash)>> {
+        match self {
+            Self::PreOrder(ob) => ob.load(node).await,
+            Self::PostOrder(ob) => ob.load(node).await,
+        }
+    }
+}
+
+#[cfg(feature = "tokio_fsm")]
+impl<D: iroh_io::AsyncSliceWriter> crate::io::fsm::OutboardMut for HeaderOutboard<D> {
+    async fn save(
+        &mut self,
+        node: TreeNode,
+        pair: &(blake3::Hash, blake3::Hash),
+    ) -> io::Result<()> {
+        match self {
+            Self::PreOrder(ob) => ob.save(node, pair).await,
+            Self::PostOrder(ob) => ob.save(node, pair).await,
+        }
+    }
+
+    async fn sync(&mut self) -> io::Result<()> {
+        match self {
+            Self::PreOrder(ob) => ob.sync().await,
+            Self::PostOrder(ob) => ob.sync().await,
+        }
+    }
+}
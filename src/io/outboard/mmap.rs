@@ -0,0 +1,104 @@
+//! Memory mapped outboards and data
+//!
+//! The memory outboards [PreOrderMemOutboard] and [PostOrderMemOutboard] work
+//! with a memory map as storage, use [Mmap] for reading and [MmapMut] for
+//! writing. Loading a hash pair from a memory mapped outboard is just a copy
+//! from the mapped memory, instead of a syscall per node as for e.g.
+//! [PreOrderOutboard](super::PreOrderOutboard) backed by a file.
+//!
+//! Just like for other storage, the mapped outboard must already have the
+//! right size for the tree.
+use std::{fs::File, io};
+
+use memmap2::{Mmap, MmapMut};
+use positioned_io::{ReadAt, Size};
+
+use super::{PostOrderMemOutboard, PreOrderMemOutboard};
+
+/// Memory mapped data of a blob.
+///
+/// Implements [ReadAt] and [Size] for sync io, and
+/// [AsyncSliceReader](iroh_io::AsyncSliceReader) if the `tokio_fsm` feature is enabled.
+#[derive(Debug)]
+pub struct MmapData(Mmap);
+
+impl MmapData {
+    /// Map a file into memory.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated while it is mapped, see
+    /// [Mmap::map].
+    pub unsafe fn open(file: &File) -> io::Result<Self> {
+        Ok(Self(Mmap::map(file)?))
+    }
+
+    /// Return the underlying memory map.
+    pub fn into_inner(self) -> Mmap {
+        self.0
+    }
+}
+
+impl From<Mmap> for MmapData {
+    fn from(mmap: Mmap) -> Self {
+        Self(mmap)
+    }
+}
+
+impl AsRef<[u8]> for MmapData {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl ReadAt for MmapData {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let data = &self.0[..];
+        let start = usize::try_from(pos).unwrap_or(usize::MAX).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+}
+
+impl Size for MmapData {
+    fn size(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.0.len() as u64))
+    }
+}
+
+#[cfg(feature = "tokio_fsm")]
+impl iroh_io::AsyncSliceReader for MmapData {
+    async fn read_at(&mut self, offset: u64, len: usize) -> io::Result<bytes::Bytes> {
+        let data = &self.0[..];
+        let start = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(data.len());
+        let end = start.saturating_add(len).min(data.len());
+        Ok(bytes::Bytes::copy_from_slice(&data[start..end]))
+    }
+
+    async fn size(&mut self) -> io::Result<u64> {
+        Ok(self.0.len() as u64)
+    }
+}
+
+impl PreOrderMemOutboard<MmapMut> {
+    /// Flush the mapped outboard to the file.
+    ///
+    /// [sync](crate::io::sync::OutboardMut::sync) is a no-op for memory
+    /// outboards, so call this to make sure the outboard is persisted.
+    pub fn flush(&self) -> io::Result<()> {
+        self.data.flush()
+    }
+}
+
+impl PostOrderMemOutboard<MmapMut> {
+    /// Flush the mapped outboard to the file.
+    ///
+    /// [sync](crate::io::sync::OutboardMut::sync) is a no-op for memory
+    /// outboards, so call this to make sure the outboard is persisted.
+    pub fn flush(&self) -> io::Result<()> {
+        self.data.flush()
+    }
+}
@@ -0,0 +1,281 @@
+//! A blob that is only partially available
+//!
+//! [PartialBlob] bundles the data, the outboard and the set of chunks that
+//! have been verified, so the available ranges of a blob do not have to be
+//! recomputed using [valid_ranges](crate::io::sync::valid_ranges) after a restart.
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::PathBuf,
+    result,
+};
+
+use positioned_io::{ReadAt, WriteAt};
+use smallvec::SmallVec;
+
+use super::{
+    read_len, read_u64,
+    sync::{DecodeResponseIter, Outboard, OutboardMut},
+    BaoContentItem, DecodeError, Leaf, Parent,
+};
+use crate::{ChunkNum, ChunkRanges, ChunkRangesRef};
+
+/// Persistent storage for the ranges of a [PartialBlob] that are available.
+pub trait RangesStore {
+    /// Load the ranges. If nothing has been stored yet, this returns the empty set.
+    fn load(&mut self) -> io::Result<ChunkRanges>;
+    /// Store the ranges, replacing the previously stored ranges.
+    ///
+    /// This must be atomic, so that after a crash either the old or the new
+    /// ranges are loaded.
+    fn store(&mut self, ranges: &ChunkRangesRef) -> io::Result<()>;
+}
+
+/// A [RangesStore] that just keeps the ranges in memory.
+#[derive(Debug, Clone)]
+pub struct MemRangesStore(ChunkRanges);
+
+impl Default for MemRangesStore {
+    fn default() -> Self {
+        Self(ChunkRanges::empty())
+    }
+}
+
+impl MemRangesStore {
+    /// The ranges that were stored last
+    pub fn ranges(&self) -> &ChunkRangesRef {
+        &self.0
+    }
+}
+
+impl RangesStore for MemRangesStore {
+    fn load(&mut self) -> io::Result<ChunkRanges> {
+        Ok(self.0.clone())
+    }
+
+    fn store(&mut self, ranges: &ChunkRangesRef) -> io::Result<()> {
+        self.0 = ChunkRanges::new_unchecked(ranges.boundaries().into());
+        Ok(())
+    }
+}
+
+/// A [RangesStore] that stores the ranges in a file.
+///
+/// The ranges are written to a temporary file next to the target file, which
+/// is then renamed to the target file. On unix, the directory is synced after
+/// the rename, so the new ranges survive a crash. The format is the number of range
+/// boundaries followed by the boundaries, all as little endian u64.
+#[derive(Debug, Clone)]
+pub struct FileRangesStore {
+    path: PathBuf,
+}
+
+impl FileRangesStore {
+    /// Create a new store for the given path.
+    ///
+    /// The file does not have to exist.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// The path of the file
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_owned();
+        name.push(".tmp");
+        self.path.with_file_name(name)
+    }
+}
+
+impl RangesStore for FileRangesStore {
+    fn load(&mut self) -> io::Result<ChunkRanges> {
+        let mut file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(ChunkRanges::empty()),
+            Err(e) => return Err(e),
+        };
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let mut data = &buf[..];
+        let n = read_len(&mut data, 8)?;
+        let mut boundaries = SmallVec::with_capacity(n);
+        for _ in 0..n {
+            boundaries.push(ChunkNum(read_u64(&mut data)?));
+        }
+        if !data.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "trailing data after ranges",
+            ));
+        }
+        ChunkRanges::new(boundaries)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid ranges"))
+    }
+
+    fn store(&mut self, ranges: &ChunkRangesRef) -> io::Result<()> {
+        let boundaries = ranges.boundaries();
+        let mut buf = Vec::with_capacity(8 + boundaries.len() * 8);
+        buf.extend_from_slice(&(boundaries.len() as u64).to_le_bytes());
+        for boundary in boundaries {
+            buf.extend_from_slice(&boundary.0.to_le_bytes());
+        }
+        let temp_path = self.temp_path();
+        let mut file = fs::File::create(&temp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+        // the rename is only durable once the directory entry is synced
+        #[cfg(unix)]
+        {
+            let parent = match self.path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => std::path::Path::new("."),
+            };
+            fs::File::open(parent)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// A blob of which only some chunks are available.
+///
+/// This bundles the data, the outboard, and a [RangesStore] that records which
+/// chunks have been verified. Leaves are only recorded as available after they
+/// have been validated and written, so the stored ranges never claim data
+/// that is not there.
+#[derive(Debug)]
+pub struct PartialBlob<D, O, S> {
+    data: D,
+    outboard: O,
+    store: S,
+    available: ChunkRanges,
+}
+
+impl<D, O, S> PartialBlob<D, O, S>
+where
+    D: ReadAt + WriteAt,
+    O: Outboard + OutboardMut,
+    S: RangesStore,
+{
+    /// Open a partial blob, loading the available ranges from the store.
+    ///
+    /// The outboard must have the root hash and tree of the complete blob.
+    pub fn new(data: D, outboard: O, mut store: S) -> io::Result<Self> {
+        let available = store.load()?;
+        Ok(Self {
+            data,
+            outboard,
+            store,
+            available,
+        })
+    }
+
+    /// The chunks that are available and verified
+    pub fn available(&self) -> &ChunkRangesRef {
+        &self.available
+    }
+
+    /// The chunks that are missing
+    pub fn missing(&self) -> ChunkRanges {
+        &ChunkRanges::from(..self.outboard.tree().chunks()) - &self.available
+    }
+
+    /// True if all chunks are available
+    pub fn is_complete(&self) -> bool {
+        self.missing().is_empty()
+    }
+
+    /// The data
+    pub fn data(&self) -> &D {
+        &self.data
+    }
+
+    /// The outboard
+    pub fn outboard(&self) -> &O {
+        &self.outboard
+    }
+
+    /// Decode a response for the given ranges into the blob.
+    ///
+    /// Parents are saved to the outboard and leaves are written to the data.
+    /// The ranges do not have to be aligned to chunk groups.
+    ///
+    /// Once the response is decoded, or decoding fails, the data and outboard
+    /// are synced and the new available ranges are stored once. So if decoding
+    /// fails, e.g. because the connection was dropped, the chunks that were
+    /// already written are not lost. Chunks written since the last call are
+    /// only lost if the process itself is interrupted.
+    pub fn decode(
+        &mut self,
+        encoded: impl Read,
+        ranges: &ChunkRangesRef,
+    ) -> result::Result<(), DecodeError> {
+        if ranges.is_empty() {
+            // the response to an empty request is empty
+            return Ok(());
+        }
+        let before = self.available.clone();
+        let res = self.decode_leaves(encoded, ranges);
+        if self.available == before {
+            return res;
+        }
+        let persisted = self.persist();
+        // report the decode error first, it is the cause of the problem
+        res?;
+        Ok(persisted?)
+    }
+
+    /// Sync the data and outboard, then store the available ranges.
+    fn persist(&mut self) -> io::Result<()> {
+        self.data.flush()?;
+        self.outboard.sync()?;
+        self.store.store(&self.available)
+    }
+
+    /// Decode the response, and add the written leaves to the available ranges
+    /// without storing them.
+    fn decode_leaves(
+        &mut self,
+        encoded: impl Read,
+        ranges: &ChunkRangesRef,
+    ) -> result::Result<(), DecodeError> {
+        let tree = self.outboard.tree();
+        let iter = DecodeResponseIter::new(self.outboard.root(), tree, encoded, ranges);
+        for item in iter {
+            match item? {
+                BaoContentItem::Parent(Parent { node, pair }) => {
+                    // parents inside a chunk group are not stored in the outboard
+                    if tree.is_relevant_for_outboard(node) {
+                        self.outboard.save(node, &pair)?;
+                    }
+                }
+                BaoContentItem::Leaf(Leaf { offset, data }) => {
+                    self.data.write_all_at(offset, &data)?;
+                    let start = ChunkNum::full_chunks(offset);
+                    let end = ChunkNum::chunks(offset + data.len() as u64);
+                    self.available |= ChunkRanges::from(start..end);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Convert to a complete blob, returning the data and outboard.
+    ///
+    /// If the blob is not complete, the partial blob is returned unchanged.
+    pub fn into_complete(self) -> result::Result<(D, O), Self> {
+        if self.is_complete() {
+            Ok((self.data, self.outboard))
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Return the data, outboard and store.
+    pub fn into_inner(self) -> (D, O, S) {
+        (self.data, self.outboard, self.store)
+    }
+}
@@ -0,0 +1,302 @@
+//! Self-contained proofs for ranges of a blob
+//!
+//! A [RangeProof] contains everything that is needed to verify a set of chunk
+//! ranges against a root hash, so it can be stored or sent independently of
+//! any outboard.
+use std::{io, result};
+
+use bytes::Bytes;
+use positioned_io::ReadAt;
+use range_collections::range_set::RangeSetRange;
+use smallvec::SmallVec;
+
+use crate::{
+    blake3,
+    io::sync::{read_parent, Outboard},
+    iter::BaoChunk,
+    rec::truncate_ranges,
+    BaoTree, BlockSize, ChunkNum, ChunkRanges, ChunkRangesRef, HashMode,
+};
+
+use super::{combine_hash_pair, read_len, read_u64, DecodeError};
+
+/// A merkle proof for a set of chunk ranges of a blob.
+///
+/// The proof contains the hash pairs of all parents on the path from the root
+/// to the requested ranges, in pre order, as well as the data of all chunk
+/// groups that overlap with the requested ranges.
+///
+/// The proof does not contain the root hash, size and block size of the blob.
+/// These have to be provided when verifying, since a proof is only meaningful
+/// relative to a known root hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeProof {
+    /// the requested ranges, truncated to the size of the blob
+    ranges: ChunkRanges,
+    /// hash pairs of the parents, in pre order
+    parents: Vec<(blake3::Hash, blake3::Hash)>,
+    /// data of the chunk groups, in order
+    leaves: Vec<Bytes>,
+}
+
+impl RangeProof {
+    /// Create a proof for the given ranges from an outboard and the data.
+    ///
+    /// The data is not validated, so creating a proof from corrupted data
+    /// will succeed, but the proof will fail to verify.
+    pub fn create(
+        outboard: impl Outboard,
+        data: impl ReadAt,
+        ranges: &ChunkRangesRef,
+    ) -> io::Result<Self> {
+        let tree = outboard.tree();
+        let ranges = truncate_ranges(ranges, tree.size());
+        let mut parents = Vec::new();
+        let mut leaves = Vec::new();
+        for item in tree.ranges_pre_order_chunks_iter_ref(ranges, 0) {
+            match item {
+                BaoChunk::Parent { node, .. } => {
+                    let Some(pair) = outboard.load(node)? else {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("parent not found (level {})", node.level()),
+                        ));
+                    };
+                    parents.push(pair);
+                }
+                BaoChunk::Leaf {
+                    start_chunk, size, ..
+                } => {
+                    let mut buf = vec![0u8; size];
+                    data.read_exact_at(start_chunk.to_bytes(), &mut buf)?;
+                    leaves.push(buf.into());
+                }
+            }
+        }
+        Ok(Self {
+            ranges: ChunkRanges::new_unchecked(ranges.boundaries().into()),
+            parents,
+            leaves,
+        })
+    }
+
+    /// The ranges covered by this proof.
+    pub fn ranges(&self) -> &ChunkRangesRef {
+        &self.ranges
+    }
+
+    /// Verify the proof against a root hash, the size of the blob and the
+    /// block size of the tree.
+    ///
+    /// On success, returns the data of the requested ranges, concatenated in
+    /// order. Chunk groups are only partially returned if they are partially
+    /// covered by the ranges.
+    ///
+    /// The ranges are taken from the proof itself, so a valid proof for other
+    /// ranges of the same blob also verifies. Callers must check
+    /// [RangeProof::ranges], or use [RangeProof::verify_ranges].
+    pub fn verify(
+        &self,
+        root: blake3::Hash,
+        size: u64,
+        block_size: BlockSize,
+    ) -> result::Result<Bytes, DecodeError> {
+        self.verify_with_mode(root, size, block_size, HashMode::DEFAULT)
+    }
+
+    /// Verify the proof, and check that it covers exactly the expected ranges.
+    ///
+    /// The expected ranges are truncated to the size in the same way as when
+    /// creating the proof. Fails with [io::ErrorKind::InvalidData] if they
+    /// don't match the ranges of the proof. See [RangeProof::verify].
+    pub fn verify_ranges(
+        &self,
+        root: blake3::Hash,
+        size: u64,
+        block_size: BlockSize,
+        expected: &ChunkRangesRef,
+    ) -> result::Result<Bytes, DecodeError> {
+        self.verify_ranges_with_mode(root, size, block_size, HashMode::DEFAULT, expected)
+    }
+
+    /// Same as [RangeProof::verify_ranges], for a tree that uses the given hash
+    /// mode.
+    pub fn verify_ranges_with_mode(
+        &self,
+        root: blake3::Hash,
+        size: u64,
+        block_size: BlockSize,
+        mode: HashMode,
+        expected: &ChunkRangesRef,
+    ) -> result::Result<Bytes, DecodeError> {
+        if truncate_ranges(&self.ranges, size) != truncate_ranges(expected, size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "proof does not cover the expected ranges",
+            )
+            .into());
+        }
+        self.verify_with_mode(root, size, block_size, mode)
+    }
+
+    /// Verify the proof for a tree that uses the given hash mode.
+    ///
+    /// See [RangeProof::verify].
+    pub fn verify_with_mode(
+        &self,
+        root: blake3::Hash,
+        size: u64,
+        block_size: BlockSize,
+        mode: HashMode,
+    ) -> result::Result<Bytes, DecodeError> {
+        let tree = BaoTree::new_with_mode(size, block_size, mode);
+        // the ranges come from an untrusted source, so they must be limited
+        // to the size before they are used to compute byte offsets
+        let ranges = truncate_ranges(&self.ranges, size);
+        let mut stack = SmallVec::<[blake3::Hash; 10]>::new();
+        stack.push(root);
+        let mut parents = self.parents.iter();
+        let mut leaves = self.leaves.iter();
+        let mut res = Vec::new();
+        for item in tree.ranges_pre_order_chunks_iter_ref(ranges, 0) {
+            match item {
+                BaoChunk::Parent {
+                    node,
+                    is_root,
+                    left,
+                    right,
+                    ..
+                } => {
+                    let (l_hash, r_hash) =
+                        parents.next().ok_or(DecodeError::ParentNotFound(node))?;
+                    let expected = stack.pop().unwrap();
+                    let actual = mode.parent_cv(l_hash, r_hash, is_root);
+                    if actual != expected {
+                        return Err(DecodeError::ParentHashMismatch(node));
+                    }
+                    if right {
+                        stack.push(*r_hash);
+                    }
+                    if left {
+                        stack.push(*l_hash);
+                    }
+                }
+                BaoChunk::Leaf {
+                    start_chunk,
+                    size,
+                    is_root,
+                    ranges,
+                } => {
+                    let data = leaves
+                        .next()
+                        .ok_or(DecodeError::LeafNotFound(start_chunk))?;
+                    let expected = stack.pop().unwrap();
+                    if data.len() != size
+                        || mode.hash_subtree(start_chunk.0, data, is_root) != expected
+                    {
+                        return Err(DecodeError::LeafHashMismatch(start_chunk));
+                    }
+                    copy_selected(start_chunk, data, ranges, &mut res);
+                }
+            }
+        }
+        if parents.next().is_some() || leaves.next().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "proof contains more items than required",
+            )
+            .into());
+        }
+        Ok(res.into())
+    }
+
+    /// Serialize the proof.
+    ///
+    /// The format is the number of range boundaries followed by the boundaries,
+    /// the number of hash pairs followed by the hash pairs, and the number of
+    /// chunk groups followed by the length and data of each chunk group. All
+    /// numbers are encoded as little endian u64.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let boundaries = self.ranges.boundaries();
+        // the capacity is just a hint, so don't preallocate if it overflows
+        let capacity = self
+            .leaves
+            .iter()
+            .try_fold(24usize, |acc, x| acc.checked_add(x.len())?.checked_add(8))
+            .and_then(|x| x.checked_add(boundaries.len().checked_mul(8)?))
+            .and_then(|x| x.checked_add(self.parents.len().checked_mul(64)?))
+            .unwrap_or_default();
+        let mut res = Vec::with_capacity(capacity);
+        res.extend_from_slice(&(boundaries.len() as u64).to_le_bytes());
+        for boundary in boundaries {
+            res.extend_from_slice(&boundary.0.to_le_bytes());
+        }
+        res.extend_from_slice(&(self.parents.len() as u64).to_le_bytes());
+        for (l_hash, r_hash) in &self.parents {
+            res.extend_from_slice(&combine_hash_pair(l_hash, r_hash));
+        }
+        res.extend_from_slice(&(self.leaves.len() as u64).to_le_bytes());
+        for leaf in &self.leaves {
+            res.extend_from_slice(&(leaf.len() as u64).to_le_bytes());
+            res.extend_from_slice(leaf);
+        }
+        res
+    }
+
+    /// Deserialize a proof that was serialized with [RangeProof::to_bytes].
+    ///
+    /// This only checks that the proof is well formed. Use [RangeProof::verify]
+    /// to check it against a root hash.
+    pub fn from_bytes(mut data: &[u8]) -> io::Result<Self> {
+        let n = read_len(&mut data, 8)?;
+        let mut boundaries = SmallVec::with_capacity(n);
+        for _ in 0..n {
+            boundaries.push(ChunkNum(read_u64(&mut data)?));
+        }
+        let ranges = ChunkRanges::new(boundaries)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid ranges"))?;
+        let n = read_len(&mut data, 64)?;
+        let mut parents = Vec::with_capacity(n);
+        for _ in 0..n {
+            parents.push(read_parent(&mut data)?);
+        }
+        let n = read_len(&mut data, 8)?;
+        let mut leaves = Vec::with_capacity(n);
+        for _ in 0..n {
+            let len = read_len(&mut data, 1)?;
+            let (leaf, rest) = data.split_at(len);
+            leaves.push(Bytes::copy_from_slice(leaf));
+            data = rest;
+        }
+        if !data.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "trailing data after proof",
+            ));
+        }
+        Ok(Self {
+            ranges,
+            parents,
+            leaves,
+        })
+    }
+}
+
+/// Copy the parts of a chunk group that are within the ranges
+///
+/// The ranges must be truncated to the size of the blob, so converting them
+/// to byte offsets can not overflow.
+fn copy_selected(start_chunk: ChunkNum, data: &[u8], ranges: &ChunkRangesRef, res: &mut Vec<u8>) {
+    let start = start_chunk.to_bytes();
+    let end = start + data.len() as u64;
+    for range in ranges.iter() {
+        let (s, e) = match range {
+            RangeSetRange::Range(range) => (range.start.to_bytes(), range.end.to_bytes()),
+            RangeSetRange::RangeFrom(range) => (range.start.to_bytes(), end),
+        };
+        let (s, e) = (s.max(start), e.min(end));
+        if s < e {
+            res.extend_from_slice(&data[(s - start) as usize..(e - start) as usize]);
+        }
+    }
+}
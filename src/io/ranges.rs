@@ -0,0 +1,110 @@
+//! Compact wire encoding for chunk ranges
+//!
+//! A [ChunkRanges] is encoded as the number of boundaries, followed by the
+//! boundaries, all as unsigned [LEB128](https://en.wikipedia.org/wiki/LEB128)
+//! varints. The first boundary is encoded as is, every following boundary as
+//! the difference to the previous one. If the number of boundaries is odd, the
+//! last range is open, e.g. `10..` is encoded as `[1, 10]`, and `10..20` as
+//! `[2, 10, 10]`.
+//!
+//! Decoding is strict, so every set of ranges has exactly one encoding. Non
+//! minimal varints, varints that overflow, and differences of zero, which would
+//! mean unsorted boundaries or empty ranges, are rejected.
+use std::io;
+
+use smallvec::SmallVec;
+
+use crate::{ChunkNum, ChunkRanges, ChunkRangesRef};
+
+/// A reasonable default for the maximum number of ranges when decoding
+pub const DEFAULT_MAX_RANGES: usize = 1024;
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub(crate) fn write_varint(mut value: u64, target: &mut Vec<u8>) {
+    while value >= 0x80 {
+        target.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    target.push(value as u8);
+}
+
+pub(crate) fn read_varint(data: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0u64;
+    for i in 0..10 {
+        let Some((&byte, rest)) = data.split_first() else {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        };
+        *data = rest;
+        let bits = u64::from(byte & 0x7f);
+        if i == 9 && bits > 1 {
+            return Err(invalid_data("varint overflow"));
+        }
+        value |= bits << (7 * i);
+        if byte & 0x80 == 0 {
+            if byte == 0 && i > 0 {
+                return Err(invalid_data("non minimal varint"));
+            }
+            return Ok(value);
+        }
+    }
+    Err(invalid_data("varint overflow"))
+}
+
+/// Append the encoding of the ranges to a buffer.
+pub fn write_chunk_ranges(ranges: &ChunkRangesRef, target: &mut Vec<u8>) {
+    let boundaries = ranges.boundaries();
+    write_varint(boundaries.len() as u64, target);
+    let mut prev = 0;
+    for boundary in boundaries {
+        write_varint(boundary.0 - prev, target);
+        prev = boundary.0;
+    }
+}
+
+/// Encode the ranges.
+pub fn encode_chunk_ranges(ranges: &ChunkRangesRef) -> Vec<u8> {
+    let mut res = Vec::new();
+    write_chunk_ranges(ranges, &mut res);
+    res
+}
+
+/// Read encoded ranges from the start of a buffer, and advance the buffer.
+///
+/// Fails if there are more than `max_ranges` ranges.
+pub fn read_chunk_ranges(data: &mut &[u8], max_ranges: usize) -> io::Result<ChunkRanges> {
+    let n = read_varint(data)?;
+    // the number of ranges is the number of boundaries divided by 2, rounded up
+    if n.div_ceil(2) > max_ranges as u64 {
+        return Err(invalid_data("too many ranges"));
+    }
+    // every boundary takes at least one byte, so don't trust larger counts
+    let mut boundaries = SmallVec::with_capacity(n.min(data.len() as u64) as usize);
+    let mut current = 0u64;
+    for i in 0..n {
+        let delta = read_varint(data)?;
+        if i > 0 && delta == 0 {
+            return Err(invalid_data("ranges not sorted or empty"));
+        }
+        current = current
+            .checked_add(delta)
+            .ok_or_else(|| invalid_data("boundary overflow"))?;
+        boundaries.push(ChunkNum(current));
+    }
+    // boundaries are strictly increasing, so this can not fail
+    Ok(ChunkRanges::new(boundaries).expect("boundaries are sorted"))
+}
+
+/// Decode ranges that were encoded with [encode_chunk_ranges].
+///
+/// Fails if there are more than `max_ranges` ranges, or if there is data
+/// after the encoded ranges.
+pub fn decode_chunk_ranges(mut data: &[u8], max_ranges: usize) -> io::Result<ChunkRanges> {
+    let res = read_chunk_ranges(&mut data, max_ranges)?;
+    if !data.is_empty() {
+        return Err(invalid_data("trailing data after ranges"));
+    }
+    Ok(res)
+}
@@ -4,6 +4,7 @@
 //! [positioned-io](https://crates.io/crates/positioned-io).
 use std::{
     io::{self, Read, Seek, Write},
+    ops::Range,
     result,
 };
 
@@ -11,20 +12,26 @@ use crate::{
     blake3,
     io::{
         error::EncodeError,
-        outboard::{parse_hash_pair, PostOrderOutboard, PreOrderOutboard},
+        hasher::PostOrderHasher,
+        outboard::{
+            parse_hash_pair, Header, HeaderOutboard, OutboardOrder, PostOrderOutboard,
+            PreOrderOutboard, HEADER_SIZE,
+        },
         Leaf, Parent,
     },
     iter::BaoChunk,
     rec::{encode_selected_rec, truncate_ranges},
-    BaoTree, BlockSize, ChunkRangesRef, TreeNode,
+    BaoTree, BlockSize, ChunkNum, ChunkRanges, ChunkRangesRef, HashMode, TreeNode,
 };
-use blake3::guts::parent_cv;
 use bytes::BytesMut;
 pub use positioned_io::{ReadAt, Size, WriteAt};
 use smallvec::SmallVec;
 
-use super::{combine_hash_pair, BaoContentItem, DecodeError};
-use crate::{hash_subtree, iter::ResponseIterRef};
+use super::{
+    available_ranges, check_stack_depth, combine_hash_pair, hash_pairs_group, skip_len,
+    skip_to_offset, BaoContentItem, DecodeError, DecoderState,
+};
+use crate::iter::ResponseIterRef;
 
 /// A binary merkle tree for blake3 hashes of a blob.
 ///
@@ -93,7 +100,8 @@ pub trait CreateOutboard {
     /// tree and only init the data and set the root hash.
     ///
     /// So this can be used to initialize an outboard that does not have a default,
-    /// such as a file based one.
+    /// such as a file based one. It can also be used to create an outboard with a
+    /// non default [HashMode], by setting the tree before.
     ///
     /// It will only include data up the the current tree size.
     fn init_from(&mut self, data: impl Read) -> io::Result<()>;
@@ -133,6 +141,35 @@ impl<O: Outboard> Outboard for &mut O {
     }
 }
 
+/// A [WriteAt] target that can be truncated or extended, like a file.
+///
+/// This is the sync equivalent of `AsyncSliceWriter::set_len` in iroh-io.
+pub trait SetLen {
+    /// Truncate or extend the target to the given length.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl SetLen for std::fs::File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        std::fs::File::set_len(self, len)
+    }
+}
+
+impl SetLen for Vec<u8> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let len = usize::try_from(len)
+            .map_err(|_| io::Error::new(io::ErrorKind::OutOfMemory, "length too large"))?;
+        self.resize(len, 0);
+        Ok(())
+    }
+}
+
+impl<T: SetLen> SetLen for &mut T {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        (**self).set_len(len)
+    }
+}
+
 impl<R: ReadAt> Outboard for PreOrderOutboard<R> {
     fn root(&self) -> blake3::Hash {
         self.root
@@ -257,10 +294,89 @@ impl<R: ReadAt> Outboard for PostOrderOutboard<R> {
     }
 }
 
+impl<R: ReadAt + Size> PostOrderOutboard<R> {
+    /// Open a post order outboard that is followed by the size of the data as
+    /// an 8 byte little endian integer, like the `.obao` files written by
+    /// [outboard_post_order_with_suffix].
+    ///
+    /// The tree is created with the given block size and hash mode.
+    ///
+    /// Fails if the length of the outboard does not match the size.
+    pub fn open_with_suffix(
+        data: R,
+        block_size: BlockSize,
+        mode: HashMode,
+        root: blake3::Hash,
+    ) -> io::Result<Self> {
+        let tree = read_size_suffix(&data, block_size, mode)?;
+        Ok(Self { root, tree, data })
+    }
+
+    /// Open a post order outboard with a size suffix, and compute the root hash.
+    ///
+    /// The root hash is computed from the hash pair of the root node, so the
+    /// outboard is not validated. If the data is just a single chunk group,
+    /// there is no hash pair, so the root hash is computed from `blob`.
+    pub fn open_with_suffix_compute_root(
+        data: R,
+        block_size: BlockSize,
+        mode: HashMode,
+        blob: impl ReadAt,
+    ) -> io::Result<Self> {
+        let tree = read_size_suffix(&data, block_size, mode)?;
+        let mut res = Self {
+            root: blake3::Hash::from([0; 32]),
+            tree,
+            data,
+        };
+        res.root = if tree.blocks() > 1 {
+            let (l_hash, r_hash) = res
+                .load(tree.root())?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "root node not found"))?;
+            tree.mode.parent_cv(&l_hash, &r_hash, true)
+        } else {
+            let mut buf = vec![0u8; tree.size as usize];
+            blob.read_exact_at(0, &mut buf)?;
+            tree.mode.hash_subtree(0, &buf, true)
+        };
+        Ok(res)
+    }
+}
+
+/// Read the 8 byte little endian size suffix of a post order outboard, and
+/// check that the outboard has the right length for it.
+fn read_size_suffix(
+    data: impl ReadAt + Size,
+    block_size: BlockSize,
+    mode: HashMode,
+) -> io::Result<BaoTree> {
+    let len = data
+        .size()?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "outboard has no size"))?;
+    let Some(offset) = len.checked_sub(8) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "outboard is too short for the size suffix",
+        ));
+    };
+    let mut size = [0u8; 8];
+    data.read_exact_at(offset, &mut size)?;
+    let tree = BaoTree::new_with_mode(u64::from_le_bytes(size), block_size, mode);
+    if tree.outboard_size() != offset {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "outboard size does not match the size suffix",
+        ));
+    }
+    Ok(tree)
+}
+
 /// Iterator that can be used to decode a response to a range request
 #[derive(Debug)]
 pub struct DecodeResponseIter<'a, R> {
     inner: ResponseIterRef<'a>,
+    ranges: &'a ChunkRangesRef,
+    encoded_offset: u64,
     stack: SmallVec<[blake3::Hash; 10]>,
     encoded: R,
     buf: BytesMut,
@@ -293,11 +409,75 @@ impl<'a, R: Read> DecodeResponseIter<'a, R> {
         Self {
             stack,
             inner: ResponseIterRef::new(tree, ranges),
+            ranges,
+            encoded_offset: 0,
             encoded,
             buf,
         }
     }
 
+    /// Create a new iterator to decode a response that starts with the size of
+    /// the blob, as produced by [encode_ranges_with_size_proof].
+    ///
+    /// This reads the size prefix and verifies the size proof that follows it,
+    /// so the tree is known to be correct before any data is yielded. If the
+    /// proof does not match the claimed size, this fails with
+    /// [DecodeError::SizeMismatch].
+    ///
+    /// The tree is created with the given block size and hash mode.
+    ///
+    /// This is a different wire format than a response that consists of just
+    /// the size and the encoded ranges, so it can only decode responses that
+    /// contain the size proof. Both sides have to agree on the format.
+    pub fn new_with_size_proof(
+        root: blake3::Hash,
+        block_size: BlockSize,
+        mode: HashMode,
+        mut encoded: R,
+        ranges: &'a ChunkRangesRef,
+    ) -> result::Result<Self, DecodeError> {
+        let mut prefix = [0u8; 8];
+        encoded.read_exact(&mut prefix)?;
+        let size = u64::from_le_bytes(prefix);
+        let tree = verify_size_proof(&mut encoded, root, size, block_size, mode)?;
+        Ok(Self::new(root, tree, encoded, ranges))
+    }
+
+    /// Resume decoding from a state that was obtained using [Self::state].
+    ///
+    /// The reader must provide the encoded stream starting at
+    /// [DecoderState::encoded_offset], e.g. as produced by [encode_ranges_validated_from].
+    ///
+    /// Fails if the offset is not at a chunk boundary of the response, or if
+    /// the stack of the state does not have the depth needed at that offset.
+    pub fn resume(state: &'a DecoderState, encoded: R) -> io::Result<Self> {
+        let tree = state.tree;
+        let ranges = truncate_ranges(&state.ranges, tree.size());
+        let mut inner = ResponseIterRef::new(tree, ranges);
+        let depth = skip_to_offset(&mut inner, state.encoded_offset)?;
+        check_stack_depth(&state.stack, depth)?;
+        Ok(Self {
+            stack: state.stack.iter().copied().collect(),
+            inner,
+            ranges,
+            encoded_offset: state.encoded_offset,
+            encoded,
+            buf: BytesMut::with_capacity(tree.block_size().bytes()),
+        })
+    }
+
+    /// The current state of the decoder, which can be persisted to resume later.
+    ///
+    /// See [DecoderState] for details.
+    pub fn state(&self) -> DecoderState {
+        DecoderState {
+            tree: self.tree(),
+            ranges: ChunkRanges::new_unchecked(self.ranges.boundaries().into()),
+            encoded_offset: self.encoded_offset,
+            stack: self.stack.to_vec(),
+        }
+    }
+
     /// Get a reference to the buffer used for decoding.
     pub fn buffer(&self) -> &[u8] {
         &self.buf
@@ -322,7 +502,7 @@ impl<'a, R: Read> DecodeResponseIter<'a, R> {
                 let pair @ (l_hash, r_hash) = read_parent(&mut self.encoded)
                     .map_err(|e| DecodeError::maybe_parent_not_found(e, node))?;
                 let parent_hash = self.stack.pop().unwrap();
-                let actual = parent_cv(&l_hash, &r_hash, is_root);
+                let actual = self.tree().mode.parent_cv(&l_hash, &r_hash, is_root);
                 if parent_hash != actual {
                     return Err(DecodeError::ParentHashMismatch(node));
                 }
@@ -332,6 +512,7 @@ impl<'a, R: Read> DecodeResponseIter<'a, R> {
                 if left {
                     self.stack.push(l_hash);
                 }
+                self.encoded_offset += 64;
                 Ok(Some(Parent { node, pair }.into()))
             }
             Some(BaoChunk::Leaf {
@@ -344,11 +525,15 @@ impl<'a, R: Read> DecodeResponseIter<'a, R> {
                 self.encoded
                     .read_exact(&mut self.buf)
                     .map_err(|e| DecodeError::maybe_leaf_not_found(e, start_chunk))?;
-                let actual = hash_subtree(start_chunk.0, &self.buf, is_root);
+                let actual = self
+                    .tree()
+                    .mode
+                    .hash_subtree(start_chunk.0, &self.buf, is_root);
                 let leaf_hash = self.stack.pop().unwrap();
                 if leaf_hash != actual {
                     return Err(DecodeError::LeafHashMismatch(start_chunk));
                 }
+                self.encoded_offset += size as u64;
                 Ok(Some(
                     Leaf {
                         offset: start_chunk.to_bytes(),
@@ -377,30 +562,84 @@ impl<'a, R: Read> Iterator for DecodeResponseIter<'a, R> {
 /// It is possible to encode ranges from a partial file and outboard.
 /// This will either succeed if the requested ranges are all present, or fail
 /// as soon as a range is missing.
+/// To encode just the ranges that are present, use [encode_ranges_available].
 pub fn encode_ranges<D: ReadAt + Size, O: Outboard, W: Write>(
     data: D,
     outboard: O,
     ranges: &ChunkRangesRef,
     encoded: W,
+) -> result::Result<(), EncodeError> {
+    encode_ranges_from(data, outboard, ranges, 0, encoded)
+}
+
+/// Encode ranges relevant to a query, starting at a byte offset in the encoded stream
+///
+/// The output is the output of [encode_ranges] without the first `offset` bytes.
+/// This can be used to resume an interrupted transfer. Data before the offset
+/// is not read unless it is needed to encode a partially requested chunk group.
+pub fn encode_ranges_from<D: ReadAt + Size, O: Outboard, W: Write>(
+    data: D,
+    outboard: O,
+    ranges: &ChunkRangesRef,
+    offset: u64,
+    encoded: W,
 ) -> result::Result<(), EncodeError> {
     let data = data;
     let mut encoded = encoded;
     let tree = outboard.tree();
     let mut buffer = vec![0u8; tree.chunk_group_bytes()];
+    let mut out_buf = Vec::new();
+    // current offset in the encoded stream
+    let mut current = 0u64;
+    // canonicalize ranges
+    let ranges = truncate_ranges(ranges, tree.size());
     for item in tree.ranges_pre_order_chunks_iter_ref(ranges, 0) {
         match item {
             BaoChunk::Parent { node, .. } => {
-                let (l_hash, r_hash) = outboard.load(node)?.unwrap();
+                if current + 64 <= offset {
+                    current += 64;
+                    continue;
+                }
+                let (l_hash, r_hash) = outboard
+                    .load(node)?
+                    .ok_or(EncodeError::ParentNotFound(node))?;
                 let pair = combine_hash_pair(&l_hash, &r_hash);
-                encoded.write_all(&pair)?;
+                encoded.write_all(&pair[skip_len(current, 64, offset)..])?;
+                current += 64;
             }
             BaoChunk::Leaf {
-                start_chunk, size, ..
+                start_chunk,
+                size,
+                is_root,
+                ranges,
             } => {
+                if ranges.is_all() && current + size as u64 <= offset {
+                    current += size as u64;
+                    continue;
+                }
                 let start = start_chunk.to_bytes();
                 let buf = &mut buffer[..size];
                 data.read_exact_at(start, buf)?;
-                encoded.write_all(buf)?;
+                let to_write = if !ranges.is_all() {
+                    // we need to encode just a part of the data, including the
+                    // hashes below the chunk group level, so we have to hash.
+                    out_buf.clear();
+                    encode_selected_rec(
+                        tree.mode,
+                        start_chunk,
+                        buf,
+                        is_root,
+                        ranges,
+                        tree.block_size.to_u32(),
+                        true,
+                        &mut out_buf,
+                    );
+                    &out_buf[..]
+                } else {
+                    &*buf
+                };
+                encoded.write_all(&to_write[skip_len(current, to_write.len(), offset)..])?;
+                current += to_write.len() as u64;
             }
         }
     }
@@ -414,11 +653,54 @@ pub fn encode_ranges<D: ReadAt + Size, O: Outboard, W: Write>(
 /// It is possible to encode ranges from a partial file and outboard.
 /// This will either succeed if the requested ranges are all present, or fail
 /// as soon as a range is missing.
+/// To encode just the ranges that are present, use [encode_ranges_available].
 pub fn encode_ranges_validated<D: ReadAt + Size, O: Outboard, W: Write>(
     data: D,
     outboard: O,
     ranges: &ChunkRangesRef,
     encoded: W,
+) -> result::Result<(), EncodeError> {
+    encode_ranges_validated_from(data, outboard, ranges, 0, encoded)
+}
+
+/// Encode the part of a query that is available from a partial blob
+///
+/// `available` are the chunks of the data that are present and valid, e.g.
+/// [PartialBlob::available](crate::io::partial::PartialBlob::available).
+/// Only the part of `ranges` that is covered by complete chunk groups in
+/// `available` is encoded, using [encode_ranges_validated]. Returns the ranges
+/// that were encoded. The receiver must decode the response using these ranges.
+///
+/// If the outboard is missing hash pairs for the available data, this fails with
+/// [EncodeError::ParentNotFound].
+pub fn encode_ranges_available<D: ReadAt + Size, O: Outboard, W: Write>(
+    data: D,
+    outboard: O,
+    ranges: &ChunkRangesRef,
+    available: &ChunkRangesRef,
+    encoded: W,
+) -> result::Result<ChunkRanges, EncodeError> {
+    let ranges = available_ranges(&outboard.tree(), ranges, available);
+    if !ranges.is_empty() {
+        encode_ranges_validated(data, outboard, &ranges, encoded)?;
+    }
+    Ok(ranges)
+}
+
+/// Encode ranges relevant to a query, starting at a byte offset in the encoded stream
+///
+/// The output is the output of [encode_ranges_validated] without the first
+/// `offset` bytes. This can be used to resume an interrupted transfer, see
+/// [DecodeResponseIter::resume].
+///
+/// Parents before the offset are still loaded and validated, but full chunk
+/// groups before the offset are neither read nor validated.
+pub fn encode_ranges_validated_from<D: ReadAt + Size, O: Outboard, W: Write>(
+    data: D,
+    outboard: O,
+    ranges: &ChunkRangesRef,
+    offset: u64,
+    encoded: W,
 ) -> result::Result<(), EncodeError> {
     let mut stack = SmallVec::<[blake3::Hash; 10]>::new();
     stack.push(outboard.root());
@@ -427,6 +709,8 @@ pub fn encode_ranges_validated<D: ReadAt + Size, O: Outboard, W: Write>(
     let tree = outboard.tree();
     let mut buffer = vec![0u8; tree.chunk_group_bytes()];
     let mut out_buf = Vec::new();
+    // current offset in the encoded stream
+    let mut current = 0u64;
     // canonicalize ranges
     let ranges = truncate_ranges(ranges, tree.size());
     for item in tree.ranges_pre_order_chunks_iter_ref(ranges, 0) {
@@ -438,8 +722,10 @@ pub fn encode_ranges_validated<D: ReadAt + Size, O: Outboard, W: Write>(
                 node,
                 ..
             } => {
-                let (l_hash, r_hash) = outboard.load(node)?.unwrap();
-                let actual = parent_cv(&l_hash, &r_hash, is_root);
+                let (l_hash, r_hash) = outboard
+                    .load(node)?
+                    .ok_or(EncodeError::ParentNotFound(node))?;
+                let actual = tree.mode.parent_cv(&l_hash, &r_hash, is_root);
                 let expected = stack.pop().unwrap();
                 if actual != expected {
                     return Err(EncodeError::ParentHashMismatch(node));
@@ -451,7 +737,8 @@ pub fn encode_ranges_validated<D: ReadAt + Size, O: Outboard, W: Write>(
                     stack.push(l_hash);
                 }
                 let pair = combine_hash_pair(&l_hash, &r_hash);
-                encoded.write_all(&pair)?;
+                encoded.write_all(&pair[skip_len(current, 64, offset)..])?;
+                current += 64;
             }
             BaoChunk::Leaf {
                 start_chunk,
@@ -461,6 +748,10 @@ pub fn encode_ranges_validated<D: ReadAt + Size, O: Outboard, W: Write>(
                 ..
             } => {
                 let expected = stack.pop().unwrap();
+                if ranges.is_all() && current + size as u64 <= offset {
+                    current += size as u64;
+                    continue;
+                }
                 let start = start_chunk.to_bytes();
                 let buf = &mut buffer[..size];
                 data.read_exact_at(start, buf)?;
@@ -471,6 +762,7 @@ pub fn encode_ranges_validated<D: ReadAt + Size, O: Outboard, W: Write>(
                     // before writing to the output.
                     out_buf.clear();
                     let actual = encode_selected_rec(
+                        tree.mode,
                         start_chunk,
                         buf,
                         is_root,
@@ -481,14 +773,14 @@ pub fn encode_ranges_validated<D: ReadAt + Size, O: Outboard, W: Write>(
                     );
                     (actual, &out_buf[..])
                 } else {
-                    let actual = hash_subtree(start_chunk.0, buf, is_root);
-                    #[allow(clippy::redundant_slicing)]
-                    (actual, &buf[..])
+                    let actual = tree.mode.hash_subtree(start_chunk.0, buf, is_root);
+                    (actual, &*buf)
                 };
                 if actual != expected {
                     return Err(EncodeError::LeafHashMismatch(start_chunk));
                 }
-                encoded.write_all(to_write)?;
+                encoded.write_all(&to_write[skip_len(current, to_write.len(), offset)..])?;
+                current += to_write.len() as u64;
             }
         }
     }
@@ -524,6 +816,74 @@ where
     Ok(())
 }
 
+/// Encode a proof for the size of the blob from a reader and outboard to a writer.
+///
+/// The proof consists of the last chunk of the blob and the hash pairs on the
+/// path from the root to it. It is the same as encoding the ranges
+/// `ChunkNum(u64::MAX)..`, since any range past the end of the blob is
+/// treated as a request for the last chunk.
+///
+/// This function validates the data before writing.
+pub fn encode_size_proof<D: ReadAt + Size, O: Outboard, W: Write>(
+    data: D,
+    outboard: O,
+    encoded: W,
+) -> result::Result<(), EncodeError> {
+    let ranges = ChunkRanges::from(ChunkNum(u64::MAX)..);
+    encode_ranges_validated(data, outboard, &ranges, encoded)
+}
+
+/// Verify a size proof that was produced by [encode_size_proof].
+///
+/// The tree for the claimed size is created with the given block size and
+/// hash mode, which must match the ones used for encoding.
+///
+/// On success, returns the tree for the claimed size. If the last chunk does
+/// not match the claimed size, or the proof ends before the last chunk, e.g.
+/// because the claimed size is too large, this fails with
+/// [DecodeError::SizeMismatch].
+///
+/// If the hash pairs on the path to the last chunk do not match, this fails
+/// with [DecodeError::ParentHashMismatch]. This can be caused by a wrong claimed
+/// size as well as by a corrupted proof.
+pub fn verify_size_proof<R: Read>(
+    encoded: R,
+    root: blake3::Hash,
+    size: u64,
+    block_size: BlockSize,
+    mode: HashMode,
+) -> result::Result<BaoTree, DecodeError> {
+    let tree = BaoTree::new_with_mode(size, block_size, mode);
+    let ranges = ChunkRanges::from(ChunkNum(u64::MAX)..);
+    for item in DecodeResponseIter::new(root, tree, encoded, &ranges) {
+        item.map_err(|e| e.into_size_mismatch(&tree))?;
+    }
+    Ok(tree)
+}
+
+/// Encode ranges relevant to a query, preceded by the size of the blob and a
+/// size proof.
+///
+/// The size is written as a little endian u64, followed by the proof produced by
+/// [encode_size_proof] and the ranges as produced by [encode_ranges_validated].
+/// Use [DecodeResponseIter::new_with_size_proof] to decode the result.
+///
+/// This is a new framing that is not compatible with a response consisting of
+/// the size followed by the encoded ranges: the size proof is sent in
+/// addition to the ranges, even if the ranges already contain the last chunk.
+/// A receiver that expects the size to be followed directly by the ranges
+/// cannot decode it, and [DecodeResponseIter::new_with_size_proof] cannot decode such a response.
+pub fn encode_ranges_with_size_proof<D: ReadAt + Size, O: Outboard, W: Write>(
+    data: D,
+    outboard: O,
+    ranges: &ChunkRangesRef,
+    mut encoded: W,
+) -> result::Result<(), EncodeError> {
+    encoded.write_all(&outboard.tree().size.to_le_bytes())?;
+    encode_size_proof(&data, &outboard, &mut encoded)?;
+    encode_ranges_validated(data, outboard, ranges, encoded)
+}
+
 /// Compute the outboard for the given data.
 ///
 /// Unlike [outboard_post_order], this will work with any outboard
@@ -538,6 +898,177 @@ pub fn outboard(
     Ok(hash)
 }
 
+/// Compute the outboard for the given data, hashing on multiple threads.
+///
+/// The tree is split into subtrees of at least [PARALLEL_SUBTREE_CHUNKS] chunks,
+/// which are read and hashed concurrently on the rayon thread pool. The hash
+/// pairs are saved in post order, one batch of subtrees at a time, so memory
+/// usage is bounded by the number of threads times the subtree size.
+///
+/// The result is the same as for [outboard].
+#[cfg(feature = "rayon")]
+pub fn outboard_parallel(
+    data: impl ReadAt + Sync,
+    tree: BaoTree,
+    mut outboard: impl OutboardMut,
+) -> io::Result<blake3::Hash> {
+    use rayon::prelude::*;
+    // height of the subtrees in chunks, must be at least the block size
+    let height = tree
+        .block_size
+        .0
+        .max(PARALLEL_SUBTREE_CHUNKS.trailing_zeros() as u8);
+    let subtree_bytes = ChunkNum(1 << height).to_bytes();
+    if tree.size <= subtree_bytes {
+        // a single subtree, nothing to parallelize
+        let mut buffer = vec![0u8; tree.size as usize];
+        data.read_exact_at(0, &mut buffer)?;
+        return self::outboard(&buffer[..], tree, outboard);
+    }
+    let count = tree.size.div_ceil(subtree_bytes);
+    let batch_size = rayon::current_num_threads() as u64;
+    let mut hashes = Vec::with_capacity(count as usize);
+    for batch_start in (0..count).step_by(batch_size as usize) {
+        let batch_end = (batch_start + batch_size).min(count);
+        let results = (batch_start..batch_end)
+            .into_par_iter()
+            .map(|i| {
+                let start = i * subtree_bytes;
+                let end = (start + subtree_bytes).min(tree.size);
+                let mut buffer = vec![0u8; (end - start) as usize];
+                data.read_exact_at(start, &mut buffer)?;
+                let mut pairs = Vec::new();
+                let start_chunk = ChunkNum(i << height);
+                let hash = super::hash_pairs_rec(
+                    tree.mode,
+                    start_chunk,
+                    height,
+                    &buffer,
+                    tree.block_size,
+                    &mut pairs,
+                );
+                Ok((hash, pairs))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        // save in order
+        for (hash, pairs) in results {
+            for (node, pair) in pairs {
+                outboard.save(node, &pair)?;
+            }
+            hashes.push(hash);
+        }
+    }
+    // combine the subtree hashes
+    let mut pairs = Vec::new();
+    let top = height + (count - 1).ilog2() as u8 + 1;
+    let root = combine_subtrees_rec(
+        tree.mode,
+        &hashes,
+        ChunkNum(0),
+        top,
+        height,
+        true,
+        &mut pairs,
+    );
+    for (node, pair) in pairs {
+        outboard.save(node, &pair)?;
+    }
+    Ok(root)
+}
+
+/// Number of chunks in a subtree that is hashed on a single thread in
+/// [outboard_parallel], unless the block size is larger.
+#[cfg(feature = "rayon")]
+pub const PARALLEL_SUBTREE_CHUNKS: u64 = 1 << 12;
+
+/// Combine the hashes of consecutive subtrees of the given height into the
+/// hash of the subtree of height `top` starting at `start`.
+///
+/// The hash pairs of the nodes above the subtrees are added to `pairs` in post order.
+#[cfg(feature = "rayon")]
+fn combine_subtrees_rec(
+    mode: HashMode,
+    hashes: &[blake3::Hash],
+    start: ChunkNum,
+    top: u8,
+    height: u8,
+    is_root: bool,
+    pairs: &mut Vec<(TreeNode, (blake3::Hash, blake3::Hash))>,
+) -> blake3::Hash {
+    if top == height {
+        return hashes[0];
+    }
+    let half = 1usize << (top - height - 1);
+    if hashes.len() <= half {
+        // no data in the right half, so there is no node for this subtree
+        return combine_subtrees_rec(mode, hashes, start, top - 1, height, is_root, pairs);
+    }
+    let (left, right) = hashes.split_at(half);
+    let mid = ChunkNum(start.0 + (1 << (top - 1)));
+    let l_hash = combine_subtrees_rec(mode, left, start, top - 1, height, false, pairs);
+    let r_hash = combine_subtrees_rec(mode, right, mid, top - 1, height, false, pairs);
+    pairs.push((TreeNode(mid.0 - 1), (l_hash, r_hash)));
+    mode.parent_cv(&l_hash, &r_hash, is_root)
+}
+
+/// Open an outboard that starts with a [Header].
+///
+/// The order, tree and root hash are taken from the header. The hash mode is
+/// not fully stored in the header, so it has to be given. Fails with
+/// [io::ErrorKind::InvalidData] if the header is invalid, does not match the
+/// kind of hash mode, or the size of the data does not match the header.
+pub fn open_with_header<D: ReadAt + Size>(
+    data: D,
+    mode: HashMode,
+) -> io::Result<HeaderOutboard<D>> {
+    let mut buf = [0u8; HEADER_SIZE];
+    data.read_exact_at(0, &mut buf).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            Header::too_short()
+        } else {
+            e
+        }
+    })?;
+    let header = Header::from_bytes(&buf, mode)?;
+    let Some(len) = data.size()? else {
+        return Err(io::Error::other("unable to determine outboard size"));
+    };
+    header.check_len(len)?;
+    Ok(HeaderOutboard::new(header, data))
+}
+
+/// Compute an outboard with a [Header] for the given data, writing it to
+/// the given target.
+///
+/// The target is truncated or extended to the size of the outboard first, so
+/// existing data is removed. The returned outboard is ready to use.
+///
+/// The kind of hash mode is recorded in the header, but not the key.
+pub fn create_with_header<W: WriteAt + SetLen>(
+    data: impl Read,
+    size: u64,
+    block_size: BlockSize,
+    mode: HashMode,
+    order: OutboardOrder,
+    target: W,
+) -> io::Result<HeaderOutboard<W>> {
+    let tree = BaoTree::new_with_mode(size, block_size, mode);
+    let header = Header {
+        order,
+        tree,
+        root: blake3::Hash::from([0; 32]),
+    };
+    let mut res = HeaderOutboard::new(header, target);
+    res.inner_mut()
+        .set_len(HEADER_SIZE as u64 + tree.outboard_size())?;
+    let root = outboard(data, tree, &mut res)?;
+    res.set_root(root);
+    let header = res.header().to_bytes()?;
+    res.inner_mut().write_all_at(0, &header)?;
+    res.sync()?;
+    Ok(res)
+}
+
 /// Internal helper for [outboard_post_order]. This takes a buffer of the chunk group size.
 fn outboard_impl(
     tree: BaoTree,
@@ -554,7 +1085,7 @@ fn outboard_impl(
                 let right_hash = stack.pop().unwrap();
                 let left_hash = stack.pop().unwrap();
                 outboard.save(node, &(left_hash, right_hash))?;
-                let parent = parent_cv(&left_hash, &right_hash, is_root);
+                let parent = tree.mode.parent_cv(&left_hash, &right_hash, is_root);
                 stack.push(parent);
             }
             BaoChunk::Leaf {
@@ -565,7 +1096,7 @@ fn outboard_impl(
             } => {
                 let buf = &mut buffer[..size];
                 data.read_exact(buf)?;
-                let hash = hash_subtree(start_chunk.0, buf, is_root);
+                let hash = tree.mode.hash_subtree(start_chunk.0, buf, is_root);
                 stack.push(hash);
             }
         }
@@ -591,6 +1122,21 @@ pub fn outboard_post_order(
     Ok(hash)
 }
 
+/// Compute the post order outboard for the given data, followed by the size
+/// of the data as an 8 byte little endian integer.
+///
+/// This is the format of `.obao` files. They can be opened using
+/// [PostOrderOutboard::open_with_suffix].
+pub fn outboard_post_order_with_suffix(
+    data: impl Read,
+    tree: BaoTree,
+    mut outboard: impl Write,
+) -> io::Result<blake3::Hash> {
+    let hash = outboard_post_order(data, tree, &mut outboard)?;
+    outboard.write_all(&tree.size.to_le_bytes())?;
+    Ok(hash)
+}
+
 /// Internal helper for [outboard_post_order]. This takes a buffer of the chunk group size.
 fn outboard_post_order_impl(
     tree: BaoTree,
@@ -608,7 +1154,7 @@ fn outboard_post_order_impl(
                 let left_hash = stack.pop().unwrap();
                 outboard.write_all(left_hash.as_bytes())?;
                 outboard.write_all(right_hash.as_bytes())?;
-                let parent = parent_cv(&left_hash, &right_hash, is_root);
+                let parent = tree.mode.parent_cv(&left_hash, &right_hash, is_root);
                 stack.push(parent);
             }
             BaoChunk::Leaf {
@@ -619,7 +1165,7 @@ fn outboard_post_order_impl(
             } => {
                 let buf = &mut buffer[..size];
                 data.read_exact(buf)?;
-                let hash = hash_subtree(start_chunk.0, buf, is_root);
+                let hash = tree.mode.hash_subtree(start_chunk.0, buf, is_root);
                 stack.push(hash);
             }
         }
@@ -629,7 +1175,247 @@ fn outboard_post_order_impl(
     Ok(hash)
 }
 
-fn read_parent(mut from: impl Read) -> std::io::Result<(blake3::Hash, blake3::Hash)> {
+/// Incremental hasher for data of unknown size.
+///
+/// Data is added using the [Write] impl. The hash pairs of the post order
+/// outboard are written to the wrapped writer as soon as they are stable,
+/// so writes to the outboard are sequential. Only the pairs for the nodes on
+/// the right edge of the tree have to wait for [BaoHasher::finalize].
+///
+/// If writing to the outboard fails, the data that was passed to the failing
+/// call is not hashed, so the call can be retried. Hash pairs that were not
+/// written yet are kept and written first on the next call.
+///
+/// Memory usage is one chunk group plus a small stack of hashes.
+#[derive(Debug)]
+pub struct BaoHasher<W> {
+    inner: PostOrderHasher,
+    outboard: W,
+}
+
+impl<W: Write> BaoHasher<W> {
+    /// Create a new hasher that writes the outboard to `outboard`.
+    pub fn new(block_size: BlockSize, outboard: W) -> Self {
+        Self::new_with_mode(block_size, HashMode::DEFAULT, outboard)
+    }
+
+    /// Create a new hasher that uses the given hash mode.
+    pub fn new_with_mode(block_size: BlockSize, mode: HashMode, outboard: W) -> Self {
+        Self {
+            inner: PostOrderHasher::new(block_size, mode),
+            outboard,
+        }
+    }
+
+    /// Number of bytes that have been written so far.
+    pub fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    /// Finish hashing and write the remaining hash pairs.
+    ///
+    /// Returns the complete outboard, which contains the root hash and the tree.
+    pub fn finalize(mut self) -> io::Result<PostOrderOutboard<W>> {
+        let (root, tree) = self.inner.finalize();
+        self.write_pairs()?;
+        self.outboard.flush()?;
+        Ok(PostOrderOutboard {
+            root,
+            tree,
+            data: self.outboard,
+        })
+    }
+
+    /// Write the pending hash pairs.
+    ///
+    /// Pairs are only removed once they are written, so after an error the
+    /// remaining pairs are written again on the next call.
+    fn write_pairs(&mut self) -> io::Result<()> {
+        while !self.inner.pairs().is_empty() {
+            match self.outboard.write(self.inner.pairs()) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.inner.consume_pairs(n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for BaoHasher<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // write the pairs of previous calls before taking new data, so that an
+        // error does not consume any data
+        self.write_pairs()?;
+        self.inner.update(buf);
+        // the data has been consumed, so a failure to write the new pairs is
+        // reported by the next call
+        self.write_pairs().ok();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pairs()?;
+        self.outboard.flush()
+    }
+}
+
+/// A writer that computes the outboard while data is written to a data sink.
+///
+/// Data written using the [Write] impl is passed through to the wrapped data
+/// writer and hashed at the same time, so the data does not have to be read
+/// again to compute the outboard. The post order outboard is written to a
+/// separate writer as for [BaoHasher].
+#[derive(Debug)]
+pub struct OutboardingWriter<W, O> {
+    data: W,
+    hasher: BaoHasher<O>,
+}
+
+impl<W: Write, O: Write> OutboardingWriter<W, O> {
+    /// Create a new writer that writes data to `data` and the outboard to `outboard`.
+    pub fn new(data: W, block_size: BlockSize, outboard: O) -> Self {
+        Self::new_with_mode(data, block_size, HashMode::DEFAULT, outboard)
+    }
+
+    /// Create a new writer that uses the given hash mode.
+    pub fn new_with_mode(data: W, block_size: BlockSize, mode: HashMode, outboard: O) -> Self {
+        Self {
+            data,
+            hasher: BaoHasher::new_with_mode(block_size, mode, outboard),
+        }
+    }
+
+    /// Number of bytes that have been written so far.
+    pub fn size(&self) -> u64 {
+        self.hasher.size()
+    }
+
+    /// Finish writing, and flush both the data and the outboard.
+    ///
+    /// Returns the data writer and the complete outboard, which contains the
+    /// root hash and the tree.
+    pub fn finalize(mut self) -> io::Result<(W, PostOrderOutboard<O>)> {
+        self.data.flush()?;
+        let outboard = self.hasher.finalize()?;
+        Ok((self.data, outboard))
+    }
+}
+
+impl<W: Write, O: Write> Write for OutboardingWriter<W, O> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // write pending pairs first, so hashing the data below can not fail
+        self.hasher.write_pairs()?;
+        // only hash what was actually written
+        let n = self.data.write(buf)?;
+        self.hasher.write_all(&buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.data.flush()?;
+        self.hasher.flush()
+    }
+}
+
+/// Grow a post order outboard to cover data that has been appended to the blob.
+///
+/// `data` is the entire data after the append, and `new_size` is its new size.
+/// Nodes that were already complete at the old size are
+/// [stable](crate::PostOrderOffset::Stable), so their hash pairs are loaded
+/// from the outboard instead of being recomputed. Only the data from the last
+/// incomplete leaf of the old tree onwards is read and hashed, and only the
+/// unstable nodes are rewritten.
+///
+/// On success, the tree and root hash of the outboard are updated and the new
+/// root hash is returned. On failure the outboard is left in an unspecified state.
+pub fn append_post_order<D, W>(
+    outboard: &mut PostOrderOutboard<W>,
+    data: D,
+    new_size: u64,
+) -> io::Result<blake3::Hash>
+where
+    D: ReadAt,
+    W: ReadAt + WriteAt,
+{
+    let old_size = outboard.tree.size;
+    if new_size < old_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "new size must not be smaller than the current size",
+        ));
+    }
+    // stable nodes have the same offset in the old and the new tree,
+    // so we can switch to the new tree right away
+    let tree = BaoTree::new_with_mode(new_size, outboard.tree.block_size, outboard.tree.mode);
+    outboard.tree = tree;
+    let (shifted_root, shifted_filled_size) = tree.shifted();
+    let mut appender = PostOrderAppender {
+        tree,
+        old_size,
+        shifted_filled_size,
+        outboard,
+        data,
+        buffer: vec![0u8; tree.chunk_group_bytes() * 2],
+    };
+    let root = appender.append_rec(shifted_root, true)?;
+    outboard.root = root;
+    outboard.sync()?;
+    Ok(root)
+}
+
+struct PostOrderAppender<'a, D, W> {
+    tree: BaoTree,
+    old_size: u64,
+    shifted_filled_size: TreeNode,
+    outboard: &'a mut PostOrderOutboard<W>,
+    data: D,
+    buffer: Vec<u8>,
+}
+
+impl<'a, D: ReadAt, W: ReadAt + WriteAt> PostOrderAppender<'a, D, W> {
+    fn append_rec(&mut self, shifted: TreeNode, is_root: bool) -> io::Result<blake3::Hash> {
+        let node = shifted.subtract_block_size(self.tree.block_size.0);
+        if node.byte_range().end <= self.old_size {
+            // the node was already complete before the append, so it is stable
+            let (l_hash, r_hash) = self.outboard.load(node)?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "stable node not found")
+            })?;
+            return Ok(self.tree.mode.parent_cv(&l_hash, &r_hash, is_root));
+        }
+        if shifted.is_leaf() {
+            let (l, m, r) = self.tree.leaf_byte_ranges3(node);
+            let start_chunk = ChunkNum::full_chunks(l);
+            if m == r {
+                // half full leaf, this is not stored in the outboard
+                let buf = &mut self.buffer[..(m - l) as usize];
+                self.data.read_exact_at(l, buf)?;
+                return Ok(self.tree.mode.hash_subtree(start_chunk.0, buf, is_root));
+            }
+            let buf = &mut self.buffer[..(r - l) as usize];
+            self.data.read_exact_at(l, buf)?;
+            let (lb, rb) = buf.split_at((m - l) as usize);
+            let l_hash = self.tree.mode.hash_subtree(start_chunk.0, lb, false);
+            let r_hash = self
+                .tree
+                .mode
+                .hash_subtree(ChunkNum::full_chunks(m).0, rb, false);
+            self.outboard.save(node, &(l_hash, r_hash))?;
+            Ok(self.tree.mode.parent_cv(&l_hash, &r_hash, is_root))
+        } else {
+            // recurse (we are in the domain of the shifted tree)
+            let left = shifted.left_child().unwrap();
+            let l_hash = self.append_rec(left, false)?;
+            let right = shifted.right_descendant(self.shifted_filled_size).unwrap();
+            let r_hash = self.append_rec(right, false)?;
+            self.outboard.save(node, &(l_hash, r_hash))?;
+            Ok(self.tree.mode.parent_cv(&l_hash, &r_hash, is_root))
+        }
+    }
+}
+
+pub(crate) fn read_parent(mut from: impl Read) -> std::io::Result<(blake3::Hash, blake3::Hash)> {
     let mut buf = [0; 64];
     from.read_exact(&mut buf)?;
     let l_hash = blake3::Hash::from(<[u8; 32]>::try_from(&buf[..32]).unwrap());
@@ -651,19 +1437,287 @@ pub fn copy(from: impl Outboard, mut to: impl OutboardMut) -> io::Result<()> {
     Ok(())
 }
 
+/// Convert an outboard to a different block size.
+///
+/// The root hash does not depend on the block size, so `to` must be set up
+/// with the same root hash and size as `from`, but the new block size.
+///
+/// When increasing the block size, the hash pairs below the new chunk group
+/// size are just dropped, and `data` is not used. When decreasing the block
+/// size, the missing hash pairs are computed from `data`, one chunk group of
+/// the old tree at a time. Each chunk group is checked against the hash in the
+/// old outboard, and the conversion fails with [io::ErrorKind::InvalidData] if
+/// the data does not match.
+pub fn convert_block_size(
+    from: impl Outboard,
+    data: impl ReadAt,
+    mut to: impl OutboardMut,
+    block_size: BlockSize,
+) -> io::Result<()> {
+    let tree = from.tree();
+    let new_tree = BaoTree::new_with_mode(tree.size, block_size, tree.mode);
+    for node in new_tree.pre_order_nodes_iter() {
+        if node.level() < tree.block_size.0 as u32 || !new_tree.is_relevant_for_outboard(node) {
+            continue;
+        }
+        let Some(hash_pair) = from.load(node)? else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("hash pair not found (level {})", node.level()),
+            ));
+        };
+        to.save(node, &hash_pair)?;
+    }
+    if block_size < tree.block_size {
+        let mut buffer = vec![0u8; tree.chunk_group_bytes()];
+        let mut pairs = Vec::new();
+        // the hashes of the old tree, to check the chunk groups against
+        let mut stack = vec![from.root()];
+        for item in tree.ranges_pre_order_chunks_iter_ref(&ChunkRanges::all(), 0) {
+            match item {
+                BaoChunk::Parent {
+                    node,
+                    is_root,
+                    left,
+                    right,
+                    ..
+                } => {
+                    let Some((l_hash, r_hash)) = from.load(node)? else {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("hash pair not found (level {})", node.level()),
+                        ));
+                    };
+                    let expected = stack.pop().unwrap();
+                    if tree.mode.parent_cv(&l_hash, &r_hash, is_root) != expected {
+                        return Err(EncodeError::ParentHashMismatch(node).into());
+                    }
+                    if right {
+                        stack.push(r_hash);
+                    }
+                    if left {
+                        stack.push(l_hash);
+                    }
+                }
+                BaoChunk::Leaf {
+                    start_chunk,
+                    size,
+                    is_root,
+                    ..
+                } => {
+                    let buf = &mut buffer[..size];
+                    data.read_exact_at(start_chunk.to_bytes(), buf)?;
+                    pairs.clear();
+                    let height = tree.block_size.0;
+                    let hash = hash_pairs_group(
+                        tree.mode,
+                        start_chunk,
+                        height,
+                        buf,
+                        block_size,
+                        is_root,
+                        &mut pairs,
+                    );
+                    // don't save pairs that are inconsistent with the old tree
+                    if hash != stack.pop().unwrap() {
+                        return Err(EncodeError::LeafHashMismatch(start_chunk).into());
+                    }
+                    for (node, hash_pair) in &pairs {
+                        to.save(*node, hash_pair)?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Compute the chunk ranges in which the data described by `b` differs from
+/// the data described by `a`.
+///
+/// Both outboards must have the same block size. The trees are walked top-down,
+/// and subtrees that cover the same bytes and have the same hash in both
+/// outboards are skipped, so the cost is proportional to the number of changes
+/// rather than to the size of the data.
+///
+/// The result is relative to `b` and has chunk group granularity, so it can be
+/// used directly to request the changed data of `b`. Everything in `b` beyond
+/// the size of `a` is considered changed. If `a` consists of just a single
+/// chunk group, only its root hash is known, so that group is considered
+/// changed unless the sizes are the same.
+///
+/// Nodes that can not be loaded from either outboard are also considered changed.
+pub fn diff(a: impl Outboard, b: impl Outboard) -> io::Result<ChunkRanges> {
+    let tree = b.tree();
+    if a.tree().block_size != tree.block_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "outboards must have the same block size",
+        ));
+    }
+    let root_a = (a.tree().size == tree.size).then(|| a.root());
+    let root_b = b.root();
+    let (shifted_root, shifted_filled_size) = tree.shifted();
+    let mut differ = Differ {
+        a,
+        b,
+        shifted_filled_size,
+        res: ChunkRanges::empty(),
+    };
+    differ.diff_rec(shifted_root, root_b, root_a)?;
+    Ok(differ.res)
+}
+
+struct Differ<A, B> {
+    a: A,
+    b: B,
+    shifted_filled_size: TreeNode,
+    res: ChunkRanges,
+}
+
+impl<A: Outboard, B: Outboard> Differ<A, B> {
+    fn add(&mut self, range: Range<u64>) {
+        self.res |=
+            ChunkRanges::from(ChunkNum::full_chunks(range.start)..ChunkNum::chunks(range.end));
+    }
+
+    /// Compare a subtree of `b` with the hash of the subtree covering the same
+    /// bytes in `a`, if there is one.
+    fn diff_rec(
+        &mut self,
+        shifted: TreeNode,
+        hash_b: blake3::Hash,
+        hash_a: Option<blake3::Hash>,
+    ) -> io::Result<()> {
+        if hash_a == Some(hash_b) {
+            // same data, no need to look at the children
+            return Ok(());
+        }
+        let tree = self.b.tree();
+        let node = shifted.subtract_block_size(tree.block_size.0);
+        let (l, m, r) = tree.leaf_byte_ranges3(node);
+        if !tree.is_relevant_for_outboard(node) {
+            // half full leaf, this is just a single chunk group
+            let group_bytes = tree.chunk_group_bytes() as u64;
+            let hash_a = match hash_a {
+                // if the group is complete in both, it has the same hash in a
+                // even if the tree of a has a different shape
+                None if r - l == group_bytes && r <= self.a.tree().size => {
+                    self.group_hash_a(ChunkNum::full_chunks(l))?
+                }
+                hash_a => hash_a,
+            };
+            if hash_a != Some(hash_b) {
+                self.add(l..r);
+            }
+            return Ok(());
+        }
+        let Some((l_hash, r_hash)) = self.b.load(node)? else {
+            self.add(l..r);
+            return Ok(());
+        };
+        let (l_hash_a, r_hash_a) = self.child_hashes_a(node)?;
+        if shifted.is_leaf() {
+            if l_hash_a != Some(l_hash) {
+                self.add(l..m);
+            }
+            if r_hash_a != Some(r_hash) {
+                self.add(m..r);
+            }
+        } else {
+            // recurse (we are in the domain of the shifted tree)
+            let left = shifted.left_child().unwrap();
+            self.diff_rec(left, l_hash, l_hash_a)?;
+            let right = shifted.right_descendant(self.shifted_filled_size).unwrap();
+            self.diff_rec(right, r_hash, r_hash_a)?;
+        }
+        Ok(())
+    }
+
+    /// Hashes of the children of `node` in `a`, for the children that cover
+    /// the same bytes as in `b`.
+    fn child_hashes_a(
+        &self,
+        node: TreeNode,
+    ) -> io::Result<(Option<blake3::Hash>, Option<blake3::Hash>)> {
+        let size_a = self.a.tree().size;
+        let size_b = self.b.tree().size;
+        let mid = node.mid().to_bytes();
+        let end = node.byte_range().end;
+        Ok(if mid < size_a {
+            // the node exists in a, and the left child is complete in both
+            let Some((l_hash, r_hash)) = self.a.load(node)? else {
+                return Ok((None, None));
+            };
+            let same_right = end.min(size_a) == end.min(size_b);
+            (Some(l_hash), same_right.then_some(r_hash))
+        } else if mid == size_a {
+            // the data of a ends exactly at the end of the left child
+            let l_hash = match node.left_child() {
+                Some(left) if self.a.tree().is_relevant_for_outboard(left) => self
+                    .a
+                    .load(left)?
+                    .map(|(l, r)| self.a.tree().mode.parent_cv(&l, &r, false)),
+                _ => self.group_hash_a(node.chunk_range().start)?,
+            };
+            (l_hash, None)
+        } else {
+            (None, None)
+        })
+    }
+
+    /// Find the hash of the chunk group starting at `start` in `a`.
+    ///
+    /// Hashes of chunk groups are only stored as part of the hash pair of their
+    /// parent, so we need to search from the root.
+    fn group_hash_a(&self, start: ChunkNum) -> io::Result<Option<blake3::Hash>> {
+        let tree = self.a.tree();
+        let (mut shifted, shifted_filled_size) = tree.shifted();
+        // hash of the current node, unless it is the root
+        let mut hash = None;
+        loop {
+            let node = shifted.subtract_block_size(tree.block_size.0);
+            if !tree.is_relevant_for_outboard(node) {
+                // half full leaf, the hash is stored in the parent
+                return Ok(hash);
+            }
+            let Some((l_hash, r_hash)) = self.a.load(node)? else {
+                return Ok(None);
+            };
+            let is_left = start < node.mid();
+            if shifted.is_leaf() {
+                return Ok(Some(if is_left { l_hash } else { r_hash }));
+            }
+            if is_left {
+                shifted = shifted.left_child().unwrap();
+                hash = Some(l_hash);
+            } else {
+                shifted = shifted.right_descendant(shifted_filled_size).unwrap();
+                hash = Some(r_hash);
+            }
+        }
+    }
+}
+
 #[cfg(feature = "validate")]
 mod validate {
-    use std::{io, ops::Range};
+    use std::{
+        io::{self, Read},
+        ops::Range,
+        result,
+    };
 
     use genawaiter::sync::{Co, Gen};
-    use positioned_io::ReadAt;
+    use positioned_io::{ReadAt, WriteAt};
 
     use crate::{
-        blake3, hash_subtree, io::LocalBoxFuture, rec::truncate_ranges, split, BaoTree, ChunkNum,
-        ChunkRangesRef, TreeNode,
+        blake3,
+        io::{round_up_to_chunk_groups, DecodeError, LocalBoxFuture},
+        rec::truncate_ranges,
+        split, BaoTree, ChunkNum, ChunkRanges, ChunkRangesRef, TreeNode,
     };
 
-    use super::Outboard;
+    use super::{decode_ranges, Outboard, OutboardMut};
 
     /// Given a data file and an outboard, compute all valid ranges.
     ///
@@ -709,7 +1763,7 @@ mod validate {
                 // special case for a tree that fits in one block / chunk group
                 let tmp = &mut buffer[..tree.size().try_into().unwrap()];
                 data.read_exact_at(0, tmp)?;
-                let actual = hash_subtree(0, tmp, true);
+                let actual = tree.mode.hash_subtree(0, tmp, true);
                 if actual == outboard.root() {
                     co.yield_(Ok(ChunkNum(0)..tree.chunks())).await;
                 }
@@ -741,7 +1795,10 @@ mod validate {
             let tmp = &mut self.buffer[..len];
             self.data.read_exact_at(range.start, tmp)?;
             // is_root is always false because the case of a single chunk group is handled before calling this function
-            let actual = hash_subtree(ChunkNum::full_chunks(range.start).0, tmp, is_root);
+            let actual =
+                self.tree
+                    .mode
+                    .hash_subtree(ChunkNum::full_chunks(range.start).0, tmp, is_root);
             if &actual == hash {
                 // yield the left range
                 self.co
@@ -775,7 +1832,7 @@ mod validate {
                     // outboard is incomplete, we can't validate
                     return Ok(());
                 };
-                let actual = blake3::guts::parent_cv(&l_hash, &r_hash, is_root);
+                let actual = self.tree.mode.parent_cv(&l_hash, &r_hash, is_root);
                 if &actual != parent_hash {
                     // hash mismatch, we can't validate
                     return Ok(());
@@ -877,7 +1934,7 @@ mod validate {
                     // outboard is incomplete, we can't validate
                     return Ok(());
                 };
-                let actual = blake3::guts::parent_cv(&l_hash, &r_hash, is_root);
+                let actual = self.tree.mode.parent_cv(&l_hash, &r_hash, is_root);
                 if &actual != parent_hash {
                     // hash mismatch, we can't validate
                     return Ok(());
@@ -901,6 +1958,59 @@ mod validate {
             })
         }
     }
+
+    /// Compute the ranges of a blob that are missing or corrupted locally.
+    ///
+    /// This validates the data and outboard within `ranges` using [valid_ranges],
+    /// and returns everything that is not valid. The result is truncated to the
+    /// size of the blob and rounded up to chunk groups, so it can be used
+    /// directly as the request for [repair].
+    pub fn invalid_ranges<O, D>(
+        outboard: O,
+        data: D,
+        ranges: &ChunkRangesRef,
+    ) -> io::Result<ChunkRanges>
+    where
+        O: Outboard,
+        D: ReadAt,
+    {
+        let tree = outboard.tree();
+        let ranges = truncate_ranges(ranges, tree.size());
+        let wanted =
+            round_up_to_chunk_groups(ranges, tree.block_size) & ChunkRanges::from(..tree.chunks());
+        let mut valid = ChunkRanges::empty();
+        for range in valid_ranges(outboard, data, &wanted) {
+            valid |= ChunkRanges::from(range?);
+        }
+        Ok(&wanted - &valid)
+    }
+
+    /// Repair a blob using a response to a request for `ranges`.
+    ///
+    /// `ranges` is typically computed using [invalid_ranges]. The response is
+    /// verified and written to the data and outboard using [decode_ranges].
+    /// Then the data is validated again, and the chunk ranges within `ranges`
+    /// that are now valid are returned.
+    pub fn repair<R, O, D>(
+        encoded: R,
+        ranges: &ChunkRangesRef,
+        mut data: D,
+        mut outboard: O,
+    ) -> result::Result<ChunkRanges, DecodeError>
+    where
+        R: Read,
+        O: OutboardMut + Outboard,
+        D: ReadAt + WriteAt,
+    {
+        if !ranges.is_empty() {
+            decode_ranges(encoded, ranges, &mut data, &mut outboard)?;
+        }
+        let mut valid = ChunkRanges::empty();
+        for range in valid_ranges(&outboard, &data, ranges) {
+            valid |= ChunkRanges::from(range?);
+        }
+        Ok(valid)
+    }
 }
 #[cfg(feature = "validate")]
-pub use validate::{valid_outboard_ranges, valid_ranges};
+pub use validate::{invalid_ranges, repair, valid_outboard_ranges, valid_ranges};
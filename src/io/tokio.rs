@@ -0,0 +1,277 @@
+//! Async io for tokio io types
+//!
+//! The [fsm] module works with the traits from
+//! [iroh-io](https://crates.io/crates/iroh-io). This module contains adapters
+//! that implement these traits for [tokio::io::AsyncRead] and
+//! [tokio::io::AsyncWrite], as well as encode, decode and outboard functions
+//! that take tokio io types directly.
+//!
+//! To use a seekable tokio io type such as [tokio::fs::File] as the storage of
+//! an outboard, use [pre_order_outboard] or [post_order_outboard].
+use std::{
+    cmp::Ordering,
+    io::{self, SeekFrom},
+};
+
+use bytes::Bytes;
+use iroh_io::{AsyncSliceReader, AsyncSliceWriter, AsyncStreamReader, AsyncStreamWriter};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    blake3,
+    io::{
+        fsm::{self, Outboard, OutboardMut},
+        outboard::{PostOrderOutboard, PreOrderOutboard},
+        DecodeError, EncodeError,
+    },
+    BaoTree, ChunkRanges, ChunkRangesRef,
+};
+
+/// A pre order outboard stored in a seekable tokio io type, by default a file
+pub type PreOrderFileOutboard<T = tokio::fs::File> = PreOrderOutboard<SliceAdapter<T>>;
+
+/// A post order outboard stored in a seekable tokio io type, by default a file
+pub type PostOrderFileOutboard<T = tokio::fs::File> = PostOrderOutboard<SliceAdapter<T>>;
+
+/// Create a pre order outboard that is stored in a seekable tokio io type.
+pub fn pre_order_outboard<T>(
+    root: blake3::Hash,
+    tree: BaoTree,
+    data: T,
+) -> PreOrderFileOutboard<T> {
+    PreOrderOutboard {
+        root,
+        tree,
+        data: SliceAdapter(data),
+    }
+}
+
+/// Create a post order outboard that is stored in a seekable tokio io type.
+pub fn post_order_outboard<T>(
+    root: blake3::Hash,
+    tree: BaoTree,
+    data: T,
+) -> PostOrderFileOutboard<T> {
+    PostOrderOutboard {
+        root,
+        tree,
+        data: SliceAdapter(data),
+    }
+}
+
+/// Adapter to use a [tokio::io::AsyncRead] as an [AsyncStreamReader]
+#[derive(Debug)]
+pub struct StreamReader<R>(pub R);
+
+impl<R> StreamReader<R> {
+    /// Get the underlying reader
+    pub fn into_inner(self) -> R {
+        self.0
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncStreamReader for StreamReader<R> {
+    async fn read_bytes(&mut self, len: usize) -> io::Result<Bytes> {
+        let mut buf = Vec::with_capacity(len);
+        (&mut self.0).take(len as u64).read_to_end(&mut buf).await?;
+        Ok(buf.into())
+    }
+
+    async fn read<const L: usize>(&mut self) -> io::Result<[u8; L]> {
+        let mut buf = [0u8; L];
+        self.0.read_exact(&mut buf).await?;
+        Ok(buf)
+    }
+}
+
+/// Adapter to use a [tokio::io::AsyncWrite] as an [AsyncStreamWriter]
+#[derive(Debug)]
+pub struct StreamWriter<W>(pub W);
+
+impl<W> StreamWriter<W> {
+    /// Get the underlying writer
+    pub fn into_inner(self) -> W {
+        self.0
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncStreamWriter for StreamWriter<W> {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.write_all(data).await
+    }
+
+    async fn write_bytes(&mut self, data: Bytes) -> io::Result<()> {
+        self.0.write_all(&data).await
+    }
+
+    async fn sync(&mut self) -> io::Result<()> {
+        self.0.flush().await
+    }
+}
+
+/// Adapter to use a seekable tokio reader as an [AsyncSliceReader], and a
+/// seekable tokio writer as an [AsyncSliceWriter].
+///
+/// Every read or write seeks to the given offset first.
+///
+/// Since [AsyncWrite] has no way to truncate, [AsyncSliceWriter::set_len] can
+/// only grow the underlying writer and fails with
+/// [io::ErrorKind::Unsupported] when asked to shrink it.
+/// [AsyncSliceWriter::sync] only flushes the writer, so to make sure the
+/// data of a [tokio::fs::File] is on disk, call
+/// [tokio::fs::File::sync_all] on the file you get back.
+#[derive(Debug)]
+pub struct SliceAdapter<T>(pub T);
+
+impl<T> SliceAdapter<T> {
+    /// Get the underlying file or reader
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: AsyncRead + AsyncSeek + Unpin> AsyncSliceReader for SliceAdapter<T> {
+    async fn read_at(&mut self, offset: u64, len: usize) -> io::Result<Bytes> {
+        self.0.seek(SeekFrom::Start(offset)).await?;
+        let mut buf = Vec::new();
+        (&mut self.0).take(len as u64).read_to_end(&mut buf).await?;
+        Ok(buf.into())
+    }
+
+    async fn size(&mut self) -> io::Result<u64> {
+        self.0.seek(SeekFrom::End(0)).await
+    }
+}
+
+impl<T: AsyncWrite + AsyncSeek + Unpin> AsyncSliceWriter for SliceAdapter<T> {
+    async fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.0.seek(SeekFrom::Start(offset)).await?;
+        self.0.write_all(data).await
+    }
+
+    async fn write_bytes_at(&mut self, offset: u64, data: Bytes) -> io::Result<()> {
+        self.write_at(offset, &data).await
+    }
+
+    async fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let size = self.0.seek(SeekFrom::End(0)).await?;
+        match len.cmp(&size) {
+            Ordering::Equal => Ok(()),
+            Ordering::Greater => {
+                // writing the last byte fills the gap with zeros
+                self.0.seek(SeekFrom::Start(len - 1)).await?;
+                self.0.write_all(&[0]).await
+            }
+            Ordering::Less => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "can not shrink an AsyncWrite",
+            )),
+        }
+    }
+
+    async fn sync(&mut self) -> io::Result<()> {
+        self.0.flush().await
+    }
+}
+
+/// Encode ranges relevant to a query from a tokio reader and outboard to a tokio writer
+///
+/// This will not validate on writing, so data corruption will be detected on
+/// reading. See [fsm::encode_ranges] for details.
+pub async fn encode_ranges<D, O, W>(
+    data: D,
+    outboard: O,
+    ranges: &ChunkRangesRef,
+    encoded: W,
+) -> Result<(), EncodeError>
+where
+    D: AsyncRead + AsyncSeek + Unpin,
+    O: Outboard,
+    W: AsyncWrite + Unpin,
+{
+    let mut encoded = StreamWriter(encoded);
+    fsm::encode_ranges(SliceAdapter(data), outboard, ranges, &mut encoded).await?;
+    encoded.sync().await?;
+    Ok(())
+}
+
+/// Encode ranges relevant to a query from a tokio reader and outboard to a tokio writer
+///
+/// This function validates the data before writing. See
+/// [fsm::encode_ranges_validated] for details.
+pub async fn encode_ranges_validated<D, O, W>(
+    data: D,
+    outboard: O,
+    ranges: &ChunkRangesRef,
+    encoded: W,
+) -> Result<(), EncodeError>
+where
+    D: AsyncRead + AsyncSeek + Unpin,
+    O: Outboard,
+    W: AsyncWrite + Unpin,
+{
+    let mut encoded = StreamWriter(encoded);
+    fsm::encode_ranges_validated(SliceAdapter(data), outboard, ranges, &mut encoded).await?;
+    encoded.sync().await?;
+    Ok(())
+}
+
+/// Decode a response from a tokio reader into a seekable tokio writer while
+/// updating an outboard.
+///
+/// Returns the target, see [SliceAdapter] for how it is synced.
+/// See [fsm::decode_ranges] for details.
+pub async fn decode_ranges<R, O, W>(
+    encoded: R,
+    ranges: ChunkRanges,
+    target: W,
+    outboard: O,
+) -> Result<W, DecodeError>
+where
+    O: OutboardMut + Outboard,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + AsyncSeek + Unpin,
+{
+    let mut target = SliceAdapter(target);
+    fsm::decode_ranges(StreamReader(encoded), ranges, &mut target, outboard).await?;
+    target.sync().await?;
+    Ok(target.into_inner())
+}
+
+/// Create a [fsm::ResponseDecoder] that reads a response from a tokio reader.
+///
+/// See [fsm::ResponseDecoder::new] for details.
+pub fn response_decoder<R: AsyncRead + Unpin>(
+    hash: blake3::Hash,
+    ranges: ChunkRanges,
+    tree: BaoTree,
+    encoded: R,
+) -> fsm::ResponseDecoder<StreamReader<R>> {
+    fsm::ResponseDecoder::new(hash, ranges, tree, StreamReader(encoded))
+}
+
+/// Compute the outboard for the data from a tokio reader.
+///
+/// See [fsm::outboard] for details.
+pub async fn outboard(
+    data: impl AsyncRead + Unpin,
+    tree: BaoTree,
+    outboard: impl OutboardMut,
+) -> io::Result<blake3::Hash> {
+    fsm::outboard(StreamReader(data), tree, outboard).await
+}
+
+/// Compute the post order outboard for the data from a tokio reader, writing
+/// it to a tokio writer.
+///
+/// See [fsm::outboard_post_order] for details.
+pub async fn outboard_post_order(
+    data: impl AsyncRead + Unpin,
+    tree: BaoTree,
+    outboard: impl AsyncWrite + Unpin,
+) -> io::Result<blake3::Hash> {
+    let mut outboard = StreamWriter(outboard);
+    let hash = fsm::outboard_post_order(StreamReader(data), tree, &mut outboard).await?;
+    outboard.sync().await?;
+    Ok(hash)
+}
@@ -0,0 +1,276 @@
+//! A random access reader that verifies data against an outboard
+//!
+//! [VerifiedReader] wraps local data and an outboard, and checks every chunk
+//! group that is touched by a read against the outboard before returning any
+//! bytes from it. Chunk groups that have been verified once are remembered, so
+//! repeated reads of the same region only pay for the verification once.
+use std::{io, sync::Mutex};
+
+use range_collections::range_set::RangeSetRange;
+
+use crate::{
+    blake3, io::error::EncodeError, iter::BaoChunk, BaoTree, ChunkNum, ChunkRanges, HashMode,
+    TreeNode,
+};
+
+/// A reader for local data that verifies all data against an outboard.
+///
+/// Reads go to the underlying data, but every chunk group that overlaps with
+/// the read is validated against the outboard before it is returned. If the
+/// data does not match, the read fails with an [io::ErrorKind::InvalidData]
+/// error, so corrupted data is never returned.
+///
+/// The ranges that have been verified are cached. The cache is not
+/// invalidated, so if the underlying data can change, create a new reader.
+/// The cache is only locked while it is accessed, so concurrent sync reads
+/// do not block each other's io.
+///
+/// Implements [positioned_io::ReadAt] for sync io, and [iroh_io::AsyncSliceReader]
+/// if the `tokio_fsm` feature is enabled.
+#[derive(Debug)]
+pub struct VerifiedReader<D, O> {
+    data: D,
+    outboard: O,
+    verified: Mutex<ChunkRanges>,
+}
+
+impl<D, O> VerifiedReader<D, O> {
+    /// Create a new verified reader from data and an outboard.
+    pub fn new(data: D, outboard: O) -> Self {
+        Self {
+            data,
+            outboard,
+            verified: Mutex::new(ChunkRanges::empty()),
+        }
+    }
+
+    /// The ranges that have been verified so far.
+    ///
+    /// This is always a union of chunk groups.
+    pub fn verified(&self) -> ChunkRanges {
+        self.verified.lock().unwrap().clone()
+    }
+
+    /// Return the underlying data and outboard.
+    pub fn into_inner(self) -> (D, O) {
+        (self.data, self.outboard)
+    }
+}
+
+/// Clamp a read to the size of the tree and compute the chunks it touches.
+///
+/// Returns `None` if the read is empty or starts after the end.
+fn read_ranges(tree: &BaoTree, pos: u64, len: usize) -> Option<(u64, ChunkRanges)> {
+    if pos >= tree.size || len == 0 {
+        return None;
+    }
+    let end = pos.saturating_add(len as u64).min(tree.size);
+    let ranges = ChunkRanges::from(ChunkNum::full_chunks(pos)..ChunkNum::chunks(end));
+    Some((end, ranges))
+}
+
+/// Copy the part of `data`, which starts at byte `start`, that overlaps with
+/// `pos..pos + buf.len()` into `buf`.
+fn copy_overlap(start: u64, data: &[u8], pos: u64, buf: &mut [u8]) {
+    let s = start.max(pos);
+    let e = (start + data.len() as u64).min(pos + buf.len() as u64);
+    if s < e {
+        buf[(s - pos) as usize..(e - pos) as usize]
+            .copy_from_slice(&data[(s - start) as usize..(e - start) as usize]);
+    }
+}
+
+/// The byte ranges of `ranges`, clamped to `pos..end`.
+fn byte_ranges(ranges: &ChunkRanges, pos: u64, end: u64) -> impl Iterator<Item = (u64, u64)> + '_ {
+    ranges.iter().filter_map(move |range| {
+        let (s, e) = match range {
+            RangeSetRange::Range(range) => (range.start.to_bytes(), range.end.to_bytes()),
+            RangeSetRange::RangeFrom(range) => (range.start.to_bytes(), end),
+        };
+        let (s, e) = (s.max(pos), e.min(end));
+        (s < e).then_some((s, e))
+    })
+}
+
+/// Validate a parent against the stack, and push the hashes of its children.
+fn validate_parent(
+    mode: HashMode,
+    stack: &mut Vec<blake3::Hash>,
+    node: TreeNode,
+    pair: Option<(blake3::Hash, blake3::Hash)>,
+    is_root: bool,
+    left: bool,
+    right: bool,
+) -> io::Result<()> {
+    let Some((l_hash, r_hash)) = pair else {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("parent not found (level {})", node.level()),
+        ));
+    };
+    let expected = stack.pop().unwrap();
+    if mode.parent_cv(&l_hash, &r_hash, is_root) != expected {
+        return Err(EncodeError::ParentHashMismatch(node).into());
+    }
+    if right {
+        stack.push(r_hash);
+    }
+    if left {
+        stack.push(l_hash);
+    }
+    Ok(())
+}
+
+/// Validate a chunk group against the stack.
+fn validate_leaf(
+    mode: HashMode,
+    stack: &mut Vec<blake3::Hash>,
+    start_chunk: ChunkNum,
+    data: &[u8],
+    size: usize,
+    is_root: bool,
+) -> io::Result<()> {
+    let expected = stack.pop().unwrap();
+    if data.len() != size || mode.hash_subtree(start_chunk.0, data, is_root) != expected {
+        return Err(EncodeError::LeafHashMismatch(start_chunk).into());
+    }
+    Ok(())
+}
+
+mod sync_impl {
+    use positioned_io::{ReadAt, Size};
+
+    use super::*;
+    use crate::io::sync::Outboard;
+
+    impl<D: ReadAt, O: Outboard> ReadAt for VerifiedReader<D, O> {
+        fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+            let tree = self.outboard.tree();
+            let Some((end, ranges)) = read_ranges(&tree, pos, buf.len()) else {
+                return Ok(0);
+            };
+            let len = (end - pos) as usize;
+            let buf = &mut buf[..len];
+            // only hold the lock while accessing the cache, not during io, so
+            // concurrent reads don't block each other. Two concurrent reads of
+            // the same unverified region will both verify it.
+            let (known, unknown) = {
+                let verified = self.verified.lock().unwrap();
+                (&ranges & &*verified, &ranges - &*verified)
+            };
+            // read the parts that have already been verified directly
+            for (s, e) in byte_ranges(&known, pos, end) {
+                self.data
+                    .read_exact_at(s, &mut buf[(s - pos) as usize..(e - pos) as usize])?;
+            }
+            // verify the rest, and copy it from the verified chunk groups
+            if unknown.is_empty() {
+                return Ok(len);
+            }
+            let mut stack = vec![self.outboard.root()];
+            let mut group = vec![0u8; tree.chunk_group_bytes()];
+            for item in tree.ranges_pre_order_chunks_iter_ref(&unknown, 0) {
+                match item {
+                    BaoChunk::Parent {
+                        node,
+                        is_root,
+                        left,
+                        right,
+                        ..
+                    } => {
+                        let pair = self.outboard.load(node)?;
+                        validate_parent(tree.mode, &mut stack, node, pair, is_root, left, right)?;
+                    }
+                    BaoChunk::Leaf {
+                        start_chunk,
+                        size,
+                        is_root,
+                        ..
+                    } => {
+                        let data = &mut group[..size];
+                        let start = start_chunk.to_bytes();
+                        self.data.read_exact_at(start, data)?;
+                        validate_leaf(tree.mode, &mut stack, start_chunk, data, size, is_root)?;
+                        copy_overlap(start, data, pos, buf);
+                        let end_chunk = start_chunk + tree.chunk_group_chunks();
+                        *self.verified.lock().unwrap() |= ChunkRanges::from(start_chunk..end_chunk);
+                    }
+                }
+            }
+            Ok(len)
+        }
+    }
+
+    impl<D, O: Outboard> Size for VerifiedReader<D, O> {
+        fn size(&self) -> io::Result<Option<u64>> {
+            Ok(Some(self.outboard.tree().size))
+        }
+    }
+}
+
+#[cfg(feature = "tokio_fsm")]
+mod fsm_impl {
+    use bytes::{Bytes, BytesMut};
+    use iroh_io::AsyncSliceReader;
+
+    use super::*;
+    use crate::io::fsm::Outboard;
+
+    impl<D: AsyncSliceReader, O: Outboard> AsyncSliceReader for VerifiedReader<D, O> {
+        async fn read_at(&mut self, pos: u64, len: usize) -> io::Result<Bytes> {
+            let tree = self.outboard.tree();
+            let Some((end, ranges)) = read_ranges(&tree, pos, len) else {
+                return Ok(Bytes::new());
+            };
+            let mut buf = BytesMut::zeroed((end - pos) as usize);
+            let verified = self.verified.get_mut().unwrap();
+            // read the parts that have already been verified directly
+            let known = &ranges & &*verified;
+            for (s, e) in byte_ranges(&known, pos, end) {
+                let data = self.data.read_at(s, (e - s) as usize).await?;
+                if data.len() != (e - s) as usize {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                copy_overlap(s, &data, pos, &mut buf);
+            }
+            // verify the rest, and copy it from the verified chunk groups
+            let unknown = &ranges - &*verified;
+            if unknown.is_empty() {
+                return Ok(buf.freeze());
+            }
+            let mut stack = vec![self.outboard.root()];
+            for item in tree.ranges_pre_order_chunks_iter_ref(&unknown, 0) {
+                match item {
+                    BaoChunk::Parent {
+                        node,
+                        is_root,
+                        left,
+                        right,
+                        ..
+                    } => {
+                        let pair = self.outboard.load(node).await?;
+                        validate_parent(tree.mode, &mut stack, node, pair, is_root, left, right)?;
+                    }
+                    BaoChunk::Leaf {
+                        start_chunk,
+                        size,
+                        is_root,
+                        ..
+                    } => {
+                        let start = start_chunk.to_bytes();
+                        let data = self.data.read_at(start, size).await?;
+                        validate_leaf(tree.mode, &mut stack, start_chunk, &data, size, is_root)?;
+                        copy_overlap(start, &data, pos, &mut buf);
+                        let end_chunk = start_chunk + tree.chunk_group_chunks();
+                        *verified |= ChunkRanges::from(start_chunk..end_chunk);
+                    }
+                }
+            }
+            Ok(buf.freeze())
+        }
+
+        async fn size(&mut self) -> io::Result<u64> {
+            Ok(self.outboard.tree().size)
+        }
+    }
+}
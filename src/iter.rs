@@ -7,7 +7,10 @@ use std::fmt::{self, Debug};
 use self_cell::self_cell;
 use smallvec::SmallVec;
 
-use crate::{split, BaoTree, BlockSize, ChunkNum, ChunkRanges, ChunkRangesRef, TreeNode};
+use crate::{
+    rec::truncate_ranges, split, BaoTree, BlockSize, ChunkNum, ChunkRanges, ChunkRangesRef,
+    TreeNode,
+};
 
 /// Extended node info.
 ///
@@ -327,6 +330,7 @@ pub enum BaoChunk<R = ()> {
 }
 
 impl<T> BaoChunk<T> {
+    /// Produce a compact, indented representation of the chunk for debugging
     #[cfg(test)]
     pub fn to_debug_string(&self, max_level: usize) -> String {
         match self {
@@ -654,7 +658,7 @@ pub struct ResponseIterRef<'a> {
 impl<'a> ResponseIterRef<'a> {
     /// Create a new iterator over the tree.
     pub fn new(tree: BaoTree, ranges: &'a ChunkRangesRef) -> Self {
-        let tree1 = BaoTree::new(tree.size, BlockSize::ZERO);
+        let tree1 = BaoTree::new_with_mode(tree.size, BlockSize::ZERO, tree.mode);
         Self {
             inner: PreOrderPartialChunkIterRef::new(tree1, ranges, tree.block_size.0),
         }
@@ -663,9 +667,10 @@ impl<'a> ResponseIterRef<'a> {
     /// Return the underlying tree.
     pub fn tree(&self) -> BaoTree {
         // the inner iterator uses a tree with block size 0, so we need to return the original tree
-        BaoTree::new(
+        BaoTree::new_with_mode(
             self.inner.tree().size,
             BlockSize(self.inner.min_full_level()),
+            self.inner.tree().mode,
         )
     }
 }
@@ -694,6 +699,11 @@ impl ResponseIterInner {
     fn tree(&self) -> BaoTree {
         self.with_dependent(|_, iter| iter.tree())
     }
+
+    #[cfg(feature = "tokio_fsm")]
+    fn ranges(&self) -> &ChunkRangesRef {
+        self.borrow_owner()
+    }
 }
 
 /// The owned version of `ResponseIterRef`.
@@ -717,6 +727,12 @@ impl ResponseIter {
     pub fn tree(&self) -> BaoTree {
         self.0.tree()
     }
+
+    /// The ranges this iterator is iterating over.
+    #[cfg(feature = "tokio_fsm")]
+    pub(crate) fn ranges(&self) -> &ChunkRangesRef {
+        self.0.ranges()
+    }
 }
 
 impl Iterator for ResponseIter {
@@ -726,3 +742,63 @@ impl Iterator for ResponseIter {
         self.0.next()
     }
 }
+
+/// A chunk of an encoded response, together with its position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodedChunk {
+    /// The chunk
+    pub chunk: BaoChunk,
+    /// Byte offset of the chunk in the encoded stream
+    pub encoded_offset: u64,
+    /// Byte offset of the data in the blob, for leaves
+    pub data_offset: Option<u64>,
+}
+
+/// An iterator that produces the chunks of an encoded response together
+/// with their offsets.
+///
+/// This wraps a [ResponseIterRef] and keeps track of the number of bytes that
+/// have been encoded so far.
+#[derive(Debug)]
+pub struct EncodedOffsetsIterRef<'a> {
+    inner: ResponseIterRef<'a>,
+    encoded_offset: u64,
+}
+
+impl<'a> EncodedOffsetsIterRef<'a> {
+    /// Create a new iterator over the tree.
+    ///
+    /// The ranges are truncated to the size of the tree, just like when encoding.
+    pub fn new(tree: BaoTree, ranges: &'a ChunkRangesRef) -> Self {
+        Self {
+            inner: ResponseIterRef::new(tree, truncate_ranges(ranges, tree.size)),
+            encoded_offset: 0,
+        }
+    }
+
+    /// The number of bytes of the encoded stream that have been iterated over.
+    ///
+    /// Once the iterator is exhausted, this is the total size of the encoded stream.
+    pub fn encoded_offset(&self) -> u64 {
+        self.encoded_offset
+    }
+}
+
+impl<'a> Iterator for EncodedOffsetsIterRef<'a> {
+    type Item = EncodedChunk;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let chunk = self.inner.next()?;
+        let data_offset = match chunk {
+            BaoChunk::Parent { .. } => None,
+            BaoChunk::Leaf { start_chunk, .. } => Some(start_chunk.to_bytes()),
+        };
+        let encoded_offset = self.encoded_offset;
+        self.encoded_offset += chunk.size() as u64;
+        Some(EncodedChunk {
+            chunk,
+            encoded_offset,
+            data_offset,
+        })
+    }
+}
@@ -199,6 +199,9 @@
 //! - use a block size of 1024, so no chunk groups
 //! - use a little endian u64 as the prefix for the encoded data
 //! - use only a single range
+//!
+//! The [io::compat] module implements the bao formats for encodings,
+//! outboards and slices on top of this crate.
 #![deny(missing_docs)]
 use range_collections::RangeSetRef;
 use std::{
@@ -207,13 +210,17 @@ use std::{
 };
 #[macro_use]
 mod macros;
+mod hash_mode;
 pub mod iter;
 mod rec;
 mod tree;
+pub use hash_mode::HashMode;
 use iter::*;
 pub use tree::{BlockSize, ChunkNum};
 pub mod io;
 pub use iroh_blake3 as blake3;
+#[cfg(feature = "serde")]
+pub mod serde_support;
 
 #[cfg(all(test, feature = "tokio_fsm"))]
 mod tests;
@@ -267,12 +274,19 @@ fn recursive_hash_subtree(start_chunk: u64, data: &[u8], is_root: bool) -> blake
 /// For some internal use, it is also possible to create trees that are just subtrees
 /// of a larger tree. In this case, the start_chunk is the chunk number of the first
 /// chunk in the tree, and the is_root flag can be false.
+///
+/// A tree also has a [HashMode], which is the regular BLAKE3 hash function
+/// unless the tree is created with [BaoTree::new_with_mode]. The mode is not
+/// part of any serialized form of a tree, since it can contain a key. It has
+/// to be provided again when deserializing.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct BaoTree {
     /// Total number of bytes in the file
     size: u64,
     /// Log base 2 of the chunk group size
     block_size: BlockSize,
+    /// The BLAKE3 mode used for hashing
+    mode: HashMode,
 }
 
 /// An offset of a node in a post-order outboard
@@ -297,7 +311,16 @@ impl PostOrderOffset {
 impl BaoTree {
     /// Create a new self contained BaoTree
     pub fn new(size: u64, block_size: BlockSize) -> Self {
-        Self { size, block_size }
+        Self::new_with_mode(size, block_size, HashMode::DEFAULT)
+    }
+
+    /// Create a new self contained BaoTree that uses the given hash mode
+    pub fn new_with_mode(size: u64, block_size: BlockSize, mode: HashMode) -> Self {
+        Self {
+            size,
+            block_size,
+            mode,
+        }
     }
 
     /// The size of the blob from which this tree was constructed, in bytes
@@ -310,6 +333,11 @@ impl BaoTree {
         self.block_size
     }
 
+    /// The hash mode of the tree
+    pub fn mode(&self) -> HashMode {
+        self.mode
+    }
+
     /// Given a tree of size `size` and block size `block_size`,
     /// compute the root node and the number of nodes for a shifted tree.
     pub(crate) fn shifted(&self) -> (TreeNode, TreeNode) {
@@ -323,7 +351,7 @@ impl BaoTree {
         let open_block = ((size & mask) != 0) as u64;
         // total number of blocks, rounding up to 1 if there are no blocks
         let blocks = (full_blocks + open_block).max(1);
-        let n = (blocks + 1) / 2;
+        let n = blocks.div_ceil(2);
         // root node
         let root = n.next_power_of_two() - 1;
         // number of nodes in the tree
@@ -371,6 +399,31 @@ impl BaoTree {
         PreOrderPartialChunkIterRef::new(*self, ranges, min_level)
     }
 
+    /// Traverse the part of the tree that is relevant for a ranges query in
+    /// the order of an encoded response, as [EncodedChunk]s.
+    ///
+    /// This gives the byte offset of each chunk in the encoded stream, and
+    /// the byte offset in the data for leaves. The ranges are truncated to the
+    /// size of the tree, just like when encoding.
+    pub fn encoded_offsets_iter_ref<'a>(
+        &self,
+        ranges: &'a ChunkRangesRef,
+    ) -> EncodedOffsetsIterRef<'a> {
+        EncodedOffsetsIterRef::new(*self, ranges)
+    }
+
+    /// The number of bytes of an encoded response for a ranges query.
+    ///
+    /// This is the exact number of bytes that [io::sync::encode_ranges] and
+    /// [io::sync::encode_ranges_validated] will write, not including any size
+    /// prefix.
+    pub fn encoded_size(&self, ranges: &ChunkRangesRef) -> u64 {
+        let ranges = rec::truncate_ranges(ranges, self.size);
+        ResponseIterRef::new(*self, ranges)
+            .map(|chunk| chunk.size() as u64)
+            .sum()
+    }
+
     /// Traverse the entire tree in post order as [TreeNode]s,
     /// down to the level given by the block size.
     pub fn post_order_nodes_iter(&self) -> impl Iterator<Item = TreeNode> {
@@ -446,7 +499,7 @@ impl BaoTree {
     #[allow(dead_code)]
     fn filled_size(&self) -> TreeNode {
         let blocks = self.chunks();
-        let n = (blocks.0 + 1) / 2;
+        let n = blocks.0.div_ceil(2);
         TreeNode(n + n.saturating_sub(1))
     }
 
@@ -589,7 +642,7 @@ impl TreeNode {
 
     /// Given a number of blocks, gives root node
     fn root(chunks: ChunkNum) -> TreeNode {
-        Self(((chunks.0 + 1) / 2).next_power_of_two() - 1)
+        Self(chunks.0.div_ceil(2).next_power_of_two() - 1)
     }
 
     /// the middle of the tree node, in blocks
@@ -2,7 +2,7 @@
 //!
 //! Encocding is used to compute hashes, decoding is only used in tests as a
 //! reference implementation.
-use crate::{blake3, split_inner, ChunkNum, ChunkRangesRef};
+use crate::{blake3, split_inner, ChunkNum, ChunkRangesRef, HashMode};
 
 /// Given a set of chunk ranges, adapt them for a tree of the given size.
 ///
@@ -96,7 +96,9 @@ fn truncated_len(ranges: &ChunkRangesRef, size: u64) -> usize {
 /// This is used as a reference implementation in tests, but also to compute hashes
 /// below the chunk group size when creating responses for outboards with a chunk group
 /// size of >0.
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn encode_selected_rec(
+    mode: HashMode,
     start_chunk: ChunkNum,
     data: &[u8],
     is_root: bool,
@@ -105,14 +107,12 @@ pub(crate) fn encode_selected_rec(
     emit_data: bool,
     res: &mut Vec<u8>,
 ) -> blake3::Hash {
-    use blake3::guts::{ChunkState, CHUNK_LEN};
+    use blake3::guts::CHUNK_LEN;
     if data.len() <= CHUNK_LEN {
         if emit_data && !query.is_empty() {
             res.extend_from_slice(data);
         }
-        let mut hasher = ChunkState::new(start_chunk.0);
-        hasher.update(data);
-        hasher.finalize(is_root)
+        mode.hash_subtree(start_chunk.0, data, is_root)
     } else {
         let chunks = data.len() / CHUNK_LEN + (data.len() % CHUNK_LEN != 0) as usize;
         let chunks = chunks.next_power_of_two();
@@ -137,6 +137,7 @@ pub(crate) fn encode_selected_rec(
         };
         // recurse to the left and right to compute the hashes and emit data
         let left = encode_selected_rec(
+            mode,
             start_chunk,
             &data[..mid_bytes],
             false,
@@ -146,6 +147,7 @@ pub(crate) fn encode_selected_rec(
             res,
         );
         let right = encode_selected_rec(
+            mode,
             mid_chunk,
             &data[mid_bytes..],
             false,
@@ -159,7 +161,7 @@ pub(crate) fn encode_selected_rec(
             res[o..o + 32].copy_from_slice(left.as_bytes());
             res[o + 32..o + 64].copy_from_slice(right.as_bytes());
         }
-        blake3::guts::parent_cv(&left, &right, is_root)
+        mode.parent_cv(&left, &right, is_root)
     }
 }
 
@@ -174,7 +176,7 @@ mod test_support {
         std::ops::Range,
     };
 
-    use crate::{BaoChunk, BaoTree, BlockSize, ChunkNum, ChunkRanges, ChunkRangesRef};
+    use crate::{BaoChunk, BaoTree, BlockSize, ChunkNum, ChunkRanges, ChunkRangesRef, HashMode};
 
     use super::{encode_selected_rec, truncate_ranges};
 
@@ -273,6 +275,7 @@ mod test_support {
         let mut res = Vec::new();
         res.extend_from_slice(&(data.len() as u64).to_le_bytes());
         let hash = encode_selected_rec(
+            HashMode::DEFAULT,
             ChunkNum(0),
             data,
             true,
@@ -288,6 +291,7 @@ mod test_support {
         let mut res = Vec::new();
         res.extend_from_slice(&(data.len() as u64).to_le_bytes());
         let hash = encode_selected_rec(
+            HashMode::DEFAULT,
             ChunkNum(0),
             data,
             true,
@@ -428,6 +432,7 @@ mod test_support {
         // canonicalize the ranges
         let ranges = truncate_ranges(ranges, size);
         let hash = encode_selected_rec(
+            HashMode::DEFAULT,
             ChunkNum(0),
             data,
             true,
@@ -439,6 +444,7 @@ mod test_support {
         (res, hash)
     }
 
+    /// Assert that the two elements of a tuple are equal
     #[macro_export]
     macro_rules! assert_tuple_eq {
         ($tuple:expr) => {
@@ -446,6 +452,7 @@ mod test_support {
         };
     }
 
+    /// Proptest version of [assert_tuple_eq]
     #[macro_export]
     macro_rules! prop_assert_tuple_eq {
         ($tuple:expr) => {
@@ -0,0 +1,184 @@
+//! Serde support for the tree and range types
+//!
+//! The representations are part of the public api and will not change in a
+//! minor release:
+//!
+//! - [ChunkNum] and [TreeNode] are serialized as a u64.
+//! - [BlockSize] is serialized as a u8, the log2 of the number of chunks in a
+//!   chunk group. Values above [BlockSize::MAX_CHUNK_LOG] are rejected.
+//! - [BaoTree] is serialized as a struct with the fields `size` (u64) and
+//!   `block_size`. The [HashMode](crate::HashMode) is not serialized, so no
+//!   key is leaked, and deserialized trees use the default mode. Serializing
+//!   a tree with a different mode fails, instead of silently changing the
+//!   mode on the other side.
+//! - [Parent] is serialized as a struct with the fields `node` and `pair`,
+//!   where `pair` is a tuple of the two hashes as 32 byte arrays.
+//! - [Leaf] is serialized as a struct with the fields `offset` (u64) and
+//!   `data` (bytes).
+//! - [ChunkRanges](crate::ChunkRanges) is a type alias for a foreign type, so it can only be
+//!   serialized using the [chunk_ranges] module with `#[serde(with = "...")]`.
+//!   It is serialized as a sequence of strictly increasing u64 boundaries.
+use bytes::Bytes;
+use serde::{de::Error, ser, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    blake3,
+    io::{Leaf, Parent},
+    BaoTree, BlockSize, ChunkNum, TreeNode,
+};
+
+impl Serialize for ChunkNum {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ChunkNum {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u64::deserialize(deserializer).map(Self)
+    }
+}
+
+impl Serialize for TreeNode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for TreeNode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u64::deserialize(deserializer).map(Self)
+    }
+}
+
+impl Serialize for BlockSize {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for BlockSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let chunk_log = u8::deserialize(deserializer)?;
+        if chunk_log > BlockSize::MAX_CHUNK_LOG {
+            return Err(D::Error::custom(format!(
+                "block size out of range: {chunk_log}"
+            )));
+        }
+        Ok(Self(chunk_log))
+    }
+}
+
+/// Wire format for [BaoTree]
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "BaoTree")]
+struct BaoTreeWireFormat {
+    size: u64,
+    block_size: BlockSize,
+}
+
+impl Serialize for BaoTree {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !self.mode.is_default() {
+            return Err(ser::Error::custom(
+                "can not serialize a tree with a non default hash mode",
+            ));
+        }
+        BaoTreeWireFormat {
+            size: self.size,
+            block_size: self.block_size,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for BaoTree {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let BaoTreeWireFormat { size, block_size } = BaoTreeWireFormat::deserialize(deserializer)?;
+        Ok(Self::new(size, block_size))
+    }
+}
+
+/// Wire format for [Parent]
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Parent")]
+struct ParentWireFormat {
+    node: TreeNode,
+    pair: ([u8; 32], [u8; 32]),
+}
+
+impl Serialize for Parent {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ParentWireFormat {
+            node: self.node,
+            pair: (*self.pair.0.as_bytes(), *self.pair.1.as_bytes()),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Parent {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let ParentWireFormat { node, pair } = ParentWireFormat::deserialize(deserializer)?;
+        Ok(Self {
+            node,
+            pair: (blake3::Hash::from(pair.0), blake3::Hash::from(pair.1)),
+        })
+    }
+}
+
+/// Wire format for [Leaf]
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Leaf")]
+struct LeafWireFormat {
+    offset: u64,
+    data: Bytes,
+}
+
+impl Serialize for Leaf {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        LeafWireFormat {
+            offset: self.offset,
+            data: self.data.clone(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Leaf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let LeafWireFormat { offset, data } = LeafWireFormat::deserialize(deserializer)?;
+        Ok(Self { offset, data })
+    }
+}
+
+/// Serialize and deserialize [crate::ChunkRanges] as a sequence of boundaries.
+///
+/// Use this with `#[serde(with = "bao_tree::serde_support::chunk_ranges")]`.
+///
+/// Unlike the serde implementation in the range-collections crate, deserializing
+/// rejects boundaries that are not strictly increasing instead of silently
+/// sorting them.
+pub mod chunk_ranges {
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    use smallvec::SmallVec;
+
+    use crate::{ChunkNum, ChunkRanges, ChunkRangesRef};
+
+    /// Serialize chunk ranges as a sequence of boundaries
+    pub fn serialize<S: Serializer>(
+        ranges: &ChunkRangesRef,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        ranges.boundaries().serialize(serializer)
+    }
+
+    /// Deserialize chunk ranges from a sequence of strictly increasing boundaries
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<ChunkRanges, D::Error> {
+        let boundaries = Vec::<ChunkNum>::deserialize(deserializer)?;
+        ChunkRanges::new(SmallVec::from_vec(boundaries))
+            .ok_or_else(|| D::Error::custom("chunk range boundaries must be strictly increasing"))
+    }
+}
@@ -26,7 +26,7 @@ use super::{
     iter::{BaoChunk, NodeInfo},
     pre_order_offset_loop,
     tree::ChunkNum,
-    BaoTree, BlockSize, TreeNode,
+    BaoTree, BlockSize, HashMode, TreeNode,
 };
 
 /// Compute the blake3 hash for the given data,
@@ -660,6 +660,7 @@ fn encode_selected_rec_cases() {
     let overhead = |data, min_level: u32| {
         let mut actual_encoded = Vec::new();
         encode_selected_rec(
+            HashMode::DEFAULT,
             ChunkNum(0),
             data,
             true,
@@ -685,6 +686,7 @@ fn encode_selected_reference(
     let max_skip_level = block_size.to_u32();
     let ranges = truncate_ranges(ranges, data.len() as u64);
     let hash = encode_selected_rec(
+        HashMode::DEFAULT,
         ChunkNum(0),
         data,
         true,
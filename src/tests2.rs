@@ -8,12 +8,12 @@
 //! handcrafted or from a previous failure of a proptest.
 use bytes::{Bytes, BytesMut};
 use proptest::prelude::*;
-use range_collections::{RangeSet2, RangeSetRef};
+use range_collections::{range_set::RangeSetRange, RangeSet2, RangeSetRef};
 use smallvec::SmallVec;
 use std::ops::Range;
 use test_strategy::proptest;
 
-use crate::io::outboard::PreOrderMemOutboard;
+use crate::io::outboard::{OutboardOrder, PreOrderMemOutboard};
 use crate::io::BaoContentItem;
 use crate::rec::{
     get_leaf_ranges, make_test_data, partial_chunk_iter_reference, range_union,
@@ -21,11 +21,11 @@ use crate::rec::{
 };
 use crate::{assert_tuple_eq, prop_assert_tuple_eq, ChunkRanges, ChunkRangesRef};
 use crate::{
-    blake3, hash_subtree,
+    blake3,
     io::{fsm::ResponseDecoderNext, outboard::PostOrderMemOutboard, sync::Outboard, Leaf, Parent},
     iter::{BaoChunk, PreOrderPartialChunkIterRef, ResponseIterRef},
     rec::{encode_selected_rec, select_nodes_rec},
-    BaoTree, BlockSize, ChunkNum, TreeNode,
+    BaoTree, BlockSize, ChunkNum, HashMode, TreeNode,
 };
 
 #[cfg(feature = "validate")]
@@ -152,8 +152,8 @@ fn outboard_test_sync(data: &[u8], outboard: impl crate::io::sync::Outboard) {
         let start_chunk = node.chunk_range().start;
         let byte_range = tree.byte_range(node);
         let data = &data[byte_range.start.try_into().unwrap()..byte_range.end.try_into().unwrap()];
-        let expected = hash_subtree(start_chunk.0, data, is_root);
-        let actual = blake3::guts::parent_cv(&l_hash, &r_hash, is_root);
+        let expected = tree.mode.hash_subtree(start_chunk.0, data, is_root);
+        let actual = tree.mode.parent_cv(&l_hash, &r_hash, is_root);
         assert_eq!(actual, expected);
     }
 }
@@ -172,8 +172,8 @@ async fn outboard_test_fsm(data: &[u8], mut outboard: impl crate::io::fsm::Outbo
         let start_chunk = node.chunk_range().start;
         let byte_range = tree.byte_range(node);
         let data = &data[byte_range.start.try_into().unwrap()..byte_range.end.try_into().unwrap()];
-        let expected = hash_subtree(start_chunk.0, data, is_root);
-        let actual = blake3::guts::parent_cv(&l_hash, &r_hash, is_root);
+        let expected = tree.mode.hash_subtree(start_chunk.0, data, is_root);
+        let actual = tree.mode.parent_cv(&l_hash, &r_hash, is_root);
         assert_eq!(actual, expected);
     }
 }
@@ -233,6 +233,2603 @@ fn mem_outboard_flip_proptest(#[strategy(tree())] tree: BaoTree) {
     mem_outboard_flip_impl(tree);
 }
 
+/// Create a post order outboard for a prefix of the data, append the rest,
+/// and compare with an outboard computed from scratch.
+fn append_post_order_sync_impl(tree: BaoTree, appended: u64) {
+    let new_size = tree.size + appended;
+    let data = make_test_data(new_size.try_into().unwrap());
+    let prefix = PostOrderMemOutboard::create(&data[..tree.size as usize], tree.block_size);
+    let mut outboard = crate::io::outboard::PostOrderOutboard {
+        root: prefix.root,
+        tree: prefix.tree,
+        data: prefix.data,
+    };
+    let root = crate::io::sync::append_post_order(&mut outboard, &data[..], new_size).unwrap();
+    let expected = PostOrderMemOutboard::create(&data, tree.block_size);
+    assert_eq!(root, expected.root);
+    assert_eq!(outboard.root, expected.root);
+    assert_eq!(outboard.tree, expected.tree);
+    assert_eq!(outboard.data, expected.data);
+}
+
+/// Same as [append_post_order_sync_impl], but using the fsm io api
+async fn append_post_order_fsm_impl(tree: BaoTree, appended: u64) {
+    let new_size = tree.size + appended;
+    let data = make_test_data(new_size.try_into().unwrap());
+    let prefix = PostOrderMemOutboard::create(&data[..tree.size as usize], tree.block_size);
+    let mut outboard = crate::io::outboard::PostOrderOutboard {
+        root: prefix.root,
+        tree: prefix.tree,
+        data: BytesMut::from(prefix.data.as_slice()),
+    };
+    let root =
+        crate::io::fsm::append_post_order(&mut outboard, Bytes::from(data.clone()), new_size)
+            .await
+            .unwrap();
+    let expected = PostOrderMemOutboard::create(&data, tree.block_size);
+    assert_eq!(root, expected.root);
+    assert_eq!(outboard.root, expected.root);
+    assert_eq!(outboard.tree, expected.tree);
+    assert_eq!(outboard.data.as_ref(), expected.data.as_slice());
+}
+
+#[test]
+fn append_post_order_cases() {
+    let cases = [
+        (0, 0, 0),
+        (0, 0, 1),
+        (1024, 0, 1024),
+        (2048, 0, 1),
+        (4096, 1, 4096),
+        (0x3001, 2, 0x10000),
+        (100000, 4, 0),
+    ];
+    for (size, block_level, appended) in cases {
+        let tree = BaoTree::new(size, BlockSize(block_level));
+        append_post_order_sync_impl(tree, appended);
+        run_blocking(append_post_order_fsm_impl(tree, appended));
+    }
+}
+
+#[proptest]
+fn append_post_order_sync_proptest(
+    #[strategy(tree())] tree: BaoTree,
+    #[strategy(0u64..100000)] appended: u64,
+) {
+    append_post_order_sync_impl(tree, appended);
+}
+
+#[proptest]
+fn append_post_order_fsm_proptest(
+    #[strategy(tree())] tree: BaoTree,
+    #[strategy(0u64..100000)] appended: u64,
+) {
+    run_blocking(append_post_order_fsm_impl(tree, appended));
+}
+
+/// Feed data to the incremental hasher in pieces of `write_size` bytes, and
+/// compare the result with an outboard computed with the size known up front.
+fn bao_hasher_impl(tree: BaoTree, write_size: usize) {
+    use std::io::Write;
+    let data = make_test_data(tree.size.try_into().unwrap());
+    let mut hasher = crate::io::sync::BaoHasher::new(tree.block_size, Vec::new());
+    for piece in data.chunks(write_size) {
+        hasher.write_all(piece).unwrap();
+    }
+    assert_eq!(hasher.size(), tree.size);
+    let actual = hasher.finalize().unwrap();
+    let expected = PostOrderMemOutboard::create(&data, tree.block_size);
+    assert_eq!(actual.root, expected.root);
+    assert_eq!(actual.tree, expected.tree);
+    assert_eq!(actual.data, expected.data);
+}
+
+/// Write data through an outboarding writer in pieces of `write_size` bytes,
+/// and compare the result with an outboard computed from the data.
+fn outboarding_writer_sync_impl(tree: BaoTree, write_size: usize) {
+    use std::io::Write;
+    let data = make_test_data(tree.size.try_into().unwrap());
+    let mut writer = crate::io::sync::OutboardingWriter::new_with_mode(
+        Vec::new(),
+        tree.block_size,
+        tree.mode,
+        Vec::new(),
+    );
+    for piece in data.chunks(write_size) {
+        writer.write_all(piece).unwrap();
+    }
+    assert_eq!(writer.size(), tree.size);
+    let (written, actual) = writer.finalize().unwrap();
+    let expected = PostOrderMemOutboard::create_with_mode(&data, tree.block_size, tree.mode);
+    assert_eq!(written, data);
+    assert_eq!(actual.root, expected.root);
+    assert_eq!(actual.tree, expected.tree);
+    assert_eq!(actual.data, expected.data);
+}
+
+async fn outboarding_writer_fsm_impl(tree: BaoTree, write_size: usize) {
+    use iroh_io::AsyncStreamWriter;
+    let data = make_test_data(tree.size.try_into().unwrap());
+    let mut writer = crate::io::fsm::OutboardingWriter::new_with_mode(
+        Vec::new(),
+        tree.block_size,
+        tree.mode,
+        Vec::new(),
+    );
+    for (i, piece) in data.chunks(write_size).enumerate() {
+        if i % 2 == 0 {
+            writer.write(piece).await.unwrap();
+        } else {
+            writer
+                .write_bytes(Bytes::copy_from_slice(piece))
+                .await
+                .unwrap();
+        }
+    }
+    assert_eq!(writer.size(), tree.size);
+    let (written, actual) = writer.finalize().await.unwrap();
+    let expected = PostOrderMemOutboard::create_with_mode(&data, tree.block_size, tree.mode);
+    assert_eq!(written, data);
+    assert_eq!(actual.root, expected.root);
+    assert_eq!(actual.tree, expected.tree);
+    assert_eq!(actual.data, expected.data);
+}
+
+#[test]
+fn outboarding_writer_cases() {
+    let cases = [
+        (0, 0, 1),
+        (1, 0, 1),
+        (1024, 0, 1024),
+        (1025, 0, 1024),
+        (4097, 1, 4097),
+        (100000, 4, 7),
+    ];
+    let modes = [HashMode::DEFAULT, HashMode::keyed(&[5; 32])];
+    for (size, block_level, write_size) in cases {
+        for mode in modes {
+            let tree = BaoTree::new_with_mode(size, BlockSize(block_level), mode);
+            outboarding_writer_sync_impl(tree, write_size);
+            run_blocking(outboarding_writer_fsm_impl(tree, write_size));
+        }
+    }
+}
+
+#[proptest]
+fn outboarding_writer_proptest(
+    #[strategy(tree())] tree: BaoTree,
+    #[strategy(1usize..20000)] write_size: usize,
+) {
+    outboarding_writer_sync_impl(tree, write_size);
+    run_blocking(outboarding_writer_fsm_impl(tree, write_size));
+}
+
+#[test]
+fn bao_hasher_cases() {
+    let cases = [
+        (0, 0, 1),
+        (1, 0, 1),
+        (1024, 0, 1024),
+        (1025, 0, 1024),
+        (4096, 1, 2048),
+        (4097, 1, 4097),
+        (0x10000, 2, 1000),
+        (100000, 4, 7),
+    ];
+    for (size, block_level, write_size) in cases {
+        let tree = BaoTree::new(size, BlockSize(block_level));
+        bao_hasher_impl(tree, write_size);
+    }
+}
+
+#[proptest]
+fn bao_hasher_proptest(
+    #[strategy(tree())] tree: BaoTree,
+    #[strategy(1usize..40000)] write_size: usize,
+) {
+    bao_hasher_impl(tree, write_size);
+}
+
+/// A writer that fails every third write while `flaky` is set, and writes at
+/// most 50 bytes at once
+struct FlakyWriter {
+    data: Vec<u8>,
+    calls: usize,
+    flaky: std::rc::Rc<std::cell::Cell<bool>>,
+}
+
+impl std::io::Write for FlakyWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.calls += 1;
+        if self.flaky.get() && self.calls % 3 == 0 {
+            return Err(std::io::ErrorKind::Other.into());
+        }
+        let n = buf.len().min(50);
+        self.data.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Failed writes to the outboard do not consume data, so they can be retried
+#[test]
+fn bao_hasher_retry() {
+    use std::io::Write;
+    let data = make_test_data(100000);
+    let flaky = std::rc::Rc::new(std::cell::Cell::new(true));
+    let outboard = FlakyWriter {
+        data: Vec::new(),
+        calls: 0,
+        flaky: flaky.clone(),
+    };
+    let mut hasher = crate::io::sync::BaoHasher::new(BlockSize(0), outboard);
+    let mut remaining = &data[..];
+    let mut errors = 0;
+    while !remaining.is_empty() {
+        match hasher.write(&remaining[..remaining.len().min(3000)]) {
+            Ok(n) => remaining = &remaining[n..],
+            Err(_) => errors += 1,
+        }
+    }
+    assert!(errors > 0);
+    assert_eq!(hasher.size(), data.len() as u64);
+    flaky.set(false);
+    let actual = hasher.finalize().unwrap();
+    let expected = PostOrderMemOutboard::create(&data, BlockSize(0));
+    assert_eq!(actual.root, expected.root);
+    assert_eq!(actual.data.data, expected.data);
+}
+
+#[test]
+fn append_post_order_shrink() {
+    let data = make_test_data(10000);
+    let prefix = PostOrderMemOutboard::create(&data, BlockSize(1));
+    let mut outboard = crate::io::outboard::PostOrderOutboard {
+        root: prefix.root,
+        tree: prefix.tree,
+        data: prefix.data,
+    };
+    assert!(crate::io::sync::append_post_order(&mut outboard, &data[..], 5000).is_err());
+}
+
+/// Create test data of `size_a` and `size_b` bytes, flipping bytes of b at the
+/// given positions, and the expected diff computed by comparing chunk groups.
+fn diff_test_data(
+    size_a: u64,
+    size_b: u64,
+    block_size: BlockSize,
+    changes: &[u64],
+) -> (Vec<u8>, Vec<u8>, ChunkRanges) {
+    let data_a = make_test_data(size_a.try_into().unwrap());
+    let mut data_b = make_test_data(size_b.try_into().unwrap());
+    if size_b > 0 {
+        for &pos in changes {
+            data_b[(pos % size_b) as usize] ^= 0xff;
+        }
+    }
+    let group_bytes = block_size.bytes() as u64;
+    let mut expected = ChunkRanges::empty();
+    let mut start = 0;
+    while start < size_b || start == 0 {
+        let end_a = (start + group_bytes).min(size_a);
+        let end_b = (start + group_bytes).min(size_b);
+        let same = start < size_a
+            && end_a == end_b
+            && data_a[start as usize..end_a as usize] == data_b[start as usize..end_b as usize];
+        // for a single chunk group, only the root hash is known
+        let single_group = size_a <= group_bytes && size_a != size_b;
+        if size_b > 0 && (!same || single_group) {
+            expected |= ChunkRanges::from(ChunkNum::full_chunks(start)..ChunkNum::chunks(end_b));
+        }
+        start += group_bytes;
+    }
+    (data_a, data_b, expected)
+}
+
+fn diff_sync_impl(size_a: u64, size_b: u64, block_size: BlockSize, changes: &[u64]) {
+    let (data_a, data_b, expected) = diff_test_data(size_a, size_b, block_size, changes);
+    let a = PreOrderMemOutboard::create(&data_a, block_size);
+    let b = PostOrderMemOutboard::create(&data_b, block_size);
+    let actual = crate::io::sync::diff(&a, &b).unwrap();
+    assert_eq!(actual, expected);
+}
+
+async fn diff_fsm_impl(size_a: u64, size_b: u64, block_size: BlockSize, changes: &[u64]) {
+    let (data_a, data_b, expected) = diff_test_data(size_a, size_b, block_size, changes);
+    let a = PostOrderMemOutboard::create(&data_a, block_size);
+    let b = PreOrderMemOutboard::create(&data_b, block_size);
+    let actual = crate::io::fsm::diff(a, b).await.unwrap();
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn diff_cases() {
+    let cases: [(u64, u64, u8, &[u64]); 10] = [
+        (0, 0, 0, &[]),
+        (1024, 1024, 0, &[]),
+        (1024, 1024, 0, &[0]),
+        (1024, 2048, 0, &[]),
+        (4096, 4096, 1, &[5000]),
+        (5000, 100000, 2, &[]),
+        (100000, 5000, 2, &[1]),
+        (100000, 100000, 4, &[20000, 90000]),
+        (43009, 43008, 1, &[]),
+        (0x3000, 0x3400, 0, &[0x1000]),
+    ];
+    for (size_a, size_b, block_level, changes) in cases {
+        diff_sync_impl(size_a, size_b, BlockSize(block_level), changes);
+        run_blocking(diff_fsm_impl(
+            size_a,
+            size_b,
+            BlockSize(block_level),
+            changes,
+        ));
+    }
+}
+
+#[proptest]
+fn diff_sync_proptest(
+    #[strategy(tree())] tree: BaoTree,
+    #[strategy(0u64..100000)] size_b: u64,
+    #[strategy(proptest::collection::vec(any::<u64>(), 0..4))] changes: Vec<u64>,
+) {
+    diff_sync_impl(tree.size, size_b, tree.block_size, &changes);
+}
+
+#[proptest]
+fn diff_fsm_proptest(
+    #[strategy(tree())] tree: BaoTree,
+    #[strategy(0u64..100000)] size_b: u64,
+    #[strategy(proptest::collection::vec(any::<u64>(), 0..4))] changes: Vec<u64>,
+) {
+    run_blocking(diff_fsm_impl(tree.size, size_b, tree.block_size, &changes));
+}
+
+/// Create a range proof, roundtrip it through the serialization, and verify it.
+/// Then check that a proof with corrupted data is rejected.
+fn range_proof_impl(size: usize, block_size: BlockSize, ranges: &ChunkRangesRef) {
+    use crate::io::proof::RangeProof;
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let proof = RangeProof::create(&outboard, &data[..], ranges).unwrap();
+    let bytes = proof.to_bytes();
+    let proof2 = RangeProof::from_bytes(&bytes).unwrap();
+    assert_eq!(proof, proof2);
+    let actual = proof2
+        .verify(outboard.root, size as u64, block_size)
+        .unwrap();
+    let mut expected = Vec::new();
+    let size = size as u64;
+    for range in proof.ranges().iter() {
+        let (start, end) = match range {
+            RangeSetRange::Range(r) => (r.start.to_bytes(), r.end.to_bytes()),
+            RangeSetRange::RangeFrom(r) => (r.start.to_bytes(), size),
+        };
+        expected.extend_from_slice(&data[start.min(size) as usize..end.min(size) as usize]);
+    }
+    assert_eq!(actual.as_ref(), expected.as_slice());
+    // the ranges the proof was created for are the expected ranges
+    let checked = proof2
+        .verify_ranges(outboard.root, size, block_size, ranges)
+        .unwrap();
+    assert_eq!(checked, actual);
+    // the proof must not verify against a different root
+    if !proof.ranges().is_empty() {
+        let res = proof.verify(blake3::hash(b"wrong"), size, block_size);
+        assert!(res.is_err());
+    }
+    // corrupt the last byte of the proof, which is part of the last leaf
+    if size > 0 && !proof.ranges().is_empty() {
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let proof = RangeProof::from_bytes(&corrupted).unwrap();
+        assert!(proof.verify(outboard.root, size, block_size).is_err());
+    }
+    // a truncated proof must not parse
+    if !bytes.is_empty() {
+        assert!(RangeProof::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
+
+#[test]
+fn range_proof_cases() {
+    let cases = [
+        (0, 0, ChunkRanges::all()),
+        (1024, 0, ChunkRanges::all()),
+        (1025, 0, ChunkRanges::from(ChunkNum(1)..ChunkNum(2))),
+        (100000, 2, ChunkRanges::from(ChunkNum(3)..ChunkNum(5))),
+        (100000, 4, ChunkRanges::from(ChunkNum(90)..)),
+        (
+            100000,
+            0,
+            ChunkRanges::from(ChunkNum(1)..ChunkNum(2))
+                | ChunkRanges::from(ChunkNum(7)..ChunkNum(9)),
+        ),
+    ];
+    for (size, block_level, ranges) in cases {
+        range_proof_impl(size, BlockSize(block_level), &ranges);
+    }
+}
+
+#[proptest]
+fn range_proof_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, selection) = size_and_selection;
+    range_proof_impl(size, block_size, &selection);
+}
+
+/// A valid proof for other ranges of the same blob must not pass as a proof for
+/// the expected ranges
+#[test]
+fn range_proof_other_ranges() {
+    use crate::io::proof::RangeProof;
+    let data = make_test_data(100000);
+    let outboard = PostOrderMemOutboard::create(&data, BlockSize(0));
+    let expected = ChunkRanges::from(ChunkNum(3)..ChunkNum(5));
+    let other = ChunkRanges::from(ChunkNum(7)..ChunkNum(9));
+    let proof = RangeProof::create(&outboard, &data[..], &other).unwrap();
+    // plain verify accepts it, since the proof itself is valid
+    assert!(proof.verify(outboard.root, 100000, BlockSize(0)).is_ok());
+    let res = proof.verify_ranges(outboard.root, 100000, BlockSize(0), &expected);
+    assert!(res.is_err());
+    // ranges past the end are compared after truncating them to the size
+    let proof = RangeProof::create(&outboard, &data[..], &ChunkRanges::all()).unwrap();
+    let past_end = ChunkRanges::from(ChunkNum(0)..ChunkNum(1000));
+    let res = proof.verify_ranges(outboard.root, 100000, BlockSize(0), &past_end);
+    assert_eq!(res.unwrap().as_ref(), data.as_slice());
+}
+
+/// A deserialized proof with ranges past the end of the blob must be truncated
+/// to the size when verifying, like when creating the proof.
+#[test]
+fn range_proof_untrusted_ranges() {
+    use crate::io::proof::RangeProof;
+    let data = make_test_data(10000);
+    let outboard = PostOrderMemOutboard::create(&data, BlockSize(0));
+    let proof = RangeProof::create(&outboard, &data[..], &ChunkRanges::all()).unwrap();
+    // replace the single boundary 0 with the range 0..2^54, whose end in bytes
+    // overflows
+    let mut bytes = proof.to_bytes();
+    assert_eq!(
+        &bytes[..16],
+        &[1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+    );
+    bytes[0] = 2;
+    bytes.splice(16..16, (1u64 << 54).to_le_bytes());
+    let proof = RangeProof::from_bytes(&bytes).unwrap();
+    let actual = proof.verify(outboard.root, 10000, BlockSize(0)).unwrap();
+    assert_eq!(actual.as_ref(), data.as_slice());
+}
+
+/// Encode a size proof and verify it against the actual size and a wrong size.
+fn size_proof_sync_impl(tree: BaoTree, claimed: u64) {
+    use crate::io::sync::verify_size_proof;
+    let data = make_test_data(tree.size.try_into().unwrap());
+    let outboard = PostOrderMemOutboard::create_with_mode(&data, tree.block_size, tree.mode);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_size_proof(&data[..], &outboard, &mut encoded).unwrap();
+    let (root, block_size, mode) = (outboard.root, tree.block_size, tree.mode);
+    let verified = verify_size_proof(&encoded[..], root, tree.size, block_size, mode).unwrap();
+    assert_eq!(verified, tree);
+    let res = verify_size_proof(&encoded[..], root, claimed, block_size, mode);
+    assert_eq!(res.is_ok(), claimed == tree.size);
+}
+
+/// Same as [size_proof_sync_impl], but using the fsm io api
+async fn size_proof_fsm_impl(tree: BaoTree, claimed: u64) {
+    let data = Bytes::from(make_test_data(tree.size.try_into().unwrap()));
+    let outboard = PostOrderMemOutboard::create_with_mode(&data, tree.block_size, tree.mode);
+    let mut encoded = Vec::new();
+    crate::io::fsm::encode_size_proof(data, &mut outboard.clone(), &mut encoded)
+        .await
+        .unwrap();
+    let encoded = Bytes::from(encoded);
+    let verified = crate::io::fsm::verify_size_proof(
+        encoded.clone(),
+        outboard.root,
+        tree.size,
+        tree.block_size,
+        tree.mode,
+    )
+    .await
+    .unwrap();
+    assert_eq!(verified, tree);
+    let res = crate::io::fsm::verify_size_proof(
+        encoded,
+        outboard.root,
+        claimed,
+        tree.block_size,
+        tree.mode,
+    )
+    .await;
+    assert_eq!(res.is_ok(), claimed == tree.size);
+}
+
+#[test]
+fn size_proof_cases() {
+    let cases = [
+        (0, 0, 1),
+        (1, 0, 0),
+        (1024, 0, 1025),
+        (100000, 2, 99999),
+        (100000, 4, 100000),
+        (0x10000, 0, 0x8000),
+    ];
+    let modes = [HashMode::DEFAULT, HashMode::keyed(&[7; 32])];
+    for (size, block_level, claimed) in cases {
+        for mode in modes {
+            let tree = BaoTree::new_with_mode(size, BlockSize(block_level), mode);
+            size_proof_sync_impl(tree, claimed);
+            run_blocking(size_proof_fsm_impl(tree, claimed));
+        }
+    }
+}
+
+#[test]
+fn size_proof_mismatch() {
+    let data = make_test_data(100000);
+    let outboard = PostOrderMemOutboard::create(&data, BlockSize(2));
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_size_proof(&data[..], &outboard, &mut encoded).unwrap();
+    let res = crate::io::sync::verify_size_proof(
+        &encoded[..],
+        outboard.root,
+        99999,
+        BlockSize(2),
+        HashMode::DEFAULT,
+    );
+    assert!(matches!(res, Err(crate::io::DecodeError::SizeMismatch)));
+}
+
+/// Only a mismatch at the last chunk or a proof that ends early is reported as
+/// a size mismatch, a corrupted hash pair is reported as such.
+#[test]
+fn size_proof_errors() {
+    use crate::io::{sync::verify_size_proof, DecodeError};
+    let data = make_test_data(100000);
+    let outboard = PostOrderMemOutboard::create(&data, BlockSize(2));
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_size_proof(&data[..], &outboard, &mut encoded).unwrap();
+    // claimed size too large, the proof ends early
+    let small = PostOrderMemOutboard::create(&data[..10], BlockSize(2));
+    let res = verify_size_proof(
+        &data[..10],
+        small.root,
+        1 << 20,
+        BlockSize(2),
+        HashMode::DEFAULT,
+    );
+    assert!(matches!(res, Err(DecodeError::SizeMismatch)), "{:?}", res);
+    // corrupted root hash pair
+    let mut corrupted = encoded.clone();
+    corrupted[0] ^= 1;
+    let res = verify_size_proof(
+        &corrupted[..],
+        outboard.root,
+        100000,
+        BlockSize(2),
+        HashMode::DEFAULT,
+    );
+    assert!(
+        matches!(res, Err(DecodeError::ParentHashMismatch(_))),
+        "{:?}",
+        res
+    );
+}
+
+#[proptest]
+fn size_proof_sync_proptest(
+    #[strategy(tree())] tree: BaoTree,
+    #[strategy(0u64..100000)] claimed: u64,
+) {
+    size_proof_sync_impl(tree, claimed);
+}
+
+#[proptest]
+fn size_proof_fsm_proptest(
+    #[strategy(tree())] tree: BaoTree,
+    #[strategy(0u64..100000)] claimed: u64,
+) {
+    run_blocking(size_proof_fsm_impl(tree, claimed));
+}
+
+/// Encode ranges with a size proof, decode them, and check that a wrong size
+/// prefix is detected before any data is yielded.
+fn size_proof_response_impl(tree: BaoTree, ranges: &ChunkRanges, claimed: u64) {
+    use crate::io::{fsm::ResponseDecoder, sync::DecodeResponseIter, DecodeError};
+    let data = Bytes::from(make_test_data(tree.size.try_into().unwrap()));
+    let outboard = PostOrderMemOutboard::create_with_mode(&data, tree.block_size, tree.mode);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges_with_size_proof(&data[..], &outboard, ranges, &mut encoded)
+        .unwrap();
+    let mut encoded_fsm = Vec::new();
+    run_blocking(crate::io::fsm::encode_ranges_with_size_proof(
+        data.clone(),
+        &mut outboard.clone(),
+        ranges,
+        &mut encoded_fsm,
+    ))
+    .unwrap();
+    assert_eq!(encoded, encoded_fsm);
+    let iter = DecodeResponseIter::new_with_size_proof(
+        outboard.root,
+        tree.block_size,
+        tree.mode,
+        &encoded[..],
+        ranges,
+    )
+    .unwrap();
+    assert_eq!(iter.tree(), tree);
+    let items = iter.collect::<Result<Vec<_>, _>>().unwrap();
+    let fsm_items = run_blocking(async {
+        let decoder = ResponseDecoder::new_with_size_proof(
+            outboard.root,
+            ranges.clone(),
+            tree.block_size,
+            tree.mode,
+            Bytes::from(encoded.clone()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(decoder.tree(), tree);
+        let mut reading = decoder;
+        let mut items = Vec::new();
+        while let crate::io::fsm::ResponseDecoderNext::More((next, item)) = reading.next().await {
+            items.push(item.unwrap());
+            reading = next;
+        }
+        items
+    });
+    assert_eq!(items.len(), fsm_items.len());
+    for item in items {
+        if let BaoContentItem::Leaf(leaf) = item {
+            let start = leaf.offset as usize;
+            assert_eq!(&leaf.data[..], &data[start..start + leaf.data.len()]);
+        }
+    }
+    if claimed == tree.size {
+        return;
+    }
+    let mut lying = encoded.clone();
+    lying[..8].copy_from_slice(&claimed.to_le_bytes());
+    let err = DecodeResponseIter::new_with_size_proof(
+        outboard.root,
+        tree.block_size,
+        tree.mode,
+        &lying[..],
+        ranges,
+    )
+    .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            DecodeError::SizeMismatch | DecodeError::ParentHashMismatch(_)
+        ),
+        "{:?}",
+        err
+    );
+    let err = run_blocking(ResponseDecoder::new_with_size_proof(
+        outboard.root,
+        ranges.clone(),
+        tree.block_size,
+        tree.mode,
+        Bytes::from(lying),
+    ))
+    .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            DecodeError::SizeMismatch | DecodeError::ParentHashMismatch(_)
+        ),
+        "{:?}",
+        err
+    );
+}
+
+#[test]
+fn size_proof_response_cases() {
+    let cases = [
+        (0, 0, ChunkRanges::all(), 1),
+        (1024, 0, ChunkRanges::all(), 1025),
+        (100000, 2, ChunkRanges::from(..ChunkNum(16)), 99999),
+        (
+            100000,
+            2,
+            ChunkRanges::from(ChunkNum(16)..ChunkNum(32)),
+            16384,
+        ),
+        (100000, 0, ChunkRanges::from(ChunkNum(50)..), 100000),
+    ];
+    let modes = [HashMode::DEFAULT, HashMode::derive_key("size proof test")];
+    for (size, block_level, ranges, claimed) in cases {
+        for mode in modes {
+            let tree = BaoTree::new_with_mode(size, BlockSize(block_level), mode);
+            size_proof_response_impl(tree, &ranges, claimed);
+        }
+    }
+}
+
+#[test]
+fn size_proof_response_mismatch() {
+    let data = make_test_data(100000);
+    let outboard = PostOrderMemOutboard::create(&data, BlockSize(2));
+    let mut encoded = Vec::new();
+    let ranges = ChunkRanges::from(..ChunkNum(16));
+    crate::io::sync::encode_ranges_with_size_proof(&data[..], &outboard, &ranges, &mut encoded)
+        .unwrap();
+    encoded[..8].copy_from_slice(&99999u64.to_le_bytes());
+    let res = crate::io::sync::DecodeResponseIter::new_with_size_proof(
+        outboard.root,
+        BlockSize(2),
+        HashMode::DEFAULT,
+        &encoded[..],
+        &ranges,
+    );
+    assert!(matches!(res, Err(crate::io::DecodeError::SizeMismatch)));
+}
+
+#[proptest]
+fn size_proof_response_proptest(
+    #[strategy(tree())] tree: BaoTree,
+    #[strategy(0u64..100000)] claimed: u64,
+) {
+    size_proof_response_impl(tree, &ChunkRanges::from(..ChunkNum(8)), claimed);
+}
+
+/// Convert an outboard to a different block size, and compare with an outboard
+/// computed from scratch with the new block size.
+/// A stream that returns at most `max` bytes per read, like a network stream
+struct ShortReads<R> {
+    inner: R,
+    max: usize,
+}
+
+impl<R: iroh_io::AsyncStreamReader> iroh_io::AsyncStreamReader for ShortReads<R> {
+    async fn read_bytes(&mut self, len: usize) -> std::io::Result<Bytes> {
+        self.inner.read_bytes(len.min(self.max)).await
+    }
+
+    async fn read<const L: usize>(&mut self) -> std::io::Result<[u8; L]> {
+        self.inner.read::<L>().await
+    }
+}
+
+/// The fsm decoder must read complete leaves even if the stream returns
+/// less data than requested, and report a leaf that ends early as not found.
+#[test]
+fn fsm_decode_short_reads() {
+    use crate::io::{fsm::ResponseDecoder, DecodeError};
+    let data = make_test_data(10000);
+    let tree = BaoTree::new(data.len() as u64, BlockSize(2));
+    let outboard = PostOrderMemOutboard::create(&data, tree.block_size);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges_validated(
+        &data[..],
+        &outboard,
+        &ChunkRanges::all(),
+        &mut encoded,
+    )
+    .unwrap();
+    let decode = |encoded: Bytes| {
+        run_blocking(async move {
+            let encoded = ShortReads {
+                inner: encoded,
+                max: 100,
+            };
+            let mut reading =
+                ResponseDecoder::new(outboard.root, ChunkRanges::all(), tree, encoded);
+            let mut res = Vec::new();
+            while let ResponseDecoderNext::More((next, item)) = reading.next().await {
+                if let BaoContentItem::Leaf(leaf) = item? {
+                    res.extend_from_slice(&leaf.data);
+                }
+                reading = next;
+            }
+            Ok::<_, DecodeError>(res)
+        })
+    };
+    let decoded = decode(Bytes::from(encoded.clone())).unwrap();
+    assert_eq!(decoded, data);
+    let truncated = Bytes::copy_from_slice(&encoded[..encoded.len() - 10]);
+    let res = decode(truncated);
+    assert!(
+        matches!(res, Err(DecodeError::LeafNotFound(_))),
+        "{:?}",
+        res
+    );
+}
+
+fn convert_block_size_sync_impl(tree: BaoTree, block_size: BlockSize) {
+    let data = make_test_data(tree.size.try_into().unwrap());
+    let from = PostOrderMemOutboard::create(&data, tree.block_size);
+    let new_tree = BaoTree::new(tree.size, block_size);
+    let mut to = PreOrderMemOutboard {
+        root: from.root,
+        tree: new_tree,
+        data: vec![0u8; new_tree.outboard_size().try_into().unwrap()],
+    };
+    crate::io::sync::convert_block_size(&from, &data[..], &mut to, block_size).unwrap();
+    let expected = PreOrderMemOutboard::create(&data, block_size);
+    assert_eq!(to, expected);
+}
+
+/// Same as [convert_block_size_sync_impl], but using the fsm io api
+async fn convert_block_size_fsm_impl(tree: BaoTree, block_size: BlockSize) {
+    let data = Bytes::from(make_test_data(tree.size.try_into().unwrap()));
+    let from = PreOrderMemOutboard::create(&data, tree.block_size);
+    let new_tree = BaoTree::new(tree.size, block_size);
+    let mut to = PostOrderMemOutboard {
+        root: from.root,
+        tree: new_tree,
+        data: vec![0u8; new_tree.outboard_size().try_into().unwrap()],
+    };
+    crate::io::fsm::convert_block_size(from, data.clone(), &mut to, block_size)
+        .await
+        .unwrap();
+    let expected = PostOrderMemOutboard::create(&data, block_size);
+    assert_eq!(to, expected);
+}
+
+#[test]
+fn convert_block_size_cases() {
+    let cases = [
+        (0, 0, 4),
+        (1024, 4, 0),
+        (4096, 0, 4),
+        (4096, 4, 0),
+        (0x10001, 0, 2),
+        (0x10001, 2, 0),
+        (100000, 1, 3),
+        (100000, 3, 1),
+    ];
+    for (size, from_level, to_level) in cases {
+        let tree = BaoTree::new(size, BlockSize(from_level));
+        convert_block_size_sync_impl(tree, BlockSize(to_level));
+        run_blocking(convert_block_size_fsm_impl(tree, BlockSize(to_level)));
+    }
+}
+
+/// Refining the block size must fail if the data does not match the outboard
+#[test]
+fn convert_block_size_corrupted() {
+    let cases = [(1024, 4, 0), (4096, 4, 0), (0x10001, 2, 0), (100000, 3, 1)];
+    for (size, from_level, to_level) in cases {
+        let data = make_test_data(size);
+        let from = PostOrderMemOutboard::create(&data, BlockSize(from_level));
+        let block_size = BlockSize(to_level);
+        let new_tree = BaoTree::new(size as u64, block_size);
+        for pos in [0, size / 2, size - 1] {
+            let mut corrupted = data.clone();
+            corrupted[pos] ^= 1;
+            let mut to = PreOrderMemOutboard {
+                root: from.root,
+                tree: new_tree,
+                data: vec![0u8; new_tree.outboard_size().try_into().unwrap()],
+            };
+            let res =
+                crate::io::sync::convert_block_size(&from, &corrupted[..], &mut to, block_size);
+            assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+            let res = run_blocking(crate::io::fsm::convert_block_size(
+                from.clone(),
+                Bytes::from(corrupted),
+                &mut to,
+                block_size,
+            ));
+            assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+}
+
+#[proptest]
+fn convert_block_size_sync_proptest(
+    #[strategy(tree())] tree: BaoTree,
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    convert_block_size_sync_impl(tree, block_size);
+}
+
+#[proptest]
+fn convert_block_size_fsm_proptest(
+    #[strategy(tree())] tree: BaoTree,
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    run_blocking(convert_block_size_fsm_impl(tree, block_size));
+}
+
+/// Check that the encoded size and offsets match the output of the encoders
+fn encoded_size_impl(size: usize, block_size: BlockSize, ranges: &ChunkRangesRef) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let tree = outboard.tree();
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data[..], &outboard, ranges, &mut encoded).unwrap();
+    let mut validated = Vec::new();
+    crate::io::sync::encode_ranges_validated(&data[..], &outboard, ranges, &mut validated).unwrap();
+    let mut fsm = Vec::new();
+    run_blocking(crate::io::fsm::encode_ranges(
+        Bytes::from(data.clone()),
+        outboard.clone(),
+        ranges,
+        &mut fsm,
+    ))
+    .unwrap();
+    assert_eq!(encoded, validated);
+    assert_eq!(encoded, fsm);
+    assert_eq!(tree.encoded_size(ranges), encoded.len() as u64);
+    let mut iter = tree.encoded_offsets_iter_ref(ranges);
+    for item in iter.by_ref() {
+        let start = item.encoded_offset as usize;
+        let end = start + item.chunk.size();
+        if let Some(data_offset) = item.data_offset {
+            let data_offset = data_offset as usize;
+            assert_eq!(
+                &encoded[start..end],
+                &data[data_offset..data_offset + item.chunk.size()]
+            );
+        }
+    }
+    assert_eq!(iter.encoded_offset(), encoded.len() as u64);
+    // the iterator truncates the ranges itself
+    let direct = crate::iter::EncodedOffsetsIterRef::new(tree, ranges).collect::<Vec<_>>();
+    assert_eq!(
+        direct,
+        tree.encoded_offsets_iter_ref(ranges).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn encoded_size_cases() {
+    let cases = [
+        (0, 0, ChunkRanges::all()),
+        (1024, 0, ChunkRanges::all()),
+        (100000, 4, ChunkRanges::from(ChunkNum(3)..ChunkNum(5))),
+        (100000, 2, ChunkRanges::from(ChunkNum(u64::MAX)..)),
+        (100000, 0, ChunkRanges::from(ChunkNum(10)..ChunkNum(200))),
+    ];
+    for (size, block_level, ranges) in cases {
+        encoded_size_impl(size, BlockSize(block_level), &ranges);
+    }
+}
+
+/// Encoding ranges that select only a part of a chunk group must include the
+/// hashes below the chunk group level, like [crate::io::sync::encode_ranges_validated],
+/// otherwise the response can not be decoded.
+#[test]
+fn encode_ranges_partial_chunk_group() {
+    let data = make_test_data(100000);
+    let block_size = BlockSize(4);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let ranges = ChunkRanges::from(ChunkNum(3)..ChunkNum(5));
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges(&data[..], &outboard, &ranges, &mut encoded).unwrap();
+    let mut fsm = Vec::new();
+    run_blocking(crate::io::fsm::encode_ranges(
+        Bytes::from(data.clone()),
+        outboard.clone(),
+        &ranges,
+        &mut fsm,
+    ))
+    .unwrap();
+    assert_eq!(encoded, fsm);
+    // the leaf is encoded with the hashes of the selected chunks, not as the
+    // full chunk group
+    let full_leaf = 64 * 2 + block_size.bytes();
+    assert!(encoded.len() < full_leaf, "{}", encoded.len());
+    let mut decoded = Vec::new();
+    for item in crate::io::sync::DecodeResponseIter::new(
+        outboard.root,
+        outboard.tree,
+        &encoded[..],
+        &ranges,
+    ) {
+        if let BaoContentItem::Leaf(Leaf { offset, data }) = item.unwrap() {
+            decoded.push((offset, data));
+        }
+    }
+    let start = ChunkNum(3).to_bytes() as usize;
+    let end = ChunkNum(5).to_bytes() as usize;
+    let decoded_bytes = decoded
+        .iter()
+        .flat_map(|(_, data)| data.iter().copied())
+        .collect::<Vec<_>>();
+    assert_eq!(decoded[0].0 as usize, start);
+    assert_eq!(&decoded_bytes[..], &data[start..end]);
+}
+
+#[proptest]
+fn encoded_size_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    encoded_size_impl(size, block_size, &ranges);
+}
+
+/// Encoding from an offset must produce the suffix of the full encoding
+fn encode_from_impl(size: usize, block_size: BlockSize, ranges: &ChunkRangesRef, offset: u64) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut full = Vec::new();
+    crate::io::sync::encode_ranges(&data[..], &outboard, ranges, &mut full).unwrap();
+    let offset = offset % (full.len() as u64 + 1);
+    let expected = &full[offset as usize..];
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges_from(&data[..], &outboard, ranges, offset, &mut encoded)
+        .unwrap();
+    assert_eq!(encoded, expected);
+    let mut validated = Vec::new();
+    crate::io::sync::encode_ranges_validated_from(
+        &data[..],
+        &outboard,
+        ranges,
+        offset,
+        &mut validated,
+    )
+    .unwrap();
+    assert_eq!(validated, expected);
+    let mut fsm = Vec::new();
+    run_blocking(crate::io::fsm::encode_ranges_from(
+        Bytes::from(data.clone()),
+        outboard.clone(),
+        ranges,
+        offset,
+        &mut fsm,
+    ))
+    .unwrap();
+    assert_eq!(fsm, expected);
+    let mut fsm_validated = Vec::new();
+    run_blocking(crate::io::fsm::encode_ranges_validated_from(
+        Bytes::from(data.clone()),
+        outboard.clone(),
+        ranges,
+        offset,
+        &mut fsm_validated,
+    ))
+    .unwrap();
+    assert_eq!(fsm_validated, expected);
+}
+
+#[test]
+fn encode_from_cases() {
+    let cases = [
+        (0, 0, ChunkRanges::all(), 0),
+        (1024, 0, ChunkRanges::all(), 100),
+        (100000, 4, ChunkRanges::from(ChunkNum(3)..ChunkNum(5)), 64),
+        (100000, 4, ChunkRanges::from(ChunkNum(3)..ChunkNum(5)), 1000),
+        (100000, 2, ChunkRanges::from(ChunkNum(u64::MAX)..), 70),
+        (
+            100000,
+            0,
+            ChunkRanges::from(ChunkNum(10)..ChunkNum(200)),
+            12345,
+        ),
+    ];
+    for (size, block_level, ranges, offset) in cases {
+        encode_from_impl(size, BlockSize(block_level), &ranges, offset);
+    }
+}
+
+#[proptest]
+fn encode_from_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+    offset: u64,
+) {
+    let (size, ranges) = size_and_selection;
+    encode_from_impl(size, block_size, &ranges, offset);
+}
+
+/// Decode the first `n` items, persist the state, and resume from the offset
+fn resume_sync_impl(
+    size: usize,
+    block_size: BlockSize,
+    mode: HashMode,
+    ranges: &ChunkRangesRef,
+    n: usize,
+) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create_with_mode(&data, block_size, mode);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges_validated(&data[..], &outboard, ranges, &mut encoded).unwrap();
+    let mut iter = crate::io::sync::DecodeResponseIter::new(
+        outboard.root,
+        outboard.tree,
+        std::io::Cursor::new(&encoded),
+        ranges,
+    );
+    let mut items = Vec::new();
+    for item in iter.by_ref().take(n) {
+        items.push(item.unwrap());
+    }
+    let state = crate::io::DecoderState::from_bytes(&iter.state().to_bytes(), mode).unwrap();
+    assert_eq!(state, iter.state());
+    let mut rest = Vec::new();
+    crate::io::sync::encode_ranges_validated_from(
+        &data[..],
+        &outboard,
+        ranges,
+        state.encoded_offset,
+        &mut rest,
+    )
+    .unwrap();
+    let iter =
+        crate::io::sync::DecodeResponseIter::resume(&state, std::io::Cursor::new(&rest)).unwrap();
+    for item in iter {
+        items.push(item.unwrap());
+    }
+    let expected = crate::io::sync::DecodeResponseIter::new(
+        outboard.root,
+        outboard.tree,
+        std::io::Cursor::new(&encoded),
+        ranges,
+    )
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+    assert_eq!(to_data(&items), to_data(&expected));
+}
+
+/// Decode the first `n` items, persist the state, and resume from the offset
+async fn resume_fsm_impl(
+    size: usize,
+    block_size: BlockSize,
+    mode: HashMode,
+    ranges: ChunkRanges,
+    n: usize,
+) {
+    let data = Bytes::from(make_test_data(size));
+    let outboard = PostOrderMemOutboard::create_with_mode(&data, block_size, mode);
+    let mut encoded = Vec::new();
+    crate::io::fsm::encode_ranges_validated(
+        data.clone(),
+        &mut outboard.clone(),
+        &ranges,
+        &mut encoded,
+    )
+    .await
+    .unwrap();
+    let mut decoder = crate::io::fsm::ResponseDecoder::new(
+        outboard.root,
+        ranges.clone(),
+        outboard.tree,
+        Bytes::from(encoded.clone()),
+    );
+    let mut items = Vec::new();
+    while items.len() < n {
+        match decoder.next().await {
+            ResponseDecoderNext::More((next, item)) => {
+                items.push(item.unwrap());
+                decoder = next;
+            }
+            ResponseDecoderNext::Done(_) => unreachable!(),
+        }
+    }
+    let state = crate::io::DecoderState::from_bytes(&decoder.state().to_bytes(), mode).unwrap();
+    assert_eq!(state, decoder.state());
+    let mut rest = Vec::new();
+    crate::io::fsm::encode_ranges_validated_from(
+        data.clone(),
+        &mut outboard.clone(),
+        &ranges,
+        state.encoded_offset,
+        &mut rest,
+    )
+    .await
+    .unwrap();
+    let mut decoder = crate::io::fsm::ResponseDecoder::resume(state, Bytes::from(rest)).unwrap();
+    while let ResponseDecoderNext::More((next, item)) = decoder.next().await {
+        items.push(item.unwrap());
+        decoder = next;
+    }
+    let expected = crate::io::sync::DecodeResponseIter::new(
+        outboard.root,
+        outboard.tree,
+        std::io::Cursor::new(&encoded),
+        &ranges,
+    )
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+    assert_eq!(to_data(&items), to_data(&expected));
+}
+
+/// The offsets and data of all leaves
+fn to_data(items: &[BaoContentItem]) -> Vec<(u64, Bytes)> {
+    items
+        .iter()
+        .filter_map(|item| match item {
+            BaoContentItem::Leaf(Leaf { offset, data }) => Some((*offset, data.clone())),
+            BaoContentItem::Parent(_) => None,
+        })
+        .collect()
+}
+
+#[test]
+fn resume_cases() {
+    let cases = [
+        (0, 0, ChunkRanges::all(), 0),
+        (1024, 0, ChunkRanges::all(), 1),
+        (100000, 4, ChunkRanges::from(ChunkNum(3)..ChunkNum(5)), 3),
+        (100000, 2, ChunkRanges::from(ChunkNum(u64::MAX)..), 2),
+        (
+            100000,
+            0,
+            ChunkRanges::from(ChunkNum(10)..ChunkNum(200)),
+            50,
+        ),
+    ];
+    let modes = [HashMode::DEFAULT, HashMode::keyed(&[3; 32])];
+    for (size, block_level, ranges, n) in cases {
+        for mode in modes {
+            let block_size = BlockSize(block_level);
+            resume_sync_impl(size, block_size, mode, &ranges, n);
+            run_blocking(resume_fsm_impl(size, block_size, mode, ranges.clone(), n));
+        }
+    }
+}
+
+#[proptest]
+fn resume_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+    n: usize,
+) {
+    let (size, ranges) = size_and_selection;
+    let tree = BaoTree::new(size as u64, block_size);
+    let count = ResponseIterRef::new(tree, crate::rec::truncate_ranges(&ranges, tree.size)).count();
+    let n = n % (count + 1);
+    resume_sync_impl(size, block_size, HashMode::DEFAULT, &ranges, n);
+    run_blocking(resume_fsm_impl(
+        size,
+        block_size,
+        HashMode::DEFAULT,
+        ranges,
+        n,
+    ));
+}
+
+#[test]
+fn resume_not_at_chunk_boundary() {
+    let state = crate::io::DecoderState {
+        tree: BaoTree::new(100000, BlockSize(0)),
+        ranges: ChunkRanges::all(),
+        encoded_offset: 65,
+        stack: Vec::new(),
+    };
+    let res = crate::io::sync::DecodeResponseIter::resume(&state, std::io::Cursor::new(&[]));
+    assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+}
+
+/// A serialized state with an invalid block size must be rejected
+#[test]
+fn decoder_state_invalid_block_size() {
+    let state = crate::io::DecoderState {
+        tree: BaoTree::new(100000, BlockSize(4)),
+        ranges: ChunkRanges::all(),
+        encoded_offset: 0,
+        stack: Vec::new(),
+    };
+    let mut bytes = state.to_bytes();
+    // the block size follows the u64 size
+    assert_eq!(bytes[8], 4);
+    bytes[8] = BlockSize::MAX_CHUNK_LOG;
+    assert!(crate::io::DecoderState::from_bytes(&bytes, HashMode::DEFAULT).is_ok());
+    for block_size in [BlockSize::MAX_CHUNK_LOG + 1, 200, u8::MAX] {
+        bytes[8] = block_size;
+        let res = crate::io::DecoderState::from_bytes(&bytes, HashMode::DEFAULT);
+        assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+}
+
+/// Resuming from a state with a stack that does not match the offset must
+/// fail instead of panicking later
+#[test]
+fn resume_invalid_stack() {
+    let data = make_test_data(100000);
+    let outboard = PostOrderMemOutboard::create(&data, BlockSize(0));
+    let ranges = ChunkRanges::all();
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges_validated(&data[..], &outboard, &ranges, &mut encoded).unwrap();
+    let mut iter = crate::io::sync::DecodeResponseIter::new(
+        outboard.root,
+        outboard.tree,
+        std::io::Cursor::new(&encoded),
+        &ranges,
+    );
+    for item in iter.by_ref().take(5) {
+        item.unwrap();
+    }
+    let mut cleared = iter.state();
+    cleared.stack.clear();
+    let mut extra = iter.state();
+    extra.stack.push(outboard.root);
+    for state in [cleared, extra] {
+        let rest = &encoded[state.encoded_offset as usize..];
+        let res = crate::io::sync::DecodeResponseIter::resume(&state, rest);
+        assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        let res =
+            crate::io::fsm::ResponseDecoder::resume(state.clone(), Bytes::copy_from_slice(rest));
+        assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+}
+
+/// Reads through a verified reader must return the data, and fail only for
+/// reads that touch the corrupted chunk group
+fn verified_reader_sync_impl(size: usize, block_size: BlockSize, reads: &[(u64, usize)]) {
+    use positioned_io::ReadAt;
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let reader = crate::io::verified::VerifiedReader::new(&data[..], &outboard);
+    for &(pos, len) in reads {
+        let mut buf = vec![0u8; len];
+        let n = reader.read_at(pos, &mut buf).unwrap();
+        let start = (pos as usize).min(size);
+        let end = (pos as usize).saturating_add(len).min(size);
+        assert_eq!(&buf[..n], &data[start..end]);
+    }
+    if size == 0 {
+        return;
+    }
+    let mut corrupted = data.clone();
+    corrupted[size / 2] ^= 1;
+    let reader = crate::io::verified::VerifiedReader::new(&corrupted[..], &outboard);
+    let group = outboard.tree.chunk_group_bytes();
+    let bad = (size / 2) / group * group;
+    for &(pos, len) in reads {
+        let mut buf = vec![0u8; len];
+        let touches = (pos as usize) < (bad + group).min(size)
+            && (pos as usize).saturating_add(len) > bad
+            && (pos as usize) < size
+            && len > 0;
+        let res = reader.read_at(pos, &mut buf);
+        if touches {
+            assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        } else {
+            let n = res.unwrap();
+            let start = (pos as usize).min(size);
+            let end = (pos as usize).saturating_add(len).min(size);
+            assert_eq!(&buf[..n], &corrupted[start..end]);
+        }
+    }
+}
+
+async fn verified_reader_fsm_impl(size: usize, block_size: BlockSize, reads: &[(u64, usize)]) {
+    use iroh_io::AsyncSliceReader;
+    let data = Bytes::from(make_test_data(size));
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut reader = crate::io::verified::VerifiedReader::new(data.clone(), outboard.clone());
+    for &(pos, len) in reads {
+        let res = reader.read_at(pos, len).await.unwrap();
+        let start = (pos as usize).min(size);
+        let end = (pos as usize).saturating_add(len).min(size);
+        assert_eq!(&res[..], &data[start..end]);
+    }
+    assert_eq!(reader.size().await.unwrap(), size as u64);
+    if size == 0 {
+        return;
+    }
+    let mut corrupted = data.to_vec();
+    corrupted[size / 2] ^= 1;
+    let mut reader =
+        crate::io::verified::VerifiedReader::new(Bytes::from(corrupted), outboard.clone());
+    let res = reader.read_at(size as u64 / 2, 1).await;
+    assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn verified_reader_cases() {
+    let reads = [
+        (0, 1),
+        (0, 1024),
+        (1000, 100),
+        (5000, 20000),
+        (0, 100000),
+        (99999, 10),
+        (200000, 10),
+        (0, 0),
+    ];
+    for size in [0, 1, 1024, 1025, 100000] {
+        for block_level in [0, 2, 4] {
+            verified_reader_sync_impl(size, BlockSize(block_level), &reads);
+            run_blocking(verified_reader_fsm_impl(
+                size,
+                BlockSize(block_level),
+                &reads,
+            ));
+        }
+    }
+}
+
+#[proptest]
+fn verified_reader_proptest(
+    #[strategy(0usize..100000)] size: usize,
+    #[strategy(block_size())] block_size: BlockSize,
+    #[strategy(proptest::collection::vec((0u64..110000, 0usize..20000), 1..10))] reads: Vec<(
+        u64,
+        usize,
+    )>,
+) {
+    verified_reader_sync_impl(size, block_size, &reads);
+    run_blocking(verified_reader_fsm_impl(size, block_size, &reads));
+}
+
+#[test]
+fn verified_reader_caches_verified_ranges() {
+    use positioned_io::ReadAt;
+    let data = make_test_data(100000);
+    let outboard = PostOrderMemOutboard::create(&data, BlockSize(4));
+    let reader = crate::io::verified::VerifiedReader::new(&data[..], &outboard);
+    let mut buf = [0u8; 10];
+    reader.read_at(20000, &mut buf).unwrap();
+    assert_eq!(
+        reader.verified(),
+        ChunkRanges::from(ChunkNum(16)..ChunkNum(32))
+    );
+}
+
+/// Data that records how many reads are in progress at the same time
+struct ConcurrencyTracker<'a> {
+    data: &'a [u8],
+    current: std::sync::atomic::AtomicUsize,
+    max: std::sync::atomic::AtomicUsize,
+}
+
+impl positioned_io::ReadAt for ConcurrencyTracker<'_> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        use std::sync::atomic::Ordering;
+        let current = self.current.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max.fetch_max(current, Ordering::SeqCst);
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let res = self.data.read_at(pos, buf);
+        self.current.fetch_sub(1, Ordering::SeqCst);
+        res
+    }
+}
+
+/// Concurrent reads from a verified reader must not wait for each other's io
+#[test]
+fn verified_reader_concurrent_reads() {
+    use positioned_io::ReadAt;
+    let data = make_test_data(100000);
+    let outboard = PostOrderMemOutboard::create(&data, BlockSize(4));
+    let tracker = ConcurrencyTracker {
+        data: &data,
+        current: Default::default(),
+        max: Default::default(),
+    };
+    let reader = crate::io::verified::VerifiedReader::new(&tracker, &outboard);
+    let barrier = std::sync::Barrier::new(2);
+    std::thread::scope(|scope| {
+        for pos in [0u64, 50000] {
+            let (reader, barrier, data) = (&reader, &barrier, &data);
+            scope.spawn(move || {
+                let mut buf = vec![0u8; 40000];
+                barrier.wait();
+                let n = reader.read_at(pos, &mut buf).unwrap();
+                assert_eq!(&buf[..n], &data[pos as usize..pos as usize + n]);
+            });
+        }
+    });
+    assert_eq!(tracker.max.load(std::sync::atomic::Ordering::SeqCst), 2);
+    assert_eq!(reader.verified(), ChunkRanges::from(..ChunkNum(96)));
+}
+
+/// Download a blob from several peers, one of which sends corrupted data.
+///
+/// Responses are decoded concurrently by polling the decoders round robin.
+async fn download_impl(size: usize, block_size: BlockSize, ranges: ChunkRanges, peers: usize) {
+    use crate::io::{download::DownloadCoordinator, fsm::ResponseDecoder};
+    let data = Bytes::from(make_test_data(size));
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let mut corrupted = data.to_vec();
+    corrupted.iter_mut().for_each(|x| *x ^= 1);
+    let corrupted = Bytes::from(corrupted);
+    let bad_peer = 1;
+    let mut coordinator = DownloadCoordinator::new(outboard.root, outboard.tree, &ranges);
+    let mut target = Vec::new();
+    let mut target_outboard = PostOrderMemOutboard {
+        root: outboard.root,
+        tree: outboard.tree,
+        data: vec![0u8; outboard.tree.outboard_size() as usize],
+    };
+    let mut active = std::collections::VecDeque::new();
+    while !coordinator.is_complete() {
+        for (peer, request) in coordinator.plan(0..peers) {
+            let source = if peer == bad_peer {
+                corrupted.clone()
+            } else {
+                data.clone()
+            };
+            let mut encoded = Vec::new();
+            crate::io::fsm::encode_ranges(source, outboard.clone(), &request, &mut encoded)
+                .await
+                .unwrap();
+            let decoder =
+                ResponseDecoder::new(outboard.root, request, outboard.tree, Bytes::from(encoded));
+            active.push_back((peer, decoder));
+        }
+        let (peer, decoder) = active.pop_front().expect("no peers left");
+        match decoder.next().await {
+            ResponseDecoderNext::More((decoder, Ok(item))) => {
+                coordinator
+                    .write(&peer, item, &mut target, &mut target_outboard)
+                    .await
+                    .unwrap();
+                active.push_back((peer, decoder));
+            }
+            ResponseDecoderNext::More((_, Err(cause))) => coordinator.failed(&peer, &cause),
+            ResponseDecoderNext::Done(_) => coordinator.finished(&peer),
+        }
+    }
+    for range in coordinator.received_ranges().iter() {
+        let RangeSetRange::Range(range) = range else {
+            unreachable!();
+        };
+        let start = range.start.to_bytes() as usize;
+        let end = (range.end.to_bytes() as usize).min(size);
+        assert_eq!(&target[start..end], &data[start..end]);
+    }
+    let wanted = crate::rec::truncate_ranges(&ranges, outboard.tree.size);
+    let wanted = ChunkRanges::new_unchecked(wanted.boundaries().into())
+        & ChunkRanges::from(..outboard.tree.chunks().max(ChunkNum(1)));
+    assert!(wanted.is_subset(coordinator.received_ranges()));
+}
+
+#[test]
+fn download_cases() {
+    let cases = [
+        (0, 0, ChunkRanges::all(), 2),
+        (1, 0, ChunkRanges::all(), 3),
+        (100000, 0, ChunkRanges::all(), 3),
+        (100000, 2, ChunkRanges::from(ChunkNum(3)..ChunkNum(50)), 4),
+        (100000, 4, ChunkRanges::all(), 5),
+        (100000, 4, ChunkRanges::from(ChunkNum(u64::MAX)..), 2),
+    ];
+    for (size, block_level, ranges, peers) in cases {
+        run_blocking(download_impl(size, BlockSize(block_level), ranges, peers));
+    }
+}
+
+#[test]
+fn download_bans_bad_peer() {
+    use crate::io::download::DownloadCoordinator;
+    let tree = BaoTree::new(100000, BlockSize(2));
+    let mut coordinator = DownloadCoordinator::new(blake3::hash(&[]), tree, &ChunkRanges::all());
+    let requests = coordinator.plan([1, 2]);
+    assert_eq!(requests.len(), 2);
+    assert!(coordinator.missing().is_empty());
+    // an io error does not ban the peer
+    let eof = crate::io::DecodeError::LeafNotFound(ChunkNum(0));
+    coordinator.failed(&1, &eof);
+    assert!(!coordinator.is_banned(&1));
+    assert_eq!(coordinator.missing(), requests[0].1);
+    // a hash mismatch does
+    coordinator.failed(&2, &crate::io::DecodeError::LeafHashMismatch(ChunkNum(0)));
+    assert!(coordinator.is_banned(&2));
+    let requests = coordinator.plan([1, 2]);
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].0, 1);
+    assert_eq!(requests[0].1, ChunkRanges::from(..tree.chunks()));
+}
+
+/// Download a blob into a partial blob in two steps, reopening the partial
+/// blob from its stored ranges in between.
+fn partial_blob_impl(size: usize, block_size: BlockSize, ranges: ChunkRanges) {
+    use crate::io::partial::{FileRangesStore, PartialBlob};
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    let dir = tempfile::tempdir().unwrap();
+    let store = FileRangesStore::new(dir.path().join("ranges"));
+    let target_outboard = PostOrderMemOutboard {
+        root: outboard.root,
+        tree: outboard.tree,
+        data: vec![0u8; outboard.tree.outboard_size() as usize],
+    };
+    let mut blob = PartialBlob::new(Vec::new(), target_outboard, store.clone()).unwrap();
+    assert!(blob.available().is_empty());
+    let mut encoded = Vec::new();
+    if !ranges.is_empty() {
+        crate::io::sync::encode_ranges_validated(&data[..], &outboard, &ranges, &mut encoded)
+            .unwrap();
+    }
+    blob.decode(&encoded[..], &ranges).unwrap();
+    let wanted = crate::rec::truncate_ranges(&ranges, outboard.tree.size);
+    let wanted = ChunkRanges::new_unchecked(wanted.boundaries().into())
+        & ChunkRanges::from(..outboard.tree.chunks());
+    assert!(wanted.is_subset(blob.available()));
+    // reopen from the stored ranges
+    let (target, target_outboard, _) = blob.into_inner();
+    let mut blob = PartialBlob::new(target, target_outboard, store).unwrap();
+    assert!(wanted.is_subset(blob.available()));
+    let missing = blob.missing();
+    let blob = if missing.is_empty() {
+        blob
+    } else {
+        let mut encoded = Vec::new();
+        crate::io::sync::encode_ranges_validated(&data[..], &outboard, &missing, &mut encoded)
+            .unwrap();
+        blob.decode(&encoded[..], &missing).unwrap();
+        blob
+    };
+    let (target, target_outboard) = blob.into_complete().expect("blob is complete");
+    assert_eq!(target, data);
+    assert_eq!(target_outboard, outboard);
+}
+
+#[test]
+fn partial_blob_cases() {
+    let cases = [
+        (0, 0, ChunkRanges::all()),
+        (1, 0, ChunkRanges::empty()),
+        (1025, 0, ChunkRanges::from(..ChunkNum(1))),
+        (100000, 0, ChunkRanges::from(ChunkNum(10)..ChunkNum(20))),
+        (100000, 2, ChunkRanges::from(ChunkNum(3)..ChunkNum(50))),
+        (100000, 4, ChunkRanges::from(ChunkNum(u64::MAX)..)),
+    ];
+    for (size, block_level, ranges) in cases {
+        partial_blob_impl(size, BlockSize(block_level), ranges);
+    }
+}
+
+#[test]
+fn partial_blob_incomplete() {
+    use crate::io::partial::{MemRangesStore, PartialBlob};
+    let data = make_test_data(100000);
+    let outboard = PostOrderMemOutboard::create(&data, BlockSize(4));
+    let ranges = ChunkRanges::from(..ChunkNum(16));
+    let target_outboard = PostOrderMemOutboard {
+        root: outboard.root,
+        tree: outboard.tree,
+        data: vec![0u8; outboard.tree.outboard_size() as usize],
+    };
+    let mut blob =
+        PartialBlob::new(Vec::new(), target_outboard, MemRangesStore::default()).unwrap();
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges_validated(&data[..], &outboard, &ranges, &mut encoded).unwrap();
+    blob.decode(&encoded[..], &ranges).unwrap();
+    let blob = blob.into_complete().expect_err("blob is not complete");
+    let (_, _, store) = blob.into_inner();
+    assert_eq!(store.ranges(), ranges.as_ref());
+}
+
+/// A [RangesStore](crate::io::partial::RangesStore) that counts how often it was written
+#[derive(Default)]
+struct CountingRangesStore {
+    inner: crate::io::partial::MemRangesStore,
+    stores: usize,
+}
+
+impl crate::io::partial::RangesStore for CountingRangesStore {
+    fn load(&mut self) -> std::io::Result<ChunkRanges> {
+        self.inner.load()
+    }
+
+    fn store(&mut self, ranges: &ChunkRangesRef) -> std::io::Result<()> {
+        self.stores += 1;
+        self.inner.store(ranges)
+    }
+}
+
+/// The ranges are stored once per response, and leaves that were written
+/// before decoding failed are still stored.
+#[test]
+fn partial_blob_persist_once() {
+    use crate::io::partial::PartialBlob;
+    let data = make_test_data(100000);
+    let outboard = PostOrderMemOutboard::create(&data, BlockSize(0));
+    let ranges = ChunkRanges::from(..ChunkNum(32));
+    let target_outboard = || PostOrderMemOutboard {
+        root: outboard.root,
+        tree: outboard.tree,
+        data: vec![0u8; outboard.tree.outboard_size() as usize],
+    };
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges_validated(&data[..], &outboard, &ranges, &mut encoded).unwrap();
+    let mut blob = PartialBlob::new(
+        Vec::new(),
+        target_outboard(),
+        CountingRangesStore::default(),
+    )
+    .unwrap();
+    blob.decode(&encoded[..], &ranges).unwrap();
+    let (_, _, store) = blob.into_inner();
+    assert_eq!(store.stores, 1);
+    assert_eq!(store.inner.ranges(), ranges.as_ref());
+    // a truncated response stores what was decoded before the error
+    let mut blob = PartialBlob::new(
+        Vec::new(),
+        target_outboard(),
+        CountingRangesStore::default(),
+    )
+    .unwrap();
+    assert!(blob.decode(&encoded[..encoded.len() / 2], &ranges).is_err());
+    let (_, _, store) = blob.into_inner();
+    assert_eq!(store.stores, 1);
+    let stored = store.inner.ranges();
+    assert!(!stored.is_empty());
+    assert!(ranges.is_superset(stored));
+}
+
+/// Encode from data of which only the `available` chunks are present, and
+/// check that the response decodes to the original data for the served ranges.
+fn encode_available_impl(
+    size: usize,
+    block_size: BlockSize,
+    ranges: &ChunkRanges,
+    available: &ChunkRanges,
+) {
+    let data = make_test_data(size);
+    let outboard = PostOrderMemOutboard::create(&data, block_size);
+    // corrupt everything that is not available, so we notice if it is used
+    let partial = data
+        .iter()
+        .enumerate()
+        .map(|(i, x)| {
+            if available.contains(&ChunkNum((i / 1024) as u64)) {
+                *x
+            } else {
+                !*x
+            }
+        })
+        .collect::<Vec<_>>();
+    // reference: the requested ranges within fully available chunk groups
+    let tree = outboard.tree;
+    let requested = truncate_ranges(ranges, tree.size);
+    let requested = ChunkRanges::new_unchecked(requested.boundaries().into());
+    let mut expected = ChunkRanges::empty();
+    let mut start = ChunkNum(0);
+    while start < tree.chunks() || start == ChunkNum(0) {
+        let end = (start + tree.chunk_group_chunks()).min(tree.chunks());
+        let group = ChunkRanges::from(start..end.max(ChunkNum(1)));
+        if ChunkRanges::from(start..end).is_subset(available) {
+            expected |= &requested & &group;
+        }
+        start = start + tree.chunk_group_chunks();
+    }
+    let mut encoded = Vec::new();
+    let served = crate::io::sync::encode_ranges_available(
+        &partial[..],
+        &outboard,
+        ranges,
+        available,
+        &mut encoded,
+    )
+    .unwrap();
+    assert_eq!(served, expected);
+    let mut encoded_fsm = Vec::new();
+    let served_fsm = run_blocking(crate::io::fsm::encode_ranges_available(
+        Bytes::from(partial),
+        outboard.clone(),
+        ranges,
+        available,
+        &mut encoded_fsm,
+    ))
+    .unwrap();
+    assert_eq!(served_fsm, served);
+    assert_eq!(encoded_fsm, encoded);
+    if served.is_empty() {
+        assert!(encoded.is_empty());
+        return;
+    }
+    let iter = crate::io::sync::DecodeResponseIter::new(outboard.root, tree, &encoded[..], &served);
+    for item in iter {
+        if let BaoContentItem::Leaf(Leaf { offset, data: leaf }) = item.unwrap() {
+            let offset = offset as usize;
+            assert_eq!(&leaf[..], &data[offset..offset + leaf.len()]);
+        }
+    }
+}
+
+#[test]
+fn encode_available_cases() {
+    let cases = [
+        (0, 0, ChunkRanges::all(), ChunkRanges::empty()),
+        (1, 0, ChunkRanges::all(), ChunkRanges::empty()),
+        (1, 0, ChunkRanges::all(), ChunkRanges::from(..ChunkNum(1))),
+        (
+            100000,
+            0,
+            ChunkRanges::all(),
+            ChunkRanges::from(ChunkNum(10)..ChunkNum(20)),
+        ),
+        (
+            100000,
+            2,
+            ChunkRanges::all(),
+            ChunkRanges::from(ChunkNum(3)..ChunkNum(50)),
+        ),
+        (
+            100000,
+            2,
+            ChunkRanges::from(ChunkNum(5)..ChunkNum(6)),
+            ChunkRanges::from(ChunkNum(4)..ChunkNum(8)),
+        ),
+        (
+            100000,
+            4,
+            ChunkRanges::from(ChunkNum(u64::MAX)..),
+            ChunkRanges::from(ChunkNum(96)..),
+        ),
+        (
+            100000,
+            4,
+            ChunkRanges::from(ChunkNum(u64::MAX)..),
+            ChunkRanges::from(ChunkNum(97)..),
+        ),
+    ];
+    for (size, block_level, ranges, available) in cases {
+        encode_available_impl(size, BlockSize(block_level), &ranges, &available);
+    }
+}
+
+#[proptest]
+fn encode_available_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+    #[strategy(selection(100000, 3))] available: ChunkRanges,
+) {
+    let (size, ranges) = size_and_selection;
+    encode_available_impl(size, block_size, &ranges, &available);
+}
+
+/// An outboard that has no hash pairs at all
+struct NoHashesOutboard(PostOrderMemOutboard);
+
+impl crate::io::sync::Outboard for NoHashesOutboard {
+    fn root(&self) -> blake3::Hash {
+        self.0.root
+    }
+    fn tree(&self) -> BaoTree {
+        self.0.tree
+    }
+    fn load(&self, _node: TreeNode) -> std::io::Result<Option<(blake3::Hash, blake3::Hash)>> {
+        Ok(None)
+    }
+}
+
+#[test]
+fn encode_missing_parent() {
+    let data = make_test_data(100000);
+    let outboard = NoHashesOutboard(PostOrderMemOutboard::create(&data, BlockSize(4)));
+    let res = crate::io::sync::encode_ranges(&data[..], &outboard, &ChunkRanges::all(), Vec::new());
+    assert!(matches!(
+        res,
+        Err(crate::io::EncodeError::ParentNotFound(node)) if node == outboard.0.tree.root()
+    ));
+}
+
+/// Encode and decode a hash sequence request, and check that all content
+/// matches the original blobs.
+fn hash_seq_impl(
+    sizes: &[usize],
+    block_size: BlockSize,
+    mode: HashMode,
+    request_children: &[(u64, ChunkRanges)],
+) {
+    use crate::io::hashseq::{
+        encode_hash_seq, HashSeq, HashSeqItem, HashSeqRequest, HashSeqResponseDecoder,
+    };
+    let mut blobs = std::collections::HashMap::new();
+    let mut add = |data: Bytes| {
+        let outboard = PostOrderMemOutboard::create_with_mode(&data, block_size, mode);
+        let hash = outboard.root;
+        blobs.insert(hash, (data, outboard));
+        hash
+    };
+    let children = sizes
+        .iter()
+        .enumerate()
+        .map(|(i, size)| {
+            let mut data = make_test_data(*size);
+            data.iter_mut().for_each(|x| *x ^= i as u8);
+            add(data.into())
+        })
+        .collect::<Vec<_>>();
+    let seq = children.iter().copied().collect::<HashSeq>();
+    assert_eq!(seq.len(), children.len());
+    assert_eq!(seq.iter().collect::<Vec<_>>(), children);
+    let root = add(seq.as_bytes().clone());
+    assert_eq!(root == seq.hash(), mode.is_default());
+    assert_eq!(root, seq.hash_with_mode(mode));
+    let mut request = HashSeqRequest::new(root, block_size).with_mode(mode);
+    for (index, ranges) in request_children {
+        request = request.with_child(*index, ranges.clone());
+    }
+    // send the request over the wire
+    let request = HashSeqRequest::from_bytes(
+        &request.to_bytes(),
+        mode,
+        crate::io::ranges::DEFAULT_MAX_RANGES,
+    )
+    .unwrap();
+    let mut encoded = Vec::new();
+    run_blocking(encode_hash_seq(
+        &request,
+        |hash| {
+            blobs
+                .get(hash)
+                .cloned()
+                .ok_or_else(|| std::io::ErrorKind::NotFound.into())
+        },
+        &mut encoded,
+    ))
+    .unwrap();
+    let mut decoder = HashSeqResponseDecoder::new(request.clone(), Bytes::from(encoded));
+    let mut started = Vec::new();
+    let mut current = None;
+    while let Some(item) = run_blocking(decoder.next()) {
+        match item.unwrap() {
+            HashSeqItem::Start { child, hash, size } => {
+                let (data, _) = &blobs[&hash];
+                assert_eq!(size, data.len() as u64);
+                if let Some(child) = child {
+                    assert_eq!(seq.get(child as usize), Some(hash));
+                }
+                started.push(child);
+                current = Some(data.clone());
+            }
+            HashSeqItem::Content { item, .. } => {
+                if let BaoContentItem::Leaf(Leaf { offset, data }) = item {
+                    let expected = current.as_ref().unwrap();
+                    let offset = offset as usize;
+                    assert_eq!(&data[..], &expected[offset..offset + data.len()]);
+                }
+            }
+        }
+    }
+    assert!(decoder.finish().unwrap().is_empty());
+    let mut expected = Vec::new();
+    if !request.seq_ranges().is_empty() {
+        expected.push(None);
+    }
+    for (index, _) in request.children() {
+        if index < seq.len() as u64 {
+            expected.push(Some(index));
+        }
+    }
+    assert_eq!(started, expected);
+}
+
+#[test]
+fn hash_seq_cases() {
+    let sizes = [0, 1, 1024, 5000, 100000];
+    let cases = [
+        (&sizes[..], 0, vec![]),
+        (&sizes[..], 0, vec![(0, ChunkRanges::all())]),
+        (
+            &sizes[..],
+            2,
+            vec![
+                (1, ChunkRanges::all()),
+                (3, ChunkRanges::from(ChunkNum(1)..ChunkNum(3))),
+                (4, ChunkRanges::from(ChunkNum(u64::MAX)..)),
+                (5, ChunkRanges::all()),
+            ],
+        ),
+        (&[][..], 4, vec![(0, ChunkRanges::all())]),
+    ];
+    let modes = [HashMode::DEFAULT, HashMode::keyed(&[5; 32])];
+    for (sizes, block_level, request_children) in cases {
+        for mode in modes {
+            hash_seq_impl(sizes, BlockSize(block_level), mode, &request_children);
+        }
+    }
+}
+
+#[test]
+fn hash_seq_many_children() {
+    let sizes = (0..100).collect::<Vec<_>>();
+    let request_children = [
+        (0, ChunkRanges::all()),
+        (31, ChunkRanges::all()),
+        (32, ChunkRanges::all()),
+        (99, ChunkRanges::all()),
+    ];
+    hash_seq_impl(&sizes, BlockSize(0), HashMode::DEFAULT, &request_children);
+}
+
+#[test]
+fn hash_seq_corrupted() {
+    use crate::io::hashseq::{encode_hash_seq, HashSeq, HashSeqRequest, HashSeqResponseDecoder};
+    let data = Bytes::from(make_test_data(5000));
+    let outboard = PostOrderMemOutboard::create(&data, BlockSize(0));
+    let seq = [outboard.root].into_iter().collect::<HashSeq>();
+    let seq_outboard = PostOrderMemOutboard::create(seq.as_bytes(), BlockSize(0));
+    let request = HashSeqRequest::new(seq.hash(), BlockSize(0)).with_child(0, ChunkRanges::all());
+    let mut encoded = Vec::new();
+    run_blocking(encode_hash_seq(
+        &request,
+        |hash| {
+            Ok(if *hash == seq.hash() {
+                (seq.as_bytes().clone(), seq_outboard.clone())
+            } else {
+                (data.clone(), outboard.clone())
+            })
+        },
+        &mut encoded,
+    ))
+    .unwrap();
+    *encoded.last_mut().unwrap() ^= 1;
+    let mut decoder = HashSeqResponseDecoder::new(request, Bytes::from(encoded));
+    let mut error = None;
+    while let Some(item) = run_blocking(decoder.next()) {
+        if let Err(cause) = item {
+            error = Some(cause);
+        }
+    }
+    assert!(matches!(
+        error,
+        Some(crate::io::DecodeError::LeafHashMismatch(_))
+    ));
+    assert!(decoder.finish().is_none());
+}
+
+/// A sender that claims a smaller hash sequence must not be able to skip
+/// requested children without an error
+#[test]
+fn hash_seq_under_reported_size() {
+    use crate::io::hashseq::{encode_hash_seq, HashSeq, HashSeqRequest, HashSeqResponseDecoder};
+    let data = Bytes::from(make_test_data(5000));
+    let outboard = PostOrderMemOutboard::create(&data, BlockSize(0));
+    let seq = std::iter::repeat(outboard.root)
+        .take(40)
+        .collect::<HashSeq>();
+    let seq_outboard = PostOrderMemOutboard::create(seq.as_bytes(), BlockSize(0));
+    let request = HashSeqRequest::new(seq.hash(), BlockSize(0)).with_child(35, ChunkRanges::all());
+    let mut encoded = Vec::new();
+    run_blocking(encode_hash_seq(
+        &request,
+        |hash| {
+            Ok(if *hash == seq.hash() {
+                (seq.as_bytes().clone(), seq_outboard.clone())
+            } else {
+                (data.clone(), outboard.clone())
+            })
+        },
+        &mut encoded,
+    ))
+    .unwrap();
+    // claim that the hash sequence only has 3 hashes
+    encoded[..8].copy_from_slice(&96u64.to_le_bytes());
+    let mut decoder = HashSeqResponseDecoder::new(request, Bytes::from(encoded));
+    let mut error = None;
+    while let Some(item) = run_blocking(decoder.next()) {
+        if let Err(cause) = item {
+            error = Some(cause);
+        }
+    }
+    assert!(error.is_some());
+    assert!(decoder.finish().is_none());
+}
+
+#[test]
+fn hash_seq_request_encoding() {
+    use crate::io::hashseq::HashSeqRequest;
+    let key = [7u8; 32];
+    let mode = HashMode::keyed(&key);
+    let request = HashSeqRequest::new(blake3::hash(b"seq"), BlockSize(4))
+        .with_mode(mode)
+        .with_seq_ranges(ChunkRanges::from(ChunkNum(10)..ChunkNum(12)))
+        .with_child(0, ChunkRanges::all())
+        .with_child(3, ChunkRanges::from(ChunkNum(5)..))
+        .with_child(1000, ChunkRanges::from(..ChunkNum(1)));
+    let bytes = request.to_bytes();
+    assert_eq!(
+        HashSeqRequest::from_bytes(&bytes, mode, 6).unwrap(),
+        request
+    );
+    // the key is not part of the encoding
+    assert!(!bytes.windows(32).any(|x| x == key));
+    let other = HashSeqRequest::from_bytes(&bytes, HashMode::keyed(&[8u8; 32]), 6).unwrap();
+    assert_ne!(other, request);
+    // wrong kind of mode
+    assert!(HashSeqRequest::from_bytes(&bytes, HashMode::DEFAULT, 6).is_err());
+    // too many ranges
+    assert!(HashSeqRequest::from_bytes(&bytes, mode, 5).is_err());
+    // truncated or trailing data
+    for i in 0..bytes.len() {
+        assert!(HashSeqRequest::from_bytes(&bytes[..i], mode, 6).is_err());
+    }
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert!(HashSeqRequest::from_bytes(&trailing, mode, 6).is_err());
+    // invalid block size
+    let mut invalid = bytes.clone();
+    invalid[32] = BlockSize::MAX_CHUNK_LOG + 1;
+    assert!(HashSeqRequest::from_bytes(&invalid, mode, 6).is_err());
+    // a child whose hash is not requested
+    let mut invalid = bytes[..34].to_vec();
+    crate::io::ranges::write_chunk_ranges(&ChunkRanges::empty(), &mut invalid);
+    invalid.extend_from_slice(&[1, 0]);
+    crate::io::ranges::write_chunk_ranges(&ChunkRanges::all(), &mut invalid);
+    assert!(HashSeqRequest::from_bytes(&invalid, mode, 6).is_err());
+}
+
+#[test]
+fn hash_seq_invalid_size() {
+    assert!(crate::io::hashseq::HashSeq::new(Bytes::from(vec![0u8; 31])).is_none());
+    assert!(crate::io::hashseq::HashSeq::new(Bytes::from(vec![0u8; 64])).is_some());
+}
+
+fn obao_impl(size: usize, block_size: BlockSize) {
+    use crate::io::{outboard::PostOrderOutboard, sync::outboard_post_order_with_suffix};
+    let data = make_test_data(size);
+    let expected = PostOrderMemOutboard::create(&data, block_size);
+    let tree = BaoTree::new(size as u64, block_size);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("test.obao");
+    let mut file = std::fs::File::create(&path).unwrap();
+    let root = outboard_post_order_with_suffix(&data[..], tree, &mut file).unwrap();
+    drop(file);
+    assert_eq!(root, expected.root);
+    let obao = std::fs::read(&path).unwrap();
+    assert_eq!(obao, expected.clone().into_inner_with_suffix());
+    // open with a known root hash
+    let file = std::fs::File::open(&path).unwrap();
+    let outboard =
+        PostOrderOutboard::open_with_suffix(file, block_size, HashMode::DEFAULT, root).unwrap();
+    assert_eq!(outboard.tree, tree);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges_validated(&data, &outboard, &ChunkRanges::all(), &mut encoded)
+        .unwrap();
+    // open and compute the root hash
+    let file = std::fs::File::open(&path).unwrap();
+    let outboard = PostOrderOutboard::open_with_suffix_compute_root(
+        file,
+        block_size,
+        HashMode::DEFAULT,
+        &data[..],
+    )
+    .unwrap();
+    assert_eq!(outboard.root, root);
+    // a truncated outboard or a wrong size must be detected
+    let mut truncated = obao.clone();
+    truncated.remove(0);
+    assert!(
+        PostOrderOutboard::open_with_suffix(truncated, block_size, HashMode::DEFAULT, root)
+            .is_err()
+    );
+    let mut wrong_size = obao.clone();
+    let n = wrong_size.len();
+    wrong_size[n - 8..].copy_from_slice(&(size as u64 + (16 << 20)).to_le_bytes());
+    assert!(
+        PostOrderOutboard::open_with_suffix(wrong_size, block_size, HashMode::DEFAULT, root)
+            .is_err()
+    );
+    assert!(PostOrderOutboard::open_with_suffix(
+        obao[..7].to_vec(),
+        block_size,
+        HashMode::DEFAULT,
+        root,
+    )
+    .is_err());
+}
+
+#[test]
+fn obao_cases() {
+    let cases = [
+        (0, 0),
+        (1024, 0),
+        (1025, 0),
+        (16384, 4),
+        (16385, 4),
+        (100000, 0),
+        (100000, 4),
+    ];
+    for (size, block_size) in cases {
+        obao_impl(size, BlockSize(block_size));
+    }
+}
+
+fn header_outboard_impl(size: usize, block_size: BlockSize, mode: HashMode, order: OutboardOrder) {
+    use crate::io::{
+        outboard::{Header, HEADER_SIZE},
+        sync::{create_with_header, open_with_header, Outboard},
+    };
+    let data = make_test_data(size);
+    let expected = PostOrderMemOutboard::create_with_mode(&data, block_size, mode);
+    // existing data in the target is removed
+    let target = vec![0xffu8; 10000];
+    let outboard =
+        create_with_header(&data[..], size as u64, block_size, mode, order, target).unwrap();
+    assert_eq!(outboard.root(), expected.root);
+    let bytes = outboard.into_inner();
+    assert_eq!(
+        bytes.len() as u64,
+        HEADER_SIZE as u64 + expected.tree.outboard_size()
+    );
+    // the header describes the outboard
+    let outboard = open_with_header(&bytes[..], mode).unwrap();
+    let header = outboard.header();
+    assert_eq!(header.order, order);
+    assert_eq!(header.tree, expected.tree);
+    assert_eq!(header.root, expected.root);
+    assert_eq!(
+        Header::from_bytes(&header.to_bytes().unwrap(), mode).unwrap(),
+        header
+    );
+    // opening with a different kind of hash mode is a clear error
+    let other_modes = [
+        HashMode::DEFAULT,
+        HashMode::keyed(&[1; 32]),
+        HashMode::derive_key("header test"),
+    ];
+    for other in other_modes {
+        if other.tag() != mode.tag() {
+            let err = open_with_header(&bytes[..], other).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+    outboard_test_sync(&data, &outboard);
+    let mut encoded = Vec::new();
+    crate::io::sync::encode_ranges_validated(&data, &outboard, &ChunkRanges::all(), &mut encoded)
+        .unwrap();
+    // async create and open produce the same outboard
+    let fsm_bytes = run_blocking(async {
+        let outboard = crate::io::fsm::create_with_header(
+            Bytes::from(data.clone()),
+            size as u64,
+            block_size,
+            mode,
+            order,
+            BytesMut::new(),
+        )
+        .await
+        .unwrap();
+        outboard.into_inner().freeze()
+    });
+    assert_eq!(&fsm_bytes[..], &bytes[..]);
+    run_blocking(async {
+        let outboard = crate::io::fsm::open_with_header(fsm_bytes, mode)
+            .await
+            .unwrap();
+        assert_eq!(outboard.header(), header);
+        outboard_test_fsm(&data, outboard).await;
+    });
+    // corrupt headers and outboards of the wrong size must be detected
+    let mut corrupt_magic = bytes.clone();
+    corrupt_magic[0] ^= 1;
+    let mut corrupt_version = bytes.clone();
+    corrupt_version[7] = 2;
+    let mut corrupt_size = bytes.clone();
+    corrupt_size[16] ^= 1;
+    let mut corrupt_root = bytes.clone();
+    corrupt_root[24] ^= 1;
+    let mut too_long = bytes.clone();
+    too_long.extend_from_slice(&[0u8; 64]);
+    let cases = [
+        corrupt_magic,
+        corrupt_version,
+        corrupt_size,
+        corrupt_root,
+        too_long,
+        bytes[..HEADER_SIZE - 1].to_vec(),
+    ];
+    for case in cases {
+        let err = open_with_header(&case[..], mode).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let err =
+            run_blocking(crate::io::fsm::open_with_header(Bytes::from(case), mode)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+    if expected.tree.outboard_size() > 0 {
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(open_with_header(truncated, mode).is_err());
+    }
+}
+
+#[test]
+fn header_outboard_cases() {
+    let cases = [
+        (0, 0),
+        (1024, 0),
+        (1025, 0),
+        (16384, 4),
+        (16385, 4),
+        (100000, 0),
+        (100000, 4),
+    ];
+    let modes = [HashMode::DEFAULT, HashMode::keyed(&[7; 32])];
+    for (size, block_size) in cases {
+        for mode in modes {
+            for order in [OutboardOrder::PreOrder, OutboardOrder::PostOrder] {
+                header_outboard_impl(size, BlockSize(block_size), mode, order);
+            }
+        }
+    }
+}
+
+/// Headers can only be written for block sizes that can be read back
+#[test]
+fn header_block_size_limit() {
+    use crate::io::outboard::Header;
+    let header = |chunk_log| Header {
+        order: OutboardOrder::PreOrder,
+        tree: BaoTree::new(u64::MAX, BlockSize(chunk_log)),
+        root: blake3::hash(b"root"),
+    };
+    let max = header(BlockSize::MAX_CHUNK_LOG);
+    let bytes = max.to_bytes().unwrap();
+    assert_eq!(Header::from_bytes(&bytes, HashMode::DEFAULT).unwrap(), max);
+    let err = header(BlockSize::MAX_CHUNK_LOG + 1).to_bytes().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+fn bao_compat_impl(size: usize) {
+    use crate::io::compat;
+    use std::io::Read;
+    let data = make_test_data(size);
+    let (expected_encoded, expected_hash) = bao::encode::encode(&data);
+    let (expected_outboard, _) = bao::encode::outboard(&data);
+    let expected_hash = blake3::Hash::from(*expected_hash.as_bytes());
+    // combined encoding and outboard
+    let (encoded, hash) = compat::encode(&data);
+    assert_eq!(hash, expected_hash);
+    assert_eq!(encoded, expected_encoded);
+    let (outboard, hash) = compat::outboard(&data);
+    assert_eq!(hash, expected_hash);
+    assert_eq!(outboard, expected_outboard);
+    // decode an encoding produced by bao, and re-encode it from the outboard
+    let mut decoded = Vec::new();
+    let decoded_outboard = compat::decode(&expected_encoded[..], hash, &mut decoded).unwrap();
+    assert_eq!(decoded, data);
+    assert_eq!(decoded_outboard.data, expected_outboard[8..]);
+    let outboard = compat::open_outboard(&expected_outboard[..], hash).unwrap();
+    let mut encoded = Vec::new();
+    compat::encode_with_outboard(&data[..], &outboard, &mut encoded).unwrap();
+    assert_eq!(encoded, expected_encoded);
+    // slices, including empty ones and ones past the end
+    let size = size as u64;
+    let slices = [
+        (0, 0),
+        (0, 1),
+        (0, size),
+        (1000, 2000),
+        (size / 2, size),
+        (size, 0),
+        (size, 1),
+        (size + 5000, 1000),
+    ];
+    for (start, len) in slices {
+        let mut expected_slice = Vec::new();
+        bao::encode::SliceExtractor::new_outboard(
+            std::io::Cursor::new(&data),
+            std::io::Cursor::new(&expected_outboard),
+            start,
+            len,
+        )
+        .read_to_end(&mut expected_slice)
+        .unwrap();
+        let mut slice = Vec::new();
+        compat::extract_slice(&data[..], &outboard, start, len, &mut slice).unwrap();
+        assert_eq!(slice, expected_slice, "slice {} {}", start, len);
+        let bao_hash = bao::Hash::from(*hash.as_bytes());
+        let mut expected_content = Vec::new();
+        bao::decode::SliceDecoder::new(&expected_slice[..], &bao_hash, start, len)
+            .read_to_end(&mut expected_content)
+            .unwrap();
+        let mut content = Vec::new();
+        let n = compat::decode_slice(&slice[..], hash, start, len, &mut content).unwrap();
+        assert_eq!(n, content.len() as u64);
+        assert_eq!(content, expected_content, "slice {} {}", start, len);
+    }
+    // corrupted data must be detected
+    if size > 0 {
+        let mut corrupted = expected_encoded.clone();
+        let n = corrupted.len();
+        corrupted[n - 1] ^= 1;
+        assert!(compat::decode(&corrupted[..], hash, Vec::new()).is_err());
+    }
+    assert!(compat::open_outboard(&expected_outboard[..7], hash).is_err());
+    assert!(
+        compat::open_outboard(&expected_outboard[..expected_outboard.len() - 1], hash).is_err()
+    );
+}
+
+#[test]
+fn bao_compat_cases() {
+    for size in [0, 1, 1023, 1024, 1025, 2048, 4096, 10000, 100000] {
+        bao_compat_impl(size);
+    }
+}
+
+#[proptest]
+fn bao_compat_proptest(#[strategy(0usize..100000)] size: usize) {
+    bao_compat_impl(size);
+}
+
+fn ranges_encoding_impl(ranges: &ChunkRangesRef) {
+    use crate::io::ranges::{decode_chunk_ranges, encode_chunk_ranges, read_chunk_ranges};
+    let encoded = encode_chunk_ranges(ranges);
+    let n = ranges.boundaries().len().div_ceil(2);
+    let decoded = decode_chunk_ranges(&encoded, n).unwrap();
+    assert_eq!(decoded.as_ref(), ranges);
+    if n > 0 {
+        assert!(decode_chunk_ranges(&encoded, n - 1).is_err());
+    }
+    // reading a prefix leaves the rest of the buffer alone
+    let mut buf = encoded.clone();
+    buf.extend_from_slice(&[1, 2, 3]);
+    let mut data = buf.as_slice();
+    let read = read_chunk_ranges(&mut data, n).unwrap();
+    assert_eq!(read.as_ref(), ranges);
+    assert_eq!(data, &[1, 2, 3]);
+    assert!(decode_chunk_ranges(&buf, n).is_err());
+    // every strict prefix is incomplete
+    for i in 0..encoded.len() {
+        assert!(decode_chunk_ranges(&encoded[..i], n).is_err());
+    }
+}
+
+#[test]
+fn ranges_encoding_cases() {
+    use crate::io::ranges::{decode_chunk_ranges, encode_chunk_ranges};
+    let cases = [
+        (ChunkRanges::empty(), vec![0]),
+        (ChunkRanges::all(), vec![1, 0]),
+        (ChunkRanges::from(ChunkNum(10)..), vec![1, 10]),
+        (
+            ChunkRanges::from(ChunkNum(10)..ChunkNum(20)),
+            vec![2, 10, 10],
+        ),
+        (ChunkRanges::from(ChunkNum(128)..), vec![1, 0x80, 1]),
+        (
+            ChunkRanges::from(ChunkNum(u64::MAX)..),
+            vec![
+                1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01,
+            ],
+        ),
+    ];
+    for (ranges, expected) in cases {
+        assert_eq!(encode_chunk_ranges(&ranges), expected);
+        assert_eq!(decode_chunk_ranges(&expected, 1).unwrap(), ranges);
+        ranges_encoding_impl(&ranges);
+    }
+    let invalid: [&[u8]; 7] = [
+        // zero delta, empty range
+        &[2, 10, 0],
+        // non minimal varint
+        &[1, 0x80, 0],
+        // varint overflow
+        &[
+            1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02,
+        ],
+        // boundary overflow
+        &[
+            2, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, 1,
+        ],
+        // missing boundaries
+        &[3, 1, 1],
+        // no data at all
+        &[],
+        // too many ranges, rejected before reading the boundaries
+        &[0xff, 0xff, 0xff, 0xff, 0x0f],
+    ];
+    for data in invalid {
+        assert!(decode_chunk_ranges(data, 1024).is_err());
+    }
+    // a huge count without data must not allocate for the count
+    let huge: &[u8] = &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f];
+    assert!(decode_chunk_ranges(huge, usize::MAX).is_err());
+}
+
+#[proptest]
+fn ranges_encoding_proptest(#[strategy(selection(1 << 40, 5))] ranges: ChunkRanges) {
+    ranges_encoding_impl(&ranges);
+}
+
+fn hash_mode_impl(size: usize, block_size: BlockSize, ranges: &ChunkRangesRef) {
+    let data = make_test_data(size);
+    let key = [7u8; 32];
+    let context = "bao-tree 2024-01-01 hash mode test";
+    let modes = [
+        (HashMode::DEFAULT, blake3::hash(&data)),
+        (HashMode::keyed(&key), blake3::keyed_hash(&key, &data)),
+        (
+            HashMode::derive_key(context),
+            blake3::Hasher::new_derive_key(context)
+                .update(&data)
+                .finalize(),
+        ),
+    ];
+    for (mode, expected) in modes {
+        let outboard = PostOrderMemOutboard::create_with_mode(&data, block_size, mode);
+        assert_eq!(outboard.root, expected);
+        assert_eq!(outboard.tree.mode(), mode);
+        // the incremental hasher must give the same outboard
+        let mut hasher = crate::io::sync::BaoHasher::new_with_mode(block_size, mode, Vec::new());
+        std::io::Write::write_all(&mut hasher, &data).unwrap();
+        let incremental = hasher.finalize().unwrap();
+        assert_eq!(incremental.root, expected);
+        assert_eq!(incremental.tree, outboard.tree);
+        assert_eq!(incremental.data, outboard.data);
+        if ranges.is_empty() {
+            continue;
+        }
+        let mut encoded = Vec::new();
+        crate::io::sync::encode_ranges_validated(&data, &outboard, ranges, &mut encoded).unwrap();
+        // decoding with the right mode works
+        let tree = outboard.tree;
+        let iter = crate::io::sync::DecodeResponseIter::new(expected, tree, &encoded[..], ranges);
+        for item in iter {
+            if let BaoContentItem::Leaf(Leaf { offset, data: leaf }) = item.unwrap() {
+                let start = offset as usize;
+                assert_eq!(&data[start..start + leaf.len()], &leaf[..]);
+            }
+        }
+        // decoding with the wrong mode fails on the first item
+        let wrong = if mode.is_default() {
+            HashMode::keyed(&key)
+        } else {
+            HashMode::DEFAULT
+        };
+        let tree = BaoTree::new_with_mode(size as u64, block_size, wrong);
+        let mut iter =
+            crate::io::sync::DecodeResponseIter::new(expected, tree, &encoded[..], ranges);
+        assert!(iter.next().unwrap().is_err());
+    }
+}
+
+#[test]
+fn hash_mode_cases() {
+    let cases = [
+        (0, 0, ChunkRanges::all()),
+        (1, 0, ChunkRanges::all()),
+        (64, 0, ChunkRanges::all()),
+        (65, 0, ChunkRanges::all()),
+        (1024, 0, ChunkRanges::all()),
+        (1025, 0, ChunkRanges::all()),
+        (100000, 0, ChunkRanges::all()),
+        (100000, 4, ChunkRanges::all()),
+        (100000, 2, ChunkRanges::from(ChunkNum(10)..ChunkNum(30))),
+        (100000, 4, ChunkRanges::from(ChunkNum(3)..ChunkNum(5))),
+    ];
+    for (size, block_size, ranges) in cases {
+        hash_mode_impl(size, BlockSize(block_size), &ranges);
+    }
+}
+
+#[proptest]
+fn hash_mode_proptest(
+    #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+    #[strategy(block_size())] block_size: BlockSize,
+) {
+    let (size, ranges) = size_and_selection;
+    hash_mode_impl(size, block_size, &ranges);
+}
+
+/// Subtrees that can not be part of a valid tree must not panic when hashed
+#[test]
+fn hash_mode_invalid_subtree() {
+    let data = make_test_data(4096);
+    for mode in [HashMode::keyed(&[1; 32]), HashMode::derive_key("invalid")] {
+        // empty non root subtree
+        mode.hash_subtree(1, &[], false);
+        // subtree that is larger than the max size at its offset
+        mode.hash_subtree(1, &data, false);
+        // root subtree that does not start at 0
+        mode.hash_subtree(1, &data[..10], true);
+        mode.hash_subtree(u64::MAX, &data[..10], false);
+    }
+}
+
+#[test]
+fn hash_mode_debug() {
+    let key = [0x42u8; 32];
+    let tree = BaoTree::new_with_mode(1000, BlockSize::ZERO, HashMode::keyed(&key));
+    let text = format!("{:?}", tree);
+    assert!(text.contains("Keyed(..)"));
+    assert!(!text.contains("66"));
+    assert_eq!(format!("{:?}", HashMode::default()), "Default");
+    assert_eq!(
+        format!("{:?}", HashMode::derive_key("context")),
+        "DeriveKey(..)"
+    );
+}
+
 #[cfg(feature = "validate")]
 mod validate {
 
@@ -321,7 +2918,7 @@ mod validate {
         let size = tree.size.try_into().unwrap();
         let block_size = tree.block_size;
         let data = make_test_data(size);
-        let mut outboard = PostOrderMemOutboard::create(&data, block_size);
+        let mut outboard = PostOrderMemOutboard::create_with_mode(&data, block_size, tree.mode());
         let expected = ChunkRanges::from(..outboard.tree().chunks());
         let actual = valid_ranges_sync(&outboard, &data);
         assert_eq!(expected, actual);
@@ -334,6 +2931,16 @@ mod validate {
         validate_pos_impl(tree);
     }
 
+    #[test]
+    fn validate_pos_keyed_cases() {
+        let cases = [(0, 0), (1024, 0), (100000, 0), (100000, 4)];
+        for (size, block_level) in cases {
+            let keyed = HashMode::keyed(&[1; 32]);
+            let tree = BaoTree::new_with_mode(size, BlockSize(block_level), keyed);
+            validate_pos_impl(tree);
+        }
+    }
+
     #[test]
     fn validate_pos_cases() {
         let cases = [
@@ -461,6 +3068,101 @@ mod validate {
         let actual = valid_ranges_fsm(outboard, data.clone());
         assert_eq!(expected, actual);
     }
+
+    /// Corrupt the data and the outboard, then repair both using a response
+    /// from the original
+    fn repair_test_data(
+        tree: BaoTree,
+        rand: u32,
+    ) -> (Vec<u8>, PostOrderMemOutboard, Vec<u8>, PostOrderMemOutboard) {
+        let rand = rand as usize;
+        let data = make_test_data(tree.size.try_into().unwrap());
+        let outboard = PostOrderMemOutboard::create(&data, tree.block_size);
+        let mut local_data = data.clone();
+        let mut local_outboard = outboard.clone();
+        if !local_data.is_empty() {
+            flip_bit(&mut local_data, rand);
+        }
+        if !local_outboard.data.is_empty() {
+            flip_bit(&mut local_outboard.data, rand / 2);
+        }
+        (data, outboard, local_data, local_outboard)
+    }
+
+    fn repair_sync_impl(tree: BaoTree, rand: u32) {
+        use crate::io::sync::{invalid_ranges, repair};
+        let (data, outboard, mut local_data, mut local_outboard) = repair_test_data(tree, rand);
+        let invalid =
+            invalid_ranges(&local_outboard, &local_data[..], &ChunkRanges::all()).unwrap();
+        assert_eq!(invalid.is_empty(), data.is_empty());
+        let mut encoded = Vec::new();
+        if !invalid.is_empty() {
+            crate::io::sync::encode_ranges_validated(&data[..], &outboard, &invalid, &mut encoded)
+                .unwrap();
+        }
+        let repaired =
+            repair(&encoded[..], &invalid, &mut local_data, &mut local_outboard).unwrap();
+        assert!(invalid.is_subset(&repaired));
+        assert_eq!(local_data, data);
+        assert_eq!(local_outboard, outboard);
+        let invalid =
+            invalid_ranges(&local_outboard, &local_data[..], &ChunkRanges::all()).unwrap();
+        assert!(invalid.is_empty());
+    }
+
+    async fn repair_fsm_impl(tree: BaoTree, rand: u32) {
+        use crate::io::fsm::{invalid_ranges, repair};
+        let (data, mut outboard, local_data, mut local_outboard) = repair_test_data(tree, rand);
+        let mut local_data = BytesMut::from(&local_data[..]);
+        let invalid = invalid_ranges(&mut local_outboard, &mut local_data, &ChunkRanges::all())
+            .await
+            .unwrap();
+        assert_eq!(invalid.is_empty(), data.is_empty());
+        let mut encoded = Vec::new();
+        if !invalid.is_empty() {
+            crate::io::fsm::encode_ranges_validated(
+                Bytes::from(data.clone()),
+                &mut outboard,
+                &invalid,
+                &mut encoded,
+            )
+            .await
+            .unwrap();
+        }
+        let repaired = repair(
+            Bytes::from(encoded),
+            &invalid,
+            &mut local_data,
+            &mut local_outboard,
+        )
+        .await
+        .unwrap();
+        assert!(invalid.is_subset(&repaired));
+        assert_eq!(&local_data[..], &data[..]);
+        assert_eq!(local_outboard, outboard);
+    }
+
+    #[test]
+    fn repair_cases() {
+        let cases = [
+            ((0, 0), 0),
+            ((1, 0), 7),
+            ((1025, 0), 12345),
+            ((0x6001, 3), 1265277760),
+            ((100000, 2), 2738363904),
+        ];
+        for ((size, block_level), rand) in cases {
+            let tree = BaoTree::new(size, BlockSize(block_level));
+            repair_sync_impl(tree, rand);
+            run_blocking(repair_fsm_impl(tree, rand));
+        }
+    }
+
+    #[proptest]
+    fn repair_proptest(#[strategy(tree())] tree: BaoTree, rand: u32) {
+        repair_sync_impl(tree, rand);
+        run_blocking(repair_fsm_impl(tree, rand));
+    }
 }
 
 /// Encode data fully, decode it again, and check that both data and outboard are the same
@@ -773,6 +3475,7 @@ fn encode_selected_reference(
     res.extend_from_slice(&(data.len() as u64).to_le_bytes());
     let max_skip_level = block_size.to_u32();
     let hash = encode_selected_rec(
+        HashMode::DEFAULT,
         ChunkNum(0),
         data,
         true,
@@ -990,3 +3693,369 @@ fn canonicalize_ranges_test() {
 fn run_blocking<F: std::future::Future>(f: F) -> F::Output {
     tokio::runtime::Runtime::new().unwrap().block_on(f)
 }
+
+#[cfg(feature = "rayon")]
+mod parallel {
+    use super::*;
+
+    /// The parallel outboard must be identical to the sequential one
+    fn outboard_parallel_impl(size: usize, block_size: BlockSize) {
+        let data = make_test_data(size);
+        let expected = PostOrderMemOutboard::create(&data, block_size);
+        let tree = expected.tree;
+        let mut actual = PostOrderMemOutboard {
+            root: blake3::hash(&[]),
+            tree,
+            data: vec![0u8; tree.outboard_size() as usize],
+        };
+        let root = crate::io::sync::outboard_parallel(&data[..], tree, &mut actual).unwrap();
+        assert_eq!(root, expected.root);
+        assert_eq!(actual.data, expected.data);
+    }
+
+    #[test]
+    fn outboard_parallel_cases() {
+        let subtree = ChunkNum(crate::io::sync::PARALLEL_SUBTREE_CHUNKS).to_bytes() as usize;
+        let sizes = [
+            0,
+            1,
+            1024,
+            subtree,
+            subtree + 1,
+            subtree * 2,
+            subtree * 3 + 1000,
+            subtree * 5 + 1,
+        ];
+        for size in sizes {
+            for block_level in [0, 4, 13] {
+                outboard_parallel_impl(size, BlockSize(block_level));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "mmap")]
+mod mmap {
+    use super::*;
+    use memmap2::{Mmap, MmapMut};
+
+    use crate::io::outboard::MmapData;
+
+    fn create_file(path: &std::path::Path) -> std::fs::File {
+        std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap()
+    }
+
+    /// Outboards computed into a memory map must match the in memory outboards,
+    /// and encoding from mapped data and outboards must match encoding from memory
+    fn mmap_impl(size: usize, block_size: BlockSize, ranges: &ChunkRangesRef) {
+        let data = make_test_data(size);
+        let pre = PreOrderMemOutboard::create(&data, block_size);
+        let post = PostOrderMemOutboard::create(&data, block_size);
+        let tree = pre.tree;
+        let dir = tempfile::tempdir().unwrap();
+        let data_file = create_file(&dir.path().join("data"));
+        std::io::Write::write_all(&mut &data_file, &data).unwrap();
+        let pre_file = create_file(&dir.path().join("pre"));
+        let post_file = create_file(&dir.path().join("post"));
+        pre_file.set_len(tree.outboard_size()).unwrap();
+        post_file.set_len(tree.outboard_size()).unwrap();
+        // compute the outboards into the mapped files
+        let mut ob = PreOrderMemOutboard {
+            root: blake3::Hash::from([0; 32]),
+            tree,
+            data: unsafe { MmapMut::map_mut(&pre_file) }.unwrap(),
+        };
+        let root = crate::io::sync::outboard(&data[..], tree, &mut ob).unwrap();
+        ob.flush().unwrap();
+        assert_eq!(root, pre.root);
+        let mut ob = PostOrderMemOutboard {
+            root,
+            tree,
+            data: unsafe { MmapMut::map_mut(&post_file) }.unwrap(),
+        };
+        crate::io::sync::outboard(&data[..], tree, &mut ob).unwrap();
+        ob.flush().unwrap();
+        drop(ob);
+        // read them back
+        let pre_mmap = PreOrderMemOutboard {
+            root,
+            tree,
+            data: unsafe { Mmap::map(&pre_file) }.unwrap(),
+        };
+        let post_mmap = PostOrderMemOutboard {
+            root,
+            tree,
+            data: unsafe { Mmap::map(&post_file) }.unwrap(),
+        };
+        assert_eq!(&pre_mmap.data[..], &pre.data[..]);
+        assert_eq!(&post_mmap.data[..], &post.data[..]);
+        let data_mmap = unsafe { MmapData::open(&data_file) }.unwrap();
+        let mut expected = Vec::new();
+        crate::io::sync::encode_ranges_validated(&data[..], &pre, ranges, &mut expected).unwrap();
+        let mut actual = Vec::new();
+        crate::io::sync::encode_ranges_validated(&data_mmap, &pre_mmap, ranges, &mut actual)
+            .unwrap();
+        assert_eq!(actual, expected);
+        let mut actual = Vec::new();
+        crate::io::sync::encode_ranges_validated(&data_mmap, &post_mmap, ranges, &mut actual)
+            .unwrap();
+        assert_eq!(actual, expected);
+        let mut data_mmap = data_mmap;
+        let mut post_mmap = post_mmap;
+        let mut actual = Vec::new();
+        run_blocking(crate::io::fsm::encode_ranges_validated(
+            &mut data_mmap,
+            &mut post_mmap,
+            ranges,
+            &mut actual,
+        ))
+        .unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn mmap_cases() {
+        let cases = [
+            (0, 0, ChunkRanges::all()),
+            (1, 0, ChunkRanges::all()),
+            (1025, 0, ChunkRanges::all()),
+            (100000, 2, ChunkRanges::from(ChunkNum(3)..ChunkNum(50))),
+            (100000, 4, ChunkRanges::all()),
+        ];
+        for (size, block_level, ranges) in cases {
+            mmap_impl(size, BlockSize(block_level), &ranges);
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+mod tokio_io {
+    use iroh_io::AsyncSliceWriter;
+
+    use super::*;
+    use crate::io::tokio::{post_order_outboard, pre_order_outboard, SliceAdapter};
+
+    async fn create_file(path: &std::path::Path) -> tokio::fs::File {
+        tokio::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .await
+            .unwrap()
+    }
+
+    /// Outboards, encoding and decoding with tokio io types must match the
+    /// results of the in memory implementations
+    async fn tokio_io_impl(size: usize, block_size: BlockSize, ranges: &ChunkRanges) {
+        use tokio::io::AsyncWriteExt;
+        let data = make_test_data(size);
+        let pre = PreOrderMemOutboard::create(&data, block_size);
+        let post = PostOrderMemOutboard::create(&data, block_size);
+        let tree = pre.tree;
+        let dir = tempfile::tempdir().unwrap();
+        let mut data_file = create_file(&dir.path().join("data")).await;
+        data_file.write_all(&data).await.unwrap();
+        // post order outboard to a stream
+        let mut post_data = Vec::new();
+        let root = crate::io::tokio::outboard_post_order(&data[..], tree, &mut post_data)
+            .await
+            .unwrap();
+        assert_eq!(root, post.root);
+        assert_eq!(post_data, post.data);
+        // pre order outboard into a file
+        let mut ob = pre_order_outboard(root, tree, create_file(&dir.path().join("pre")).await);
+        let root = crate::io::tokio::outboard(&data[..], tree, &mut ob)
+            .await
+            .unwrap();
+        assert_eq!(root, pre.root);
+        let mut expected = Vec::new();
+        crate::io::sync::encode_ranges_validated(&data[..], &pre, ranges, &mut expected).unwrap();
+        let mut actual = Vec::new();
+        crate::io::tokio::encode_ranges_validated(&mut data_file, &mut ob, ranges, &mut actual)
+            .await
+            .unwrap();
+        assert_eq!(actual, expected);
+        let mut actual = Vec::new();
+        crate::io::tokio::encode_ranges(&mut data_file, &mut ob, ranges, &mut actual)
+            .await
+            .unwrap();
+        assert_eq!(actual, expected);
+        // decode into a file and a post order outboard file
+        let file = create_file(&dir.path().join("decoded.obao")).await;
+        let mut decoded_ob = post_order_outboard(root, tree, file);
+        let target = create_file(&dir.path().join("decoded")).await;
+        crate::io::tokio::decode_ranges(&expected[..], ranges.clone(), target, &mut decoded_ob)
+            .await
+            .unwrap();
+        let decoded = tokio::fs::read(dir.path().join("decoded")).await.unwrap();
+        let mut actual = Vec::new();
+        crate::io::sync::encode_ranges_validated(&decoded[..], &post, ranges, &mut actual).unwrap();
+        assert_eq!(actual, expected);
+        let mut actual = Vec::new();
+        crate::io::fsm::encode_ranges_validated(
+            Bytes::from(decoded),
+            &mut decoded_ob,
+            ranges,
+            &mut actual,
+        )
+        .await
+        .unwrap();
+        assert_eq!(actual, expected);
+        // decode into in memory tokio io types
+        let mut decoded_ob = pre_order_outboard(root, tree, std::io::Cursor::new(Vec::new()));
+        let target = std::io::Cursor::new(Vec::new());
+        let target =
+            crate::io::tokio::decode_ranges(&expected[..], ranges.clone(), target, &mut decoded_ob)
+                .await
+                .unwrap();
+        let mut actual = Vec::new();
+        crate::io::sync::encode_ranges_validated(
+            &target.into_inner()[..],
+            &pre,
+            ranges,
+            &mut actual,
+        )
+        .unwrap();
+        assert_eq!(actual, expected);
+        // the response decoder yields the same items as the fsm one
+        let mut decoder =
+            crate::io::tokio::response_decoder(root, ranges.clone(), tree, &expected[..]);
+        let mut items = 0;
+        while let crate::io::fsm::ResponseDecoderNext::More((next, item)) = decoder.next().await {
+            item.unwrap();
+            items += 1;
+            decoder = next;
+        }
+        let expected_items = tree
+            .ranges_pre_order_chunks_iter_ref(truncate_ranges(ranges, tree.size), 0)
+            .count();
+        assert_eq!(items, expected_items);
+    }
+
+    /// Slice writers for tokio io types can grow, but not shrink
+    #[test]
+    fn tokio_slice_writer_set_len() {
+        run_blocking(async {
+            let mut writer = SliceAdapter(std::io::Cursor::new(vec![1u8; 10]));
+            writer.set_len(10).await.unwrap();
+            writer.set_len(100).await.unwrap();
+            let data = writer.into_inner().into_inner();
+            assert_eq!(data.len(), 100);
+            assert!(data[10..].iter().all(|x| *x == 0));
+            let mut writer = SliceAdapter(std::io::Cursor::new(data));
+            let err = writer.set_len(50).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+        });
+    }
+
+    #[test]
+    fn tokio_io_cases() {
+        let cases = [
+            (0, 0, ChunkRanges::all()),
+            (1024, 0, ChunkRanges::all()),
+            (10000, 0, ChunkRanges::from(ChunkNum(2)..ChunkNum(5))),
+            (100000, 2, ChunkRanges::all()),
+            (100000, 4, ChunkRanges::from(..ChunkNum(32))),
+        ];
+        for (size, block_level, ranges) in cases {
+            run_blocking(tokio_io_impl(size, BlockSize(block_level), &ranges));
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde_support {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    const MAX_CHUNK_LOG: u8 = BlockSize::MAX_CHUNK_LOG;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Request {
+        tree: BaoTree,
+        #[serde(with = "crate::serde_support::chunk_ranges")]
+        ranges: ChunkRanges,
+    }
+
+    fn roundtrip<T: Serialize + for<'a> Deserialize<'a>>(value: &T) -> T {
+        let bytes = postcard::to_stdvec(value).unwrap();
+        postcard::from_bytes(&bytes).unwrap()
+    }
+
+    fn request_roundtrip_impl(tree: BaoTree, ranges: ChunkRanges) {
+        let request = Request { tree, ranges };
+        assert_eq!(roundtrip(&request), request);
+    }
+
+    #[test]
+    fn request_roundtrip_cases() {
+        let cases = [
+            (BaoTree::new(0, BlockSize(0)), ChunkRanges::empty()),
+            (BaoTree::new(100000, BlockSize(4)), ChunkRanges::all()),
+            (
+                BaoTree::new(u64::MAX, BlockSize(MAX_CHUNK_LOG)),
+                ChunkRanges::from(ChunkNum(1)..ChunkNum(10)),
+            ),
+        ];
+        for (tree, ranges) in cases {
+            request_roundtrip_impl(tree, ranges);
+        }
+    }
+
+    #[proptest]
+    fn request_roundtrip_proptest(
+        #[strategy(size_and_selection(0..100000, 2))] size_and_selection: (usize, ChunkRanges),
+        #[strategy(block_size())] block_size: BlockSize,
+    ) {
+        let (size, ranges) = size_and_selection;
+        request_roundtrip_impl(BaoTree::new(size as u64, block_size), ranges);
+    }
+
+    #[test]
+    fn content_item_roundtrip() {
+        let node = TreeNode(7);
+        let pair = (blake3::hash(b"l"), blake3::hash(b"r"));
+        let parent = roundtrip(&Parent { node, pair });
+        assert_eq!((parent.node, parent.pair), (node, pair));
+        let data = Bytes::from(make_test_data(1500));
+        let leaf = roundtrip(&Leaf {
+            offset: 2048,
+            data: data.clone(),
+        });
+        assert_eq!((leaf.offset, leaf.data), (2048, data));
+    }
+
+    #[test]
+    fn reject_invalid() {
+        // boundaries must be strictly increasing
+        let bytes = postcard::to_stdvec(&(BaoTree::new(0, BlockSize(0)), vec![5u64, 3])).unwrap();
+        assert!(postcard::from_bytes::<Request>(&bytes).is_err());
+        let bytes = postcard::to_stdvec(&(BaoTree::new(0, BlockSize(0)), vec![3u64, 3])).unwrap();
+        assert!(postcard::from_bytes::<Request>(&bytes).is_err());
+        // block size must be in range
+        let bytes = postcard::to_stdvec(&(MAX_CHUNK_LOG + 1)).unwrap();
+        assert!(postcard::from_bytes::<BlockSize>(&bytes).is_err());
+        let bytes = postcard::to_stdvec(&MAX_CHUNK_LOG).unwrap();
+        assert_eq!(
+            postcard::from_bytes::<BlockSize>(&bytes).unwrap(),
+            BlockSize(MAX_CHUNK_LOG)
+        );
+    }
+
+    /// Trees with a non default hash mode must not silently lose their mode
+    #[test]
+    fn reject_non_default_mode() {
+        for mode in [HashMode::keyed(&[1; 32]), HashMode::derive_key("serde")] {
+            let tree = BaoTree::new_with_mode(1000, BlockSize(0), mode);
+            assert!(postcard::to_stdvec(&tree).is_err());
+        }
+    }
+}
@@ -55,6 +55,12 @@ impl BlockSize {
     /// This means that blocks and blake3 chunks are the same size.
     pub const ZERO: BlockSize = BlockSize(0);
 
+    /// The largest chunk log that is accepted when reading a block size from
+    /// untrusted input, e.g. when deserializing.
+    ///
+    /// This is the largest value for which the block size in bytes fits into a u64.
+    pub const MAX_CHUNK_LOG: u8 = 53;
+
     /// Number of bytes in a block at this level
     pub const fn bytes(self) -> usize {
         BLAKE3_CHUNK_SIZE << self.0